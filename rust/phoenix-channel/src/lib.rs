@@ -177,6 +177,30 @@ where
         self.send_message(topic, message)
     }
 
+    /// Reply to a request the server sent us on the given topic.
+    pub fn reply_ok(
+        &mut self,
+        topic: impl Into<String>,
+        req_id: InboundRequestId,
+        response: impl Serialize,
+    ) {
+        self.send_reply(topic, req_id, PhxReply::Ok(OkReply::Message(response)));
+    }
+
+    /// Reject a request the server sent us on the given topic.
+    pub fn reply_error(
+        &mut self,
+        topic: impl Into<String>,
+        req_id: InboundRequestId,
+        reason: impl Into<String>,
+    ) {
+        self.send_reply(
+            topic,
+            req_id,
+            PhxReply::<()>::Error(ErrorInfo::Reason(reason.into())),
+        );
+    }
+
     pub fn poll(
         &mut self,
         cx: &mut Context,
@@ -219,6 +243,7 @@ where
                         }
                         Some(reference) => {
                             return Poll::Ready(Ok(Event::InboundReq {
+                                topic: message.topic,
                                 req_id: InboundRequestId(reference),
                                 req: msg,
                             }))
@@ -314,6 +339,18 @@ where
         OutboundRequestId(request_id)
     }
 
+    fn send_reply(
+        &mut self,
+        topic: impl Into<String>,
+        req_id: InboundRequestId,
+        reply: PhxReply<impl Serialize>,
+    ) {
+        self.pending_messages.push(Message::Text(
+            serde_json::to_string(&PhoenixMessage::<(), _>::reply(topic, reply, req_id.0))
+                .expect("we should always be able to serialize a reply"),
+        ));
+    }
+
     fn fetch_add_request_id(&mut self) -> u64 {
         let next_id = self.next_request_id;
         self.next_request_id += 1;
@@ -359,7 +396,10 @@ pub enum Event<TInboundMsg, TOutboundRes> {
         msg: TInboundMsg,
     },
     /// The server sent us a request and is expecting a response.
+    ///
+    /// Use [`PhoenixChannel::reply_ok`] or [`PhoenixChannel::reply_error`] to respond.
     InboundReq {
+        topic: String,
         req_id: InboundRequestId,
         req: TInboundMsg,
     },
@@ -392,6 +432,14 @@ impl<T, R> PhoenixMessage<T, R> {
             reference: Some(reference),
         }
     }
+
+    fn reply(topic: impl Into<String>, reply: PhxReply<R>, reference: u64) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: Payload::Reply(ReplyMessage::PhxReply(reply)),
+            reference: Some(reference),
+        }
+    }
 }
 
 // This is basically the same as tungstenite does but we add some new headers (namely user-agent)
@@ -501,6 +549,35 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn can_serialize_replies() {
+        #[derive(Serialize)]
+        struct Res {
+            count: u32,
+        }
+
+        let ok = PhoenixMessage::<(), _>::reply(
+            "relay",
+            PhxReply::Ok(OkReply::Message(Res { count: 1 })),
+            42,
+        );
+        let error = PhoenixMessage::<(), ()>::reply(
+            "relay",
+            PhxReply::Error(ErrorInfo::Reason("unknown_user".to_owned())),
+            43,
+        );
+
+        assert_eq!(
+            serde_json::to_string(&ok).unwrap(),
+            r#"{"topic":"relay","event":"phx_reply","payload":{"status":"ok","response":{"count":1}},"ref":42}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"topic":"relay","event":"phx_reply","payload":{"status":"error","response":{"reason":"unknown_user"}},"ref":43}"#
+        );
+    }
+
     #[test]
     fn can_deserialize_init_message() {
         #[derive(Deserialize, PartialEq, Debug)]
@@ -0,0 +1,411 @@
+use crate::auth::constant_time_eq;
+use crate::{
+    AllocationId, AllocationInfo, CaptureFilter, CaptureId, MAX_CAPTURE_DURATION, MIN_CAPTURE_BYTES,
+};
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router, Server};
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_CAPTURE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_CAPTURE_DURATION: u64 = 60;
+
+/// A request from the admin API to the event loop.
+///
+/// Each request carries a channel on which the event loop sends the result.
+#[derive(Debug)]
+pub enum AdminRequest {
+    ListAllocations {
+        reply: oneshot::Sender<Vec<AllocationInfo>>,
+    },
+    KillAllocation {
+        id: AllocationId,
+        reply: oneshot::Sender<bool>,
+    },
+    KillAllocationsOfUser {
+        username: String,
+        reply: oneshot::Sender<Vec<AllocationId>>,
+    },
+    RotateAuthSecret {
+        secret: SecretString,
+        reply: oneshot::Sender<()>,
+    },
+    StartDraining {
+        reply: oneshot::Sender<()>,
+    },
+    StartCapture {
+        filter: CaptureFilter,
+        max_bytes: u64,
+        duration: Duration,
+        reply: oneshot::Sender<Result<StartedCapture, StartCaptureError>>,
+    },
+    StopCapture {
+        id: CaptureId,
+        reply: oneshot::Sender<bool>,
+    },
+}
+
+/// A capture that was started and the file it is written to.
+#[derive(Debug, Serialize)]
+pub struct StartedCapture {
+    pub id: CaptureId,
+    pub file: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum StartCaptureError {
+    /// Captures require a directory to write them to.
+    Disabled,
+    /// The allocation to capture doesn't exist.
+    UnknownAllocation,
+    /// The capture file could not be created.
+    Io,
+}
+
+#[derive(Deserialize)]
+struct RotateAuthSecret {
+    secret: String,
+}
+
+/// The limits of a capture, in bytes and seconds.
+#[derive(Deserialize)]
+struct StartCapture {
+    #[serde(default = "default_capture_max_bytes")]
+    max_bytes: u64,
+    #[serde(default = "default_capture_duration")]
+    duration: u64,
+}
+
+fn default_capture_max_bytes() -> u64 {
+    DEFAULT_CAPTURE_MAX_BYTES
+}
+
+fn default_capture_duration() -> u64 {
+    DEFAULT_CAPTURE_DURATION
+}
+
+#[derive(Clone)]
+struct AppState {
+    token: Arc<SecretString>,
+    requests: mpsc::Sender<AdminRequest>,
+}
+
+/// Serves our admin API.
+///
+/// - `GET /allocations` lists all allocations including their channel bindings and relayed traffic.
+/// - `DELETE /allocations/:id` deletes a single allocation.
+/// - `DELETE /users/:username/allocations` deletes all allocations of a username.
+/// - `POST /auth-secret` replaces the secret used to authenticate clients, see [`Server::rotate_auth_secret`](crate::Server::rotate_auth_secret).
+/// - `POST /drain` puts the relay into drain mode, see [`Server::start_draining`](crate::Server::start_draining).
+/// - `POST /allocations/:id/capture` and `POST /users/:username/capture` start a pcapng capture of the relayed traffic of an allocation or username.
+///   The JSON body may limit the capture via `max_bytes` and `duration` in seconds, which default to 10 MiB and 60 seconds.
+///   `duration` must not exceed [`MAX_CAPTURE_DURATION`].
+/// - `DELETE /captures/:id` stops a capture.
+///
+/// All requests must carry the given token as `Authorization: Bearer <token>`.
+pub async fn serve(
+    addr: impl Into<SocketAddr>,
+    token: SecretString,
+    requests: mpsc::Sender<AdminRequest>,
+) -> Result<()> {
+    let addr = addr.into();
+
+    let service = Router::new()
+        .route("/allocations", get(list_allocations))
+        .route("/allocations/:id", delete(kill_allocation))
+        .route(
+            "/users/:username/allocations",
+            delete(kill_allocations_of_user),
+        )
+        .route("/auth-secret", post(rotate_auth_secret))
+        .route("/drain", post(start_draining))
+        .route("/allocations/:id/capture", post(capture_allocation))
+        .route("/users/:username/capture", post(capture_user))
+        .route("/captures/:id", delete(stop_capture))
+        .with_state(AppState {
+            token: Arc::new(token),
+            requests,
+        })
+        .into_make_service();
+
+    Server::try_bind(&addr)?.serve(service).await?;
+
+    Ok(())
+}
+
+async fn list_allocations(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match state
+        .request(|reply| AdminRequest::ListAllocations { reply })
+        .await
+    {
+        Some(allocations) => Json(allocations).into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+async fn kill_allocation(
+    State(state): State<AppState>,
+    Path(id): Path<AllocationId>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match state
+        .request(|reply| AdminRequest::KillAllocation { id, reply })
+        .await
+    {
+        Some(true) => StatusCode::NO_CONTENT,
+        Some(false) => StatusCode::NOT_FOUND,
+        None => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn kill_allocations_of_user(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match state
+        .request(|reply| AdminRequest::KillAllocationsOfUser { username, reply })
+        .await
+    {
+        Some(ids) => Json(ids).into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+async fn rotate_auth_secret(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<RotateAuthSecret>,
+) -> StatusCode {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let secret = SecretString::new(body.secret);
+
+    match state
+        .request(|reply| AdminRequest::RotateAuthSecret { secret, reply })
+        .await
+    {
+        Some(()) => StatusCode::NO_CONTENT,
+        None => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn start_draining(State(state): State<AppState>, headers: HeaderMap) -> StatusCode {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match state
+        .request(|reply| AdminRequest::StartDraining { reply })
+        .await
+    {
+        Some(()) => StatusCode::ACCEPTED,
+        None => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn capture_allocation(
+    State(state): State<AppState>,
+    Path(id): Path<AllocationId>,
+    headers: HeaderMap,
+    Json(body): Json<StartCapture>,
+) -> Response {
+    start_capture(state, CaptureFilter::Allocation(id), headers, body).await
+}
+
+async fn capture_user(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<StartCapture>,
+) -> Response {
+    start_capture(state, CaptureFilter::Username(username), headers, body).await
+}
+
+async fn start_capture(
+    state: AppState,
+    filter: CaptureFilter,
+    headers: HeaderMap,
+    body: StartCapture,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    // A capture must at least fit the header of its file and must not run for longer than a day.
+    if body.max_bytes < MIN_CAPTURE_BYTES || body.duration > MAX_CAPTURE_DURATION.as_secs() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let max_bytes = body.max_bytes;
+    let duration = Duration::from_secs(body.duration);
+
+    match state
+        .request(|reply| AdminRequest::StartCapture {
+            filter,
+            max_bytes,
+            duration,
+            reply,
+        })
+        .await
+    {
+        Some(Ok(capture)) => (StatusCode::CREATED, Json(capture)).into_response(),
+        Some(Err(StartCaptureError::Disabled)) => StatusCode::NOT_IMPLEMENTED.into_response(),
+        Some(Err(StartCaptureError::UnknownAllocation)) => StatusCode::NOT_FOUND.into_response(),
+        Some(Err(StartCaptureError::Io)) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+async fn stop_capture(
+    State(state): State<AppState>,
+    Path(id): Path<CaptureId>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if !state.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match state
+        .request(|reply| AdminRequest::StopCapture { id, reply })
+        .await
+    {
+        Some(true) => StatusCode::NO_CONTENT,
+        Some(false) => StatusCode::NOT_FOUND,
+        None => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+impl AppState {
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        constant_time_eq(token.as_bytes(), self.token.expose_secret().as_bytes())
+    }
+
+    /// Sends a request to the event loop and waits for its reply.
+    ///
+    /// Returns `None` if the event loop is gone.
+    async fn request<T>(
+        &self,
+        make_request: impl FnOnce(oneshot::Sender<T>) -> AdminRequest,
+    ) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+
+        self.requests.clone().send(make_request(reply)).await.ok()?;
+
+        response.await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_accepts_matching_bearer_token() {
+        let (requests, _) = mpsc::channel(1);
+        let state = AppState {
+            token: Arc::new(SecretString::from("secret".to_owned())),
+            requests,
+        };
+
+        let mut headers = HeaderMap::new();
+        assert!(!state.is_authorized(&headers));
+
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!state.is_authorized(&headers));
+
+        headers.insert(header::AUTHORIZATION, "secret".parse().unwrap());
+        assert!(!state.is_authorized(&headers));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(state.is_authorized(&headers));
+    }
+
+    #[tokio::test]
+    async fn rejects_captures_smaller_than_the_file_header() {
+        let (requests, mut receiver) = mpsc::channel(1);
+        let state = AppState {
+            token: Arc::new(SecretString::from("secret".to_owned())),
+            requests,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+
+        let response = start_capture(
+            state,
+            CaptureFilter::Username("1700000600:client".to_owned()),
+            headers,
+            StartCapture {
+                max_bytes: MIN_CAPTURE_BYTES - 1,
+                duration: DEFAULT_CAPTURE_DURATION,
+            },
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            matches!(receiver.try_next(), Ok(None)),
+            "no request reaches the event loop"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_captures_longer_than_the_maximum_duration() {
+        let (requests, mut receiver) = mpsc::channel(1);
+        let state = AppState {
+            token: Arc::new(SecretString::from("secret".to_owned())),
+            requests,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+
+        let response = start_capture(
+            state,
+            CaptureFilter::Username("1700000600:client".to_owned()),
+            headers,
+            StartCapture {
+                max_bytes: DEFAULT_CAPTURE_MAX_BYTES,
+                duration: u64::MAX,
+            },
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            matches!(receiver.try_next(), Ok(None)),
+            "no request reaches the event loop"
+        );
+    }
+}
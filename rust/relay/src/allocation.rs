@@ -1,15 +1,31 @@
-use crate::server::AllocationId;
-use crate::udp_socket::UdpSocket;
-use crate::AddressFamily;
-use anyhow::{bail, Result};
+use crate::net_ext::IpAddrExt;
+use crate::server::{AllocationId, ConnectionId};
+use crate::udp_socket::{DatagramBatch, UdpSocket, BATCH_SIZE};
+use anyhow::{bail, Context as _, Result};
 use futures::channel::mpsc;
 use futures::{SinkExt, StreamExt};
+use once_cell::sync::Lazy;
+use opentelemetry::metrics::Counter;
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::task;
 
+/// How long we try to connect to a peer on behalf of a client.
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The maximum amount of items that can be buffered in the channel to the allocation task.
-const MAX_BUFFERED_ITEMS: usize = 10;
+///
+/// Large enough to fill a few batches while the task is busy sending the previous one.
+const MAX_BUFFERED_ITEMS: usize = 4 * BATCH_SIZE;
+
+static DROPPED_PACKETS_COUNTER: Lazy<Counter<u64>> = Lazy::new(|| {
+    opentelemetry_api::global::meter("relay")
+        .u64_counter("dropped_packets_total")
+        .with_description("The number of packets dropped because an allocation could not keep up")
+        .init()
+});
 
 pub struct Allocation {
     id: AllocationId,
@@ -23,10 +39,11 @@ pub struct Allocation {
 
 impl Allocation {
     pub fn new(
-        relay_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, AllocationId)>,
+        relay_data_sender: mpsc::Sender<(DatagramBatch, AllocationId)>,
         id: AllocationId,
-        family: AddressFamily,
+        ip: IpAddr,
         port: u16,
+        dont_fragment: bool,
     ) -> Self {
         let (client_to_peer_sender, client_to_peer_receiver) = mpsc::channel(MAX_BUFFERED_ITEMS);
 
@@ -35,15 +52,15 @@ impl Allocation {
                 relay_data_sender,
                 client_to_peer_receiver,
                 id,
-                family,
-                port,
+                SocketAddr::new(ip, port),
+                dont_fragment,
             )
             .await
             else {
                 unreachable!()
             };
 
-            tracing::warn!(allocation = %id, %family, "Allocation task failed: {e:#}");
+            tracing::warn!(allocation = %id, family = %ip.family(), "Allocation task failed: {e:#}");
 
             // With the task stopping, the channel will be closed and any attempt to send data to it will fail.
         });
@@ -71,6 +88,7 @@ impl Allocation {
             }
             Err(e) if e.is_full() => {
                 tracing::warn!(allocation = %self.id, "Send buffer for allocation is full, dropping packet");
+                DROPPED_PACKETS_COUNTER.add(1, &[]);
                 Ok(())
             }
             Err(_) => {
@@ -89,24 +107,158 @@ impl Drop for Allocation {
 }
 
 async fn forward_incoming_relay_data(
-    mut relayed_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, AllocationId)>,
-    mut client_to_peer_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    mut relayed_data_sender: mpsc::Sender<(DatagramBatch, AllocationId)>,
+    client_to_peer_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
     id: AllocationId,
-    family: AddressFamily,
-    port: u16,
+    addr: SocketAddr,
+    dont_fragment: bool,
 ) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, port)?;
+    let mut socket = UdpSocket::bind(addr)?;
+    if dont_fragment {
+        socket.set_dont_fragment()?;
+    }
+    let mut client_to_peer_receiver = client_to_peer_receiver.ready_chunks(BATCH_SIZE);
 
     loop {
         tokio::select! {
-            result = socket.recv() => {
-                let (data, sender) = result?;
-                relayed_data_sender.send((data.to_vec(), sender, id)).await?;
+            result = socket.recv_batch() => {
+                relayed_data_sender.send((result?, id)).await?;
             }
 
-            Some((data, recipient)) = client_to_peer_receiver.next() => {
-                socket.send_to(&data, recipient).await?;
+            Some(datagrams) = client_to_peer_receiver.next() => {
+                socket.send_batch(&datagrams).await?;
             }
         }
     }
 }
+
+/// Events emitted by the tasks that handle the TCP connections of [`TcpAllocation`]s.
+#[derive(Debug)]
+pub enum PeerConnectionEvent {
+    /// A peer connected to a TCP allocation.
+    Accepted {
+        id: AllocationId,
+        peer: SocketAddr,
+        stream: TcpStream,
+    },
+    /// We connected to a peer as requested via [`TcpAllocation::connect`].
+    Connected {
+        connection: ConnectionId,
+        stream: TcpStream,
+    },
+    /// Connecting to a peer as requested via [`TcpAllocation::connect`] failed.
+    Failed { connection: ConnectionId },
+}
+
+/// A TCP allocation, accepting connections from peers.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062>.
+pub struct TcpAllocation {
+    addr: SocketAddr,
+
+    /// The handle to the task that is accepting connections.
+    ///
+    /// Stored here to make resource-cleanup easy.
+    handle: task::JoinHandle<()>,
+}
+
+impl TcpAllocation {
+    pub fn new(
+        peer_connection_sender: mpsc::Sender<PeerConnectionEvent>,
+        id: AllocationId,
+        ip: IpAddr,
+        port: u16,
+    ) -> Self {
+        let addr = SocketAddr::new(ip, port);
+
+        let task = tokio::spawn(async move {
+            let Err(e) = accept_peer_connections(peer_connection_sender, id, addr).await else {
+                unreachable!()
+            };
+
+            tracing::warn!(allocation = %id, family = %ip.family(), "TCP allocation task failed: {e:#}");
+        });
+
+        Self { addr, handle: task }
+    }
+
+    /// Connect to a peer from the address of this allocation.
+    ///
+    /// The outcome is reported as [`PeerConnectionEvent::Connected`] or [`PeerConnectionEvent::Failed`].
+    pub fn connect(
+        &self,
+        mut peer_connection_sender: mpsc::Sender<PeerConnectionEvent>,
+        connection: ConnectionId,
+        peer: SocketAddr,
+    ) {
+        let addr = self.addr;
+
+        tokio::spawn(async move {
+            let event =
+                match tokio::time::timeout(PEER_CONNECT_TIMEOUT, connect_to_peer(addr, peer))
+                    .await
+                    .context("Timed out")
+                    .and_then(|result| result)
+                {
+                    Ok(stream) => PeerConnectionEvent::Connected { connection, stream },
+                    Err(e) => {
+                        tracing::debug!(%connection, %peer, "Failed to connect to peer: {e:#}");
+
+                        PeerConnectionEvent::Failed { connection }
+                    }
+                };
+
+            let _ = peer_connection_sender.send(event).await;
+        });
+    }
+}
+
+impl Drop for TcpAllocation {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn accept_peer_connections(
+    mut peer_connection_sender: mpsc::Sender<PeerConnectionEvent>,
+    id: AllocationId,
+    addr: SocketAddr,
+) -> Result<Infallible> {
+    let listener = make_tcp_socket(addr)?.listen(1024)?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+
+        peer_connection_sender
+            .send(PeerConnectionEvent::Accepted { id, peer, stream })
+            .await?;
+    }
+}
+
+async fn connect_to_peer(addr: SocketAddr, peer: SocketAddr) -> Result<TcpStream> {
+    let stream = make_tcp_socket(addr)?.connect(peer).await?;
+
+    Ok(stream)
+}
+
+/// Creates a [`TcpSocket`] bound to the address of a TCP allocation.
+///
+/// Connections to peers originate from the same address as the listener of the allocation, thus all of these sockets need `SO_REUSEPORT`.
+/// Like our UDP sockets, this sets the `IPV6_V6ONLY` flag to allow binding to IP4 and IP6 addresses on the same port.
+fn make_tcp_socket(addr: SocketAddr) -> Result<TcpSocket> {
+    use socket2::*;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SockAddr::from(addr))
+        .with_context(|| format!("Failed to bind TCP socket to {addr}"))?;
+
+    Ok(TcpSocket::from_std_stream(socket.into()))
+}
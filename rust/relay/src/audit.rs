@@ -0,0 +1,293 @@
+//! A structured log of the lifecycle of allocations, for retention and incident response.
+
+use crate::{AllocationId, ChannelUsage, Event, Traffic};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// Writes one JSON object per line for each [`Event`] of the server.
+///
+/// Unlike our logs, the records don't depend on the log level and have a stable schema:
+/// Fields may be added over time but are never renamed or removed.
+pub struct AuditLog<W> {
+    writer: W,
+}
+
+impl<W> AuditLog<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Appends a record for the given event and flushes it to the underlying writer.
+    ///
+    /// Events that don't concern the lifecycle of allocations, like captured traffic, are skipped.
+    pub fn record(&mut self, event: &Event, now: SystemTime) -> io::Result<()> {
+        let Some(event) = RecordEvent::from_event(event) else {
+            return Ok(());
+        };
+        let record = Record {
+            timestamp: unix_seconds(now),
+            event,
+        };
+
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// When the event happened, as a UNIX timestamp in seconds.
+    timestamp: u64,
+    #[serde(flatten)]
+    event: RecordEvent<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "event")]
+enum RecordEvent<'a> {
+    AllocationCreated {
+        allocation: AllocationId,
+        client: SocketAddr,
+        username: &'a str,
+        relay_addresses: &'a [SocketAddr],
+        /// In seconds.
+        lifetime: u64,
+    },
+    AllocationRefreshed {
+        allocation: AllocationId,
+        client: SocketAddr,
+        username: &'a str,
+        /// In seconds.
+        lifetime: u64,
+    },
+    AllocationMoved {
+        allocation: AllocationId,
+        username: &'a str,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+    ChannelBound {
+        allocation: AllocationId,
+        client: SocketAddr,
+        username: &'a str,
+        channel: u16,
+        peer: SocketAddr,
+    },
+    AllocationDeleted {
+        allocation: AllocationId,
+        client: SocketAddr,
+        username: &'a str,
+        reason: &'static str,
+        relay_addresses: &'a [SocketAddr],
+        traffic: RecordTraffic,
+        channels: Vec<RecordChannel>,
+    },
+}
+
+/// The data relayed through an allocation or channel, decoupled from [`Traffic`] to keep the schema stable.
+#[derive(Serialize)]
+struct RecordTraffic {
+    bytes_to_peer: u64,
+    packets_to_peer: u64,
+    bytes_to_client: u64,
+    packets_to_client: u64,
+}
+
+impl From<&Traffic> for RecordTraffic {
+    fn from(traffic: &Traffic) -> Self {
+        Self {
+            bytes_to_peer: traffic.bytes_to_peer,
+            packets_to_peer: traffic.packets_to_peer,
+            bytes_to_client: traffic.bytes_to_client,
+            packets_to_client: traffic.packets_to_client,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RecordChannel {
+    channel: u16,
+    peer: SocketAddr,
+    traffic: RecordTraffic,
+}
+
+impl From<&ChannelUsage> for RecordChannel {
+    fn from(usage: &ChannelUsage) -> Self {
+        Self {
+            channel: usage.number,
+            peer: usage.peer,
+            traffic: RecordTraffic::from(&usage.traffic),
+        }
+    }
+}
+
+impl<'a> RecordEvent<'a> {
+    fn from_event(event: &'a Event) -> Option<Self> {
+        let record = match event {
+            Event::AllocationCreated {
+                id,
+                client,
+                username,
+                relay_addresses,
+                lifetime,
+            } => RecordEvent::AllocationCreated {
+                allocation: *id,
+                client: *client,
+                username,
+                relay_addresses,
+                lifetime: lifetime.as_secs(),
+            },
+            Event::AllocationRefreshed {
+                id,
+                client,
+                username,
+                lifetime,
+            } => RecordEvent::AllocationRefreshed {
+                allocation: *id,
+                client: *client,
+                username,
+                lifetime: lifetime.as_secs(),
+            },
+            Event::AllocationMoved {
+                id,
+                username,
+                from,
+                to,
+            } => RecordEvent::AllocationMoved {
+                allocation: *id,
+                username,
+                from: *from,
+                to: *to,
+            },
+            Event::ChannelBound {
+                id,
+                client,
+                username,
+                number,
+                peer,
+            } => RecordEvent::ChannelBound {
+                allocation: *id,
+                client: *client,
+                username,
+                channel: *number,
+                peer: *peer,
+            },
+            Event::AllocationDeleted {
+                usage,
+                reason,
+                relay_addresses,
+            } => RecordEvent::AllocationDeleted {
+                allocation: usage.id,
+                client: usage.client,
+                username: &usage.username,
+                reason: reason.as_str(),
+                relay_addresses,
+                traffic: RecordTraffic::from(&usage.traffic),
+                channels: usage.channels.iter().map(RecordChannel::from).collect(),
+            },
+            Event::CaptureData { .. } | Event::CaptureFinished { .. } => return None,
+        };
+
+        Some(record)
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllocationUsage, DeletionReason};
+    use std::time::Duration;
+
+    #[test]
+    fn records_are_json_lines() {
+        let mut audit_log = AuditLog::new(Vec::new());
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        audit_log
+            .record(
+                &Event::AllocationCreated {
+                    id: serde_json::from_str("1").unwrap(),
+                    client: "203.0.113.1:50000".parse().unwrap(),
+                    username: "1700000600:client".to_owned(),
+                    relay_addresses: vec!["198.51.100.1:49152".parse().unwrap()],
+                    lifetime: Duration::from_secs(600),
+                },
+                now,
+            )
+            .unwrap();
+        audit_log
+            .record(
+                &Event::ChannelBound {
+                    id: serde_json::from_str("1").unwrap(),
+                    client: "203.0.113.1:50000".parse().unwrap(),
+                    username: "1700000600:client".to_owned(),
+                    number: 0x4000,
+                    peer: "192.0.2.1:443".parse().unwrap(),
+                },
+                now,
+            )
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(audit_log.writer).unwrap(),
+            concat!(
+                r#"{"timestamp":1700000000,"event":"allocation_created","allocation":1,"client":"203.0.113.1:50000","username":"1700000600:client","relay_addresses":["198.51.100.1:49152"],"lifetime":600}"#,
+                "\n",
+                r#"{"timestamp":1700000000,"event":"channel_bound","allocation":1,"client":"203.0.113.1:50000","username":"1700000600:client","channel":16384,"peer":"192.0.2.1:443"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn deleted_allocation_record_includes_reason_and_traffic() {
+        let mut audit_log = AuditLog::new(Vec::new());
+        let traffic = Traffic {
+            bytes_to_peer: 100,
+            packets_to_peer: 1,
+            bytes_to_client: 200,
+            packets_to_client: 2,
+        };
+
+        audit_log
+            .record(
+                &Event::AllocationDeleted {
+                    usage: AllocationUsage {
+                        id: serde_json::from_str("1").unwrap(),
+                        client: "203.0.113.1:50000".parse().unwrap(),
+                        username: "1700000600:client".to_owned(),
+                        traffic,
+                        channels: vec![ChannelUsage {
+                            number: 0x4000,
+                            peer: "192.0.2.1:443".parse().unwrap(),
+                            traffic,
+                        }],
+                    },
+                    reason: DeletionReason::Expired,
+                    relay_addresses: vec!["198.51.100.1:49152".parse().unwrap()],
+                },
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1700000600),
+            )
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(audit_log.writer).unwrap(),
+            concat!(
+                r#"{"timestamp":1700000600,"event":"allocation_deleted","allocation":1,"client":"203.0.113.1:50000","username":"1700000600:client","reason":"expired","relay_addresses":["198.51.100.1:49152"],"traffic":{"bytes_to_peer":100,"packets_to_peer":1,"bytes_to_client":200,"packets_to_client":2},"channels":[{"channel":16384,"peer":"192.0.2.1:443","traffic":{"bytes_to_peer":100,"packets_to_peer":1,"bytes_to_client":200,"packets_to_client":2}}]}"#,
+                "\n",
+            )
+        );
+    }
+}
@@ -1,19 +1,77 @@
-use base64::prelude::BASE64_STANDARD_NO_PAD;
+use crate::server::{MessageIntegritySha256, PasswordAlgorithm};
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce as AeadNonce};
+use anyhow::{bail, Result};
+use base64::prelude::{BASE64_STANDARD, BASE64_STANDARD_NO_PAD};
 use base64::Engine;
+use hmac::{Hmac, Mac};
 use once_cell::sync::Lazy;
-use secrecy::{ExposeSecret, SecretString};
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use sha1::Sha1;
 use sha2::digest::FixedOutput;
 use sha2::Sha256;
 use std::borrow::ToOwned;
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::time::{Duration, SystemTime};
-use stun_codec::rfc5389::attributes::{MessageIntegrity, Realm, Username};
-use uuid::Uuid;
+use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Realm, Username};
 
 // TODO: Upstream a const constructor to `stun-codec`.
 pub static FIREZONE: Lazy<Realm> = Lazy::new(|| Realm::new("firezone".to_owned()).unwrap());
 
+/// Prefix of all our nonces.
+///
+/// This is the nonce cookie "obMatJos2" followed by the base64-encoded STUN security features, of which we only set "Password algorithms".
+/// It tells RFC 8489 clients that they may negotiate a password algorithm, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2>.
+const NONCE_COOKIE: &str = "obMatJos2gAAA";
+
+/// A message integrity attribute that can be checked against long-term credentials.
+pub trait LongTermCredential {
+    fn check_long_term_credential(
+        &self,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+    ) -> Result<(), ErrorCode>;
+}
+
+impl LongTermCredential for MessageIntegrity {
+    fn check_long_term_credential(
+        &self,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+    ) -> Result<(), ErrorCode> {
+        MessageIntegrity::check_long_term_credential(self, username, realm, password)
+    }
+}
+
+/// The message integrity of a request.
+///
+/// Requests may carry the HMAC-SHA1 based `MESSAGE-INTEGRITY` or the HMAC-SHA256 based `MESSAGE-INTEGRITY-SHA256`, in which case the key is derived with the negotiated password algorithm.
+pub enum Integrity<'a> {
+    Sha1(&'a MessageIntegrity),
+    Sha256(&'a MessageIntegritySha256, PasswordAlgorithm),
+}
+
+impl LongTermCredential for Integrity<'_> {
+    fn check_long_term_credential(
+        &self,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+    ) -> Result<(), ErrorCode> {
+        match self {
+            Integrity::Sha1(message_integrity) => {
+                message_integrity.check_long_term_credential(username, realm, password)
+            }
+            Integrity::Sha256(message_integrity, algorithm) => {
+                message_integrity.check_long_term_credential(username, realm, password, *algorithm)
+            }
+        }
+    }
+}
+
 pub trait MessageIntegrityExt {
     fn verify(
         &self,
@@ -23,7 +81,10 @@ pub trait MessageIntegrityExt {
     ) -> Result<(), Error>;
 }
 
-impl MessageIntegrityExt for MessageIntegrity {
+impl<T> MessageIntegrityExt for T
+where
+    T: LongTermCredential,
+{
     fn verify(
         &self,
         relay_secret: &SecretString,
@@ -31,7 +92,9 @@ impl MessageIntegrityExt for MessageIntegrity {
         now: SystemTime,
     ) -> Result<(), Error> {
         let (expiry_unix_timestamp, salt) = split_username(username)?;
-        let expired = systemtime_from_unix(expiry_unix_timestamp);
+        let expired = SystemTime::UNIX_EPOCH
+            .checked_add(Duration::from_secs(expiry_unix_timestamp))
+            .ok_or(Error::InvalidUsername)?;
 
         if expired < now {
             return Err(Error::Expired);
@@ -39,7 +102,8 @@ impl MessageIntegrityExt for MessageIntegrity {
 
         let password = generate_password(relay_secret, expired, salt);
 
-        self.check_long_term_credential(
+        LongTermCredential::check_long_term_credential(
+            self,
             &Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
                 .map_err(|_| Error::InvalidUsername)?,
             &FIREZONE,
@@ -51,45 +115,436 @@ impl MessageIntegrityExt for MessageIntegrity {
     }
 }
 
-/// Tracks valid nonces for the TURN relay.
+/// How client credentials are derived from the auth secret.
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialScheme {
+    /// Credentials minted by the portal.
+    ///
+    /// The username is `<expiry>:<salt>` and the password is the base64-encoded SHA256 of `<expiry>:<secret>:<salt>`.
+    #[default]
+    Firezone,
+    /// Credentials as used by coturn's `use-auth-secret`, also known as the "TURN REST API".
+    ///
+    /// The username is `<expiry>:<user>` or just `<expiry>` and the password is the base64-encoded HMAC-SHA1 of the username, keyed by the secret.
+    TurnRest,
+}
+
+impl CredentialScheme {
+    fn verify(
+        &self,
+        message_integrity: &impl LongTermCredential,
+        relay_secret: &SecretString,
+        username: &str,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        match self {
+            CredentialScheme::Firezone => message_integrity.verify(relay_secret, username, now),
+            CredentialScheme::TurnRest => {
+                verify_turn_rest(message_integrity, relay_secret, username, now)
+            }
+        }
+    }
+}
+
+fn verify_turn_rest(
+    message_integrity: &impl LongTermCredential,
+    relay_secret: &SecretString,
+    username: &str,
+    now: SystemTime,
+) -> Result<(), Error> {
+    // Like coturn, we only split off the expiry, thus the user part may contain further separators.
+    let expiry_unix_timestamp = username
+        .split_once(':')
+        .map_or(username, |(expiry, _)| expiry)
+        .parse::<u64>()
+        .map_err(|_| Error::InvalidUsername)?;
+
+    let expiry = SystemTime::UNIX_EPOCH
+        .checked_add(Duration::from_secs(expiry_unix_timestamp))
+        .ok_or(Error::InvalidUsername)?;
+
+    if expiry < now {
+        return Err(Error::Expired);
+    }
+
+    let password = generate_turn_rest_password(relay_secret, username);
+
+    LongTermCredential::check_long_term_credential(
+        message_integrity,
+        &Username::new(username.to_owned()).map_err(|_| Error::InvalidUsername)?,
+        &FIREZONE,
+        &password,
+    )
+    .map_err(|_| Error::InvalidPassword)?;
+
+    Ok(())
+}
+
+/// The secrets we accept credentials for.
 ///
-/// The semantic nature of nonces is an implementation detail of the relay in TURN.
-/// This could just as easily also be a time-based logic (i.e. nonces are valid for 10min).
+/// Credentials are minted with the current secret.
+/// After a rotation, credentials minted with a previous secret remain valid until the end of its grace period.
+/// This allows rotating the secret without invalidating the credentials of existing allocations.
+pub struct AuthSecrets {
+    scheme: CredentialScheme,
+    current: SecretString,
+    /// Previous secrets, together with the time until which we still accept them.
+    previous: Vec<(SecretString, SystemTime)>,
+}
+
+impl AuthSecrets {
+    pub fn new(current: SecretString) -> Self {
+        Self {
+            scheme: CredentialScheme::default(),
+            current,
+            previous: Vec::new(),
+        }
+    }
+
+    pub fn set_scheme(&mut self, scheme: CredentialScheme) {
+        self.scheme = scheme;
+    }
+
+    pub fn current(&self) -> &SecretString {
+        &self.current
+    }
+
+    /// The previous secrets that may still be valid, together with the time until which we accept them.
+    pub fn previous(&self) -> impl Iterator<Item = (&SecretString, SystemTime)> {
+        self.previous
+            .iter()
+            .map(|(secret, valid_until)| (secret, *valid_until))
+    }
+
+    /// Replaces the current secret without keeping the old one around.
+    pub fn set_current(&mut self, secret: SecretString) {
+        self.current = secret;
+    }
+
+    /// Accept credentials minted with the given previous secret until `valid_until`.
+    pub fn add_previous(&mut self, secret: SecretString, valid_until: SystemTime) {
+        self.previous.push((secret, valid_until));
+    }
+
+    /// Makes `secret` the current secret, accepting the old one until `valid_until`.
+    ///
+    /// Previous secrets that are no longer valid at `now` are forgotten.
+    pub fn rotate(&mut self, secret: SecretString, now: SystemTime, valid_until: SystemTime) {
+        let old = std::mem::replace(&mut self.current, secret);
+
+        self.previous.retain(|(_, valid_until)| *valid_until >= now);
+        self.previous.push((old, valid_until));
+    }
+
+    /// Verify the message integrity against the current secret and all previous secrets that are still valid.
+    pub fn verify(
+        &self,
+        message_integrity: &impl LongTermCredential,
+        username: &str,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let result = self
+            .scheme
+            .verify(message_integrity, &self.current, username, now);
+
+        // Only the password depends on the secret, all other errors would be the same for the previous secrets.
+        if result != Err(Error::InvalidPassword) {
+            return result;
+        }
+
+        let valid_with_previous_secret = self
+            .previous
+            .iter()
+            .filter(|(_, valid_until)| *valid_until >= now)
+            .any(|(secret, _)| {
+                self.scheme
+                    .verify(message_integrity, secret, username, now)
+                    .is_ok()
+            });
+
+        if valid_with_previous_secret {
+            return Ok(());
+        }
+
+        result
+    }
+}
+
+/// The keys we decrypt access tokens with, indexed by their key ID.
+///
+/// Access tokens are issued by a third-party authorization server like the portal, see <https://www.rfc-editor.org/rfc/rfc7635>.
+/// They are self-contained: Each token is encrypted with AES-GCM for our server name and carries the key for the message integrity of the client's requests as well as its expiry.
+/// Clients reference the key the token is encrypted with via their `USERNAME`.
+pub struct AccessTokenKeys {
+    server_name: String,
+    keys: HashMap<String, SecretVec<u8>>,
+}
+
+impl AccessTokenKeys {
+    /// The length of the nonces we use for AES-GCM.
+    const NONCE_LEN: usize = 12;
+
+    /// Accept access tokens issued for the given server name.
+    pub fn new(server_name: String) -> Self {
+        Self {
+            server_name,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Accept access tokens encrypted with the given key, which must be 16 (AES-128-GCM) or 32 bytes (AES-256-GCM) long.
+    pub fn with_key(mut self, key_id: String, key: Vec<u8>) -> Result<Self> {
+        if !matches!(key.len(), 16 | 32) {
+            bail!(
+                "Access token key '{key_id}' must be 16 or 32 bytes long, got {} bytes",
+                key.len()
+            )
+        }
+
+        self.keys.insert(key_id, SecretVec::new(key));
+
+        Ok(self)
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Issues an access token for the given `mac_key`, encrypted with the key `key_id`.
+    ///
+    /// The token layout is defined in <https://www.rfc-editor.org/rfc/rfc7635#section-6.2>.
+    pub fn seal(
+        &self,
+        key_id: &str,
+        nonce: [u8; 12],
+        mac_key: &[u8],
+        issued_at: SystemTime,
+        lifetime: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let key = self.keys.get(key_id).ok_or(Error::UnknownKey)?;
+
+        let mut plaintext = Vec::with_capacity(2 + mac_key.len() + 8 + 4);
+        plaintext.extend_from_slice(&(mac_key.len() as u16).to_be_bytes());
+        plaintext.extend_from_slice(mac_key);
+        plaintext.extend_from_slice(&to_token_timestamp(issued_at).to_be_bytes());
+        plaintext.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+
+        let encrypted_block = aes_gcm_encrypt(
+            key.expose_secret(),
+            &nonce,
+            &plaintext,
+            self.server_name.as_bytes(),
+        )
+        .ok_or(Error::InvalidAccessToken)?;
+
+        let mut token = Vec::with_capacity(2 + nonce.len() + encrypted_block.len());
+        token.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&encrypted_block);
+
+        Ok(token)
+    }
+
+    /// Decrypts the given access token with the key `key_id` if it is still valid.
+    pub fn open(
+        &self,
+        key_id: &str,
+        token: &[u8],
+        now: SystemTime,
+    ) -> Result<OpenedAccessToken, Error> {
+        let key = self.keys.get(key_id).ok_or(Error::UnknownKey)?;
+
+        let (nonce_len, rest) = split_u16(token)?;
+        if usize::from(nonce_len) != Self::NONCE_LEN || rest.len() < Self::NONCE_LEN {
+            return Err(Error::InvalidAccessToken);
+        }
+        let (nonce, encrypted_block) = rest.split_at(Self::NONCE_LEN);
+
+        let plaintext = aes_gcm_decrypt(
+            key.expose_secret(),
+            nonce,
+            encrypted_block,
+            self.server_name.as_bytes(),
+        )
+        .ok_or(Error::InvalidAccessToken)?;
+
+        let (key_len, rest) = split_u16(&plaintext)?;
+        let key_len = usize::from(key_len);
+        if rest.len() != key_len + 8 + 4 {
+            return Err(Error::InvalidAccessToken);
+        }
+        let (mac_key, rest) = rest.split_at(key_len);
+        let (timestamp, lifetime) = rest.split_at(8);
+
+        let issued_at =
+            from_token_timestamp(u64::from_be_bytes(timestamp.try_into().expect("8 bytes")));
+        let lifetime = u32::from_be_bytes(lifetime.try_into().expect("4 bytes"));
+
+        if issued_at + Duration::from_secs(u64::from(lifetime)) <= now {
+            return Err(Error::Expired);
+        }
+
+        Ok(OpenedAccessToken {
+            mac_key: mac_key.to_vec(),
+            session: format!("{key_id}/{}", hex::encode(nonce)),
+        })
+    }
+}
+
+/// The contents of a valid access token.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OpenedAccessToken {
+    /// The key for the message integrity of the client's requests.
+    pub mac_key: Vec<u8>,
+    /// Identifies the session the token was issued for.
+    ///
+    /// AES-GCM requires a unique nonce for every token encrypted with the same key, so the key ID together with the nonce is unique per token.
+    pub session: String,
+}
+
+/// Encrypts with AES-128-GCM or AES-256-GCM, depending on the length of the key.
+fn aes_gcm_encrypt(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let nonce = AeadNonce::from_slice(nonce);
+    let payload = Payload { msg, aad };
+
+    match key.len() {
+        16 => <Aes128Gcm as aes_gcm::KeyInit>::new_from_slice(key)
+            .ok()?
+            .encrypt(nonce, payload)
+            .ok(),
+        32 => <Aes256Gcm as aes_gcm::KeyInit>::new_from_slice(key)
+            .ok()?
+            .encrypt(nonce, payload)
+            .ok(),
+        _ => None,
+    }
+}
+
+/// Decrypts with AES-128-GCM or AES-256-GCM, depending on the length of the key.
+fn aes_gcm_decrypt(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let nonce = AeadNonce::from_slice(nonce);
+    let payload = Payload { msg, aad };
+
+    match key.len() {
+        16 => <Aes128Gcm as aes_gcm::KeyInit>::new_from_slice(key)
+            .ok()?
+            .decrypt(nonce, payload)
+            .ok(),
+        32 => <Aes256Gcm as aes_gcm::KeyInit>::new_from_slice(key)
+            .ok()?
+            .decrypt(nonce, payload)
+            .ok(),
+        _ => None,
+    }
+}
+
+fn split_u16(bytes: &[u8]) -> Result<(u16, &[u8]), Error> {
+    if bytes.len() < 2 {
+        return Err(Error::InvalidAccessToken);
+    }
+    let (value, rest) = bytes.split_at(2);
+
+    Ok((u16::from_be_bytes([value[0], value[1]]), rest))
+}
+
+/// Access tokens carry their issue time as 48 bits of seconds and 16 bits of fractions of a second since the UNIX epoch.
+fn to_token_timestamp(time: SystemTime) -> u64 {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let fraction = (u64::from(since_epoch.subsec_nanos()) << 16) / 1_000_000_000;
+
+    (since_epoch.as_secs() << 16) | fraction
+}
+
+fn from_token_timestamp(timestamp: u64) -> SystemTime {
+    let nanos = ((timestamp & 0xFFFF) * 1_000_000_000) >> 16;
+
+    SystemTime::UNIX_EPOCH + Duration::new(timestamp >> 16, nanos as u32)
+}
+
+/// Issues and validates nonces for the TURN relay.
 ///
-/// For simplicity reasons, we use a count-based strategy.
-/// Each nonce can be used for a certain number of requests before it is invalid.
-#[derive(Default)]
+/// Nonces are stateless: Each nonce consists of the time it was issued and an HMAC over this timestamp and the client's address, keyed by a secret.
+/// Validating a nonce thus doesn't require us to remember it, which means unauthenticated clients cannot make us allocate memory by requesting nonces.
+/// It also allows relays that share the same secret to accept each other's nonces.
+#[derive(Clone)]
 pub struct Nonces {
-    inner: HashMap<Uuid, u64>,
+    secret: SecretString,
+    lifetime: Duration,
 }
 
 impl Nonces {
-    /// How many requests a client can perform with the same nonce.
-    const NUM_REQUESTS: u64 = 10;
+    /// How long a nonce is valid by default.
+    pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+    pub fn new(secret: SecretString, lifetime: Duration) -> Self {
+        Self { secret, lifetime }
+    }
 
-    pub fn add_new(&mut self, nonce: Uuid) {
-        self.inner.insert(nonce, Self::NUM_REQUESTS);
+    pub fn secret(&self) -> &SecretString {
+        &self.secret
     }
 
-    /// Record the usage of a nonce in a request.
-    pub fn handle_nonce_used(&mut self, nonce: Uuid) -> Result<(), Error> {
-        let mut entry = match self.inner.entry(nonce) {
-            Entry::Vacant(_) => return Err(Error::InvalidNonce),
-            Entry::Occupied(entry) => entry,
-        };
+    pub fn set_secret(&mut self, secret: SecretString) {
+        self.secret = secret;
+    }
 
-        let remaining_requests = entry.get_mut();
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
 
-        if *remaining_requests == 0 {
-            entry.remove();
+    /// Issue a new nonce for the given client.
+    pub fn issue(&self, client: SocketAddr, now: SystemTime) -> String {
+        let issued_at = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("now must be later than UNIX_EPOCH")
+            .as_secs();
 
+        format!("{NONCE_COOKIE}{issued_at}:{}", self.mac(issued_at, client))
+    }
+
+    /// Verify that the given nonce was issued by us, for this client and is not yet expired.
+    pub fn verify(&self, nonce: &str, client: SocketAddr, now: SystemTime) -> Result<(), Error> {
+        let (issued_at, mac) = nonce
+            .strip_prefix(NONCE_COOKIE)
+            .and_then(|nonce| nonce.split_once(':'))
+            .ok_or(Error::InvalidNonce)?;
+        let issued_at = issued_at.parse::<u64>().map_err(|_| Error::InvalidNonce)?;
+
+        if !constant_time_eq(mac.as_bytes(), self.mac(issued_at, client).as_bytes()) {
             return Err(Error::InvalidNonce);
         }
 
-        *remaining_requests -= 1;
+        // A lifetime that exceeds what `SystemTime` can represent never ends.
+        if systemtime_from_unix(issued_at)
+            .checked_add(self.lifetime)
+            .is_some_and(|expires_at| expires_at < now)
+        {
+            return Err(Error::ExpiredNonce);
+        }
 
         Ok(())
     }
+
+    fn mac(&self, issued_at: u64, client: SocketAddr) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+
+        mac.update(&issued_at.to_be_bytes());
+        mac.update(client.to_string().as_bytes());
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Compares two byte slices without leaking the position of the first difference via timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 #[derive(Debug, PartialEq)]
@@ -98,6 +553,24 @@ pub enum Error {
     InvalidPassword,
     InvalidUsername,
     InvalidNonce,
+    ExpiredNonce,
+    UnknownKey,
+    InvalidAccessToken,
+}
+
+impl Error {
+    /// A short, machine-readable description of the error, suitable as a metric label.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::Expired => "expired",
+            Error::InvalidPassword => "invalid_password",
+            Error::InvalidUsername => "invalid_username",
+            Error::InvalidNonce => "invalid_nonce",
+            Error::ExpiredNonce => "expired_nonce",
+            Error::UnknownKey => "unknown_key",
+            Error::InvalidAccessToken => "invalid_access_token",
+        }
+    }
 }
 
 pub(crate) fn split_username(username: &str) -> Result<(u64, &str), Error> {
@@ -137,6 +610,15 @@ pub(crate) fn generate_password(
     BASE64_STANDARD_NO_PAD.encode(array.as_slice())
 }
 
+pub(crate) fn generate_turn_rest_password(relay_secret: &SecretString, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(relay_secret.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any length");
+
+    mac.update(username.as_bytes());
+
+    BASE64_STANDARD.encode(mac.finalize().into_bytes())
+}
+
 pub(crate) fn systemtime_from_unix(seconds: u64) -> SystemTime {
     SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
 }
@@ -242,33 +724,437 @@ mod tests {
     }
 
     #[test]
-    fn nonces_are_valid_for_10_requests() {
-        let mut nonces = Nonces::default();
-        let nonce = Uuid::new_v4();
+    fn unrepresentable_expiry_is_invalid_username() {
+        let username = format!("{}:n23JJ2wKKtt30oXi", u64::MAX);
+        let message_integrity = MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.clone()).unwrap(),
+            &FIREZONE,
+            "password",
+        )
+        .unwrap();
 
-        nonces.add_new(nonce);
+        let result = message_integrity.verify(
+            &RELAY_SECRET_1.parse().unwrap(),
+            &username,
+            systemtime_from_unix(1685200000),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidUsername)
+    }
+
+    #[test]
+    fn generate_turn_rest_password_test_vector() {
+        let password =
+            generate_turn_rest_password(&RELAY_SECRET_1.parse().unwrap(), "1685200000:alice");
+
+        assert_eq!(password, "vN+SrsNUl3Gre2AmkovLjr1T/pc=")
+    }
+
+    #[test]
+    fn turn_rest_credentials_are_valid() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+        let now = systemtime_from_unix(1685200000 - 1000);
 
-        for _ in 0..10 {
-            nonces.handle_nonce_used(nonce).unwrap();
+        for username in ["1685200000:alice", "1685200000"] {
+            let message_integrity = turn_rest_message_integrity(RELAY_SECRET_1, username);
+
+            secrets.verify(&message_integrity, username, now).unwrap();
+        }
+    }
+
+    #[test]
+    fn turn_rest_user_may_contain_separator() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+        let message_integrity =
+            turn_rest_message_integrity(RELAY_SECRET_1, "1685200000:alice:laptop");
+
+        secrets
+            .verify(
+                &message_integrity,
+                "1685200000:alice:laptop",
+                systemtime_from_unix(1685200000 - 1000),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn expired_turn_rest_credentials_are_not_valid() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+        let message_integrity = turn_rest_message_integrity(RELAY_SECRET_1, "1685200000:alice");
+
+        let result = secrets.verify(
+            &message_integrity,
+            "1685200000:alice",
+            systemtime_from_unix(1685200000 + 1),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::Expired)
+    }
+
+    #[test]
+    fn unrepresentable_turn_rest_expiry_is_invalid_username() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+
+        for username in [format!("{}", u64::MAX), format!("{}:alice", u64::MAX)] {
+            let message_integrity = turn_rest_message_integrity(RELAY_SECRET_1, &username);
+
+            let result = secrets.verify(
+                &message_integrity,
+                &username,
+                systemtime_from_unix(1685200000),
+            );
+
+            assert_eq!(result.unwrap_err(), Error::InvalidUsername)
         }
+    }
+
+    #[test]
+    fn turn_rest_credentials_of_other_secret_are_not_valid() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+        let message_integrity = turn_rest_message_integrity(RELAY_SECRET_2, "1685200000:alice");
+
+        let result = secrets.verify(
+            &message_integrity,
+            "1685200000:alice",
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn turn_rest_username_without_expiry_is_not_valid() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+        let message_integrity = turn_rest_message_integrity(RELAY_SECRET_1, "alice");
+
+        let result = secrets.verify(
+            &message_integrity,
+            "alice",
+            systemtime_from_unix(1685200000),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidUsername)
+    }
+
+    #[test]
+    fn firezone_credentials_are_not_valid_in_turn_rest_scheme() {
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.set_scheme(CredentialScheme::TurnRest);
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
 
+        let result = secrets.verify(
+            &message_integrity,
+            "1685200000:n23JJ2wKKtt30oXi",
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn credentials_of_previous_secret_are_valid_until_end_of_grace_period() {
+        let now = systemtime_from_unix(1685200000 - 1000);
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        secrets.rotate(
+            RELAY_SECRET_2.parse().unwrap(),
+            now,
+            now + Duration::from_secs(60),
+        );
+
+        secrets
+            .verify(&message_integrity, "1685200000:n23JJ2wKKtt30oXi", now)
+            .unwrap();
         assert_eq!(
-            nonces.handle_nonce_used(nonce).unwrap_err(),
+            secrets
+                .verify(
+                    &message_integrity,
+                    "1685200000:n23JJ2wKKtt30oXi",
+                    now + Duration::from_secs(61)
+                )
+                .unwrap_err(),
+            Error::InvalidPassword
+        );
+    }
+
+    #[test]
+    fn credentials_of_current_secret_are_valid_after_rotation() {
+        let now = systemtime_from_unix(1685200000 - 1000);
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_2.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        secrets.rotate(RELAY_SECRET_2.parse().unwrap(), now, now);
+
+        secrets
+            .verify(
+                &message_integrity,
+                "1685200000:n23JJ2wKKtt30oXi",
+                now + Duration::from_secs(1),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn rotation_forgets_expired_previous_secrets() {
+        let now = systemtime_from_unix(1685200000);
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+
+        secrets.rotate(
+            RELAY_SECRET_2.parse().unwrap(),
+            now,
+            now + Duration::from_secs(60),
+        );
+        secrets.rotate(
+            RELAY_SECRET_1.parse().unwrap(),
+            now + Duration::from_secs(120),
+            now + Duration::from_secs(180),
+        );
+
+        assert_eq!(secrets.previous().count(), 1);
+    }
+
+    #[test]
+    fn issued_nonces_are_valid() {
+        let nonces = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::from_secs(600));
+        let now = systemtime_from_unix(1685200000);
+        let client = "203.0.113.1:50000".parse().unwrap();
+
+        let nonce = nonces.issue(client, now);
+
+        nonces.verify(&nonce, client, now).unwrap();
+        nonces
+            .verify(&nonce, client, now + Duration::from_secs(600))
+            .unwrap();
+    }
+
+    #[test]
+    fn nonces_expire_after_their_lifetime() {
+        let nonces = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::from_secs(600));
+        let now = systemtime_from_unix(1685200000);
+        let client = "203.0.113.1:50000".parse().unwrap();
+
+        let nonce = nonces.issue(client, now);
+
+        assert_eq!(
+            nonces
+                .verify(&nonce, client, now + Duration::from_secs(601))
+                .unwrap_err(),
+            Error::ExpiredNonce
+        );
+    }
+
+    #[test]
+    fn nonces_with_unrepresentable_expiry_do_not_expire() {
+        let nonces = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::MAX);
+        let now = systemtime_from_unix(1685200000);
+        let client = "203.0.113.1:50000".parse().unwrap();
+
+        let nonce = nonces.issue(client, now);
+
+        nonces
+            .verify(&nonce, client, now + Duration::from_secs(601))
+            .unwrap();
+    }
+
+    #[test]
+    fn nonces_are_bound_to_the_client_address() {
+        let nonces = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::from_secs(600));
+        let now = systemtime_from_unix(1685200000);
+
+        let nonce = nonces.issue("203.0.113.1:50000".parse().unwrap(), now);
+
+        assert_eq!(
+            nonces
+                .verify(&nonce, "203.0.113.1:50001".parse().unwrap(), now)
+                .unwrap_err(),
+            Error::InvalidNonce
+        );
+    }
+
+    #[test]
+    fn nonces_of_other_secret_are_invalid() {
+        let nonces_1 = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::from_secs(600));
+        let nonces_2 = Nonces::new(RELAY_SECRET_2.parse().unwrap(), Duration::from_secs(600));
+        let now = systemtime_from_unix(1685200000);
+        let client = "203.0.113.1:50000".parse().unwrap();
+
+        let nonce = nonces_1.issue(client, now);
+
+        assert_eq!(
+            nonces_2.verify(&nonce, client, now).unwrap_err(),
             Error::InvalidNonce
         );
     }
 
     #[test]
-    fn unknown_nonces_are_invalid() {
-        let mut nonces = Nonces::default();
-        let nonce = Uuid::new_v4();
+    fn tampered_timestamp_is_invalid() {
+        let nonces = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::from_secs(600));
+        let now = systemtime_from_unix(1685200000);
+        let client = "203.0.113.1:50000".parse().unwrap();
+
+        let nonce = nonces.issue(client, now);
+        let (_, mac) = nonce.split_once(':').unwrap();
+        let tampered = format!("{NONCE_COOKIE}{}:{mac}", 1685200000 + 3600);
 
         assert_eq!(
-            nonces.handle_nonce_used(nonce).unwrap_err(),
+            nonces
+                .verify(&tampered, client, now + Duration::from_secs(3600))
+                .unwrap_err(),
             Error::InvalidNonce
         );
     }
 
+    #[test]
+    fn malformed_nonces_are_invalid() {
+        let nonces = Nonces::new(RELAY_SECRET_1.parse().unwrap(), Duration::from_secs(600));
+        let now = systemtime_from_unix(1685200000);
+        let client = "203.0.113.1:50000".parse().unwrap();
+
+        for nonce in [
+            "",
+            "foobar",
+            "abc:def",
+            "1685200000",
+            "obMatJos2gAAA1685200000",
+        ] {
+            assert_eq!(
+                nonces.verify(nonce, client, now).unwrap_err(),
+                Error::InvalidNonce
+            );
+        }
+    }
+
+    #[test]
+    fn sealed_access_tokens_can_be_opened() {
+        let keys = access_token_keys();
+        let now = systemtime_from_unix(1685200000);
+
+        let token = keys
+            .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(keys.open("kid", &token, now).unwrap().mac_key, vec![2; 32]);
+    }
+
+    #[test]
+    fn access_tokens_identify_their_session() {
+        let keys = access_token_keys();
+        let now = systemtime_from_unix(1685200000);
+
+        let token = keys
+            .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+        let other_token = keys
+            .seal("kid", [5; 12], &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(
+            keys.open("kid", &token, now).unwrap().session,
+            "kid/010101010101010101010101"
+        );
+        assert_ne!(
+            keys.open("kid", &token, now).unwrap().session,
+            keys.open("kid", &other_token, now).unwrap().session
+        );
+    }
+
+    #[test]
+    fn access_tokens_expire_after_their_lifetime() {
+        let keys = access_token_keys();
+        let now = systemtime_from_unix(1685200000);
+
+        let token = keys
+            .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(
+            keys.open("kid", &token, now + Duration::from_secs(3600))
+                .unwrap_err(),
+            Error::Expired
+        );
+    }
+
+    #[test]
+    fn access_tokens_are_bound_to_key_and_server_name() {
+        let keys = access_token_keys()
+            .with_key("other".to_owned(), vec![4; 32])
+            .unwrap();
+        let other_server = AccessTokenKeys::new("other.example.com".to_owned())
+            .with_key("kid".to_owned(), vec![3; 16])
+            .unwrap();
+        let now = systemtime_from_unix(1685200000);
+
+        let token = keys
+            .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(
+            keys.open("other", &token, now).unwrap_err(),
+            Error::InvalidAccessToken
+        );
+        assert_eq!(
+            keys.open("unknown", &token, now).unwrap_err(),
+            Error::UnknownKey
+        );
+        assert_eq!(
+            other_server.open("kid", &token, now).unwrap_err(),
+            Error::InvalidAccessToken
+        );
+    }
+
+    #[test]
+    fn tampered_access_tokens_are_invalid() {
+        let keys = access_token_keys();
+        let now = systemtime_from_unix(1685200000);
+
+        let mut token = keys
+            .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+        *token.last_mut().unwrap() ^= 1;
+
+        assert_eq!(
+            keys.open("kid", &token, now).unwrap_err(),
+            Error::InvalidAccessToken
+        );
+        assert_eq!(
+            keys.open("kid", &[0, 12, 1], now).unwrap_err(),
+            Error::InvalidAccessToken
+        );
+    }
+
+    #[test]
+    fn access_token_keys_must_be_16_or_32_bytes() {
+        assert!(access_token_keys()
+            .with_key("short".to_owned(), vec![0; 8])
+            .is_err());
+    }
+
+    fn access_token_keys() -> AccessTokenKeys {
+        AccessTokenKeys::new("relay.example.com".to_owned())
+            .with_key("kid".to_owned(), vec![3; 16])
+            .unwrap()
+    }
+
     fn message_integrity(
         relay_secret: &SecretString,
         username_expiry: u64,
@@ -290,6 +1176,18 @@ mod tests {
         .unwrap()
     }
 
+    fn turn_rest_message_integrity(relay_secret: &str, username: &str) -> MessageIntegrity {
+        let password = generate_turn_rest_password(&relay_secret.parse().unwrap(), username);
+
+        MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.to_owned()).unwrap(),
+            &FIREZONE,
+            &password,
+        )
+        .unwrap()
+    }
+
     fn sample_message() -> Message<Attribute> {
         Message::new(
             MessageClass::Request,
@@ -1,16 +1,124 @@
 use anyhow::Result;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::{Router, Server};
+use prometheus::{Encoder, Registry, TextEncoder};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-pub async fn serve(addr: impl Into<SocketAddr>) -> Result<()> {
+/// Whether the relay is ready to accept new allocations.
+///
+/// Updated by the event loop and reported via the `/readyz` endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set(&self, ready: bool) {
+        self.0.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    registry: Registry,
+    readiness: Readiness,
+}
+
+/// Serves our health-check server.
+///
+/// - `/healthz` always returns 200 as long as the process is running.
+/// - `/readyz` returns 200 if we accept new allocations and 503 otherwise.
+/// - `/metrics` returns our metrics in the Prometheus text format.
+///
+/// This server is meant to be reachable from within the deployment infrastructure only.
+pub async fn serve(
+    addr: impl Into<SocketAddr>,
+    registry: Registry,
+    readiness: Readiness,
+) -> Result<()> {
     let addr = addr.into();
 
     let service = Router::new()
         .route("/healthz", get(|| async { "" }))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .with_state(AppState {
+            registry,
+            readiness,
+        })
         .into_make_service();
 
     Server::try_bind(&addr)?.serve(service).await?;
 
     Ok(())
 }
+
+async fn readyz(State(state): State<AppState>) -> StatusCode {
+    if state.readiness.get() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&state.registry.gather(), &mut buffer) {
+        tracing::warn!("Failed to encode metrics: {e}");
+
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        buffer,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+    use prometheus::IntCounter;
+
+    #[tokio::test]
+    async fn metrics_are_served_in_prometheus_text_format() {
+        let registry = Registry::new();
+        let counter = IntCounter::new("allocations_total", "The number of allocations").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc_by(3);
+
+        let response = metrics(State(AppState {
+            registry,
+            readiness: Readiness::default(),
+        }))
+        .await
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while let Some(chunk) = body.data().await {
+            text.extend_from_slice(&chunk.unwrap());
+        }
+        let text = String::from_utf8(text).unwrap();
+
+        assert!(text.contains("# TYPE allocations_total counter"));
+        assert!(text.contains("allocations_total 3"));
+    }
+}
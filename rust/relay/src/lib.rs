@@ -6,19 +6,29 @@ mod sleep;
 mod time_events;
 mod udp_socket;
 
+pub mod admin;
+pub mod audit;
 pub mod health_check;
+pub mod messages;
 #[cfg(feature = "proptest")]
 pub mod proptest;
+pub mod stream;
 
-pub use allocation::Allocation;
+pub use allocation::{Allocation, PeerConnectionEvent, TcpAllocation};
+pub use auth::{AccessTokenKeys, CredentialScheme, OpenedAccessToken};
 pub use net_ext::{IpAddrExt, SocketAddrExt};
 pub use server::{
-    Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    AccessToken, Allocate, AllocationId, AllocationInfo, AllocationUsage, Attribute, Binding,
+    CaptureEnd, CaptureFilter, CaptureId, ChannelBind, ChannelData, ChannelInfo, ChannelUsage,
+    ClientMessage, Command, Connect, ConnectionAlreadyExists, ConnectionBind, ConnectionId,
+    ConnectionTimeoutOrFailure, CreatePermission, DeletionReason, DiscoverySocket, Event, Limits,
+    MessageIntegritySha256, NatDiscovery, PasswordAlgorithm, PasswordAlgorithms, PeerFilter,
+    Refresh, SendIndication, Server, Snapshot, ThirdPartyAuthorization, Traffic,
+    MAX_CAPTURE_DURATION, MIN_CAPTURE_BYTES,
 };
 pub use sleep::Sleep;
 pub use stun_codec::rfc8656::attributes::AddressFamily;
-pub use udp_socket::UdpSocket;
+pub use udp_socket::{DatagramBatch, UdpSocket, BATCH_SIZE};
 
 pub(crate) use time_events::TimeEvents;
 
@@ -1,30 +1,61 @@
 use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
 use clap::Parser;
+use firezone_relay::admin::{AdminRequest, StartCaptureError, StartedCapture};
+use firezone_relay::audit::AuditLog;
+use firezone_relay::health_check::Readiness;
+use firezone_relay::messages::{
+    EgressMessages, IngressMessages, IngressReplies, RevokeUser, RotateAuthSecret,
+    RotateNonceSecret, SetLogLevel, SiblingRelays, StartDraining,
+};
+use firezone_relay::stream::{Outbound, StreamEvent};
 use firezone_relay::{
-    AddressFamily, Allocation, AllocationId, Command, IpStack, Server, Sleep, SocketAddrExt,
-    UdpSocket,
+    stream, AccessTokenKeys, AddressFamily, Allocation, AllocationId, CaptureFilter, CaptureId,
+    Command, ConnectionId, CredentialScheme, DatagramBatch, DeletionReason, DiscoverySocket,
+    IpStack, Limits, NatDiscovery, PeerConnectionEvent, PeerFilter, Server, Sleep, Snapshot,
+    SocketAddrExt, TcpAllocation, UdpSocket, BATCH_SIZE,
 };
 use futures::channel::mpsc;
-use futures::{future, FutureExt, SinkExt, StreamExt};
+use futures::{future, FutureExt, Sink, SinkExt, StreamExt};
+use ip_network::IpNetwork;
 use opentelemetry::{sdk, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use phoenix_channel::{Error, Event, PhoenixChannel, SecureUrl};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
-use secrecy::{Secret, SecretString};
+use secrecy::{ExposeSecret, Secret, SecretString};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::Poll;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
 use tracing::{level_filters::LevelFilter, Instrument, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use tracing_subscriber::layer::{Filter, SubscriberExt};
+use tracing_subscriber::{reload, util::SubscriberInitExt, EnvFilter, Layer, Registry};
 use url::Url;
 
+/// The maximum number of datagrams buffered in the channel to each main UDP socket task.
+///
+/// Large enough to fill a few batches while the task is busy sending the previous one.
+const MAX_BUFFERED_OUTBOUND_DATAGRAMS: usize = 4 * BATCH_SIZE;
+
+/// The channel to a UDP socket task, carrying datagrams and their recipient.
+type OutboundDatagramSender = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+
+/// Allows replacing the filter of our logs at runtime.
+type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
 #[derive(Parser, Debug)]
 struct Args {
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
@@ -33,18 +64,192 @@ struct Args {
     /// The public (i.e. internet-reachable) IPv6 address of the relay server.
     #[arg(long, env)]
     public_ip6_addr: Option<Ipv6Addr>,
+    /// The local addresses on which we accept TURN clients over UDP, at most one per address family.
+    ///
+    /// Defaults to port 3478 on all interfaces of each family we have a public address for.
+    /// The IP of each listen address is also used for TCP / TLS listeners and the relayed sockets of allocations.
+    /// Set this if the public address is not a local address (e.g. behind a 1:1 NAT) or to run multiple relays on one host.
+    #[arg(long, env, value_delimiter = ',')]
+    listen_addrs: Vec<SocketAddr>,
+    /// The number of UDP sockets per listen address that receive and send client traffic in parallel.
+    ///
+    /// With more than one, the sockets are bound with `SO_REUSEPORT` and the kernel distributes clients across them.
+    /// This only spreads the syscalls across cores: all datagrams are still processed by a single task.
+    #[arg(long, env, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    udp_workers: u16,
+    /// A second port on which we answer STUN binding requests, enabling NAT behaviour discovery (RFC 5780).
+    ///
+    /// Clients can then ask us to respond from this port, e.g. to find out how their NAT filters incoming traffic.
+    /// Requires the same listen port for IPv4 and IPv6.
+    #[arg(long, env)]
+    nat_discovery_port: Option<u16>,
+    /// A second public IPv4 address on which we answer STUN binding requests for NAT behaviour discovery.
+    ///
+    /// The address must be assigned to a local interface and requires an IPv4 listen address other than `0.0.0.0`.
+    #[arg(long, env, requires = "nat_discovery_port")]
+    nat_discovery_ip4_addr: Option<Ipv4Addr>,
+    /// A second public IPv6 address on which we answer STUN binding requests for NAT behaviour discovery.
+    ///
+    /// The address must be assigned to a local interface and requires an IPv6 listen address other than `[::]`.
+    #[arg(long, env, requires = "nat_discovery_port")]
+    nat_discovery_ip6_addr: Option<Ipv6Addr>,
     /// The address of the local interface where we should serve our health-check endpoint.
     ///
     /// The actual health-check endpoint will be at `http://<health_check_addr>/healthz`.
+    /// Metrics in the Prometheus text format are served at `http://<health_check_addr>/metrics`.
     #[arg(long, env, hide = true, default_value = "0.0.0.0:8080")]
     health_check_addr: SocketAddr,
+    /// The address of the local interface where we should serve the admin API.
+    ///
+    /// The admin API allows listing and deleting allocations and requires `--admin-token`.
+    /// If omitted, the admin API is disabled.
+    #[arg(long, env, requires = "admin_token")]
+    admin_addr: Option<SocketAddr>,
+    /// The bearer token that requests to the admin API must carry.
+    #[arg(long, env)]
+    admin_token: Option<SecretString>,
     // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
     /// The lowest port used for TURN allocations.
-    #[arg(long, env, hide = true, default_value = "49152")]
+    ///
+    /// Relays sharing an IP must use disjoint port ranges.
+    #[arg(long, env, default_value = "49152")]
     lowest_port: u16,
     /// The highest port used for TURN allocations.
-    #[arg(long, env, hide = true, default_value = "65535")]
+    ///
+    /// Must be greater than `--lowest-port`.
+    #[arg(long, env, default_value = "65535")]
     highest_port: u16,
+    /// The port on which we accept TURN clients over TCP.
+    ///
+    /// If omitted, we only accept clients over UDP.
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+    #[arg(long, env)]
+    tcp_port: Option<u16>,
+    /// The ports on which we accept TURN clients over TLS, typically 443 and/or 5349.
+    ///
+    /// Requires `--tls-cert-file` and `--tls-key-file`.
+    #[arg(long, env, value_delimiter = ',', requires_all = ["tls_cert_file", "tls_key_file"])]
+    tls_ports: Vec<u16>,
+    /// Path to the PEM-encoded certificate chain to use for TLS connections.
+    #[arg(long, env)]
+    tls_cert_file: Option<PathBuf>,
+    /// Path to the PEM-encoded private key to use for TLS connections.
+    #[arg(long, env)]
+    tls_key_file: Option<PathBuf>,
+    /// Sibling relays to redirect clients to once we stop accepting new allocations, e.g. because we are draining.
+    ///
+    /// If omitted, new allocations are rejected with a 508 (Insufficient Capacity) instead.
+    /// When connected to the portal, this list is replaced by the sibling relays the portal sends us.
+    #[arg(long, env, value_delimiter = ',')]
+    alternate_servers: Vec<SocketAddr>,
+    /// Networks that clients may not relay data to, in CIDR notation.
+    ///
+    /// These are denied in addition to loopback, link-local, private (RFC 1918, RFC 6598 and RFC 4193), multicast and cloud metadata addresses as well as our own public and listen addresses.
+    #[arg(long, env, value_delimiter = ',')]
+    denied_peer_networks: Vec<IpNetwork>,
+    /// Networks that clients may relay data to even though they are denied, in CIDR notation.
+    ///
+    /// This also overrides the networks denied by default, except for our own public and listen addresses.
+    /// For example, pass `10.0.0.0/8` to let clients reach peers in a private network next to the relay.
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_peer_networks: Vec<IpNetwork>,
+    /// The fraction of the port range (between 0.0 and 1.0) in use above which we redirect new allocations to a sibling relay.
+    ///
+    /// Has no effect without any alternate servers.
+    #[arg(long, env)]
+    load_shedding_threshold: Option<f64>,
+    /// How long to wait for existing allocations to expire when draining, in seconds.
+    ///
+    /// Draining starts upon SIGTERM or a `POST /drain` on the health-check server.
+    /// The relay exits once all allocations are gone or this timeout is reached, whichever comes first.
+    /// Allocations that are left at the timeout are deleted, unless they are persisted to `--state-file`.
+    #[arg(long, env, default_value = "600")]
+    drain_timeout: u64,
+    /// The maximum number of concurrent allocations per username.
+    #[arg(long, env)]
+    max_allocations_per_user: Option<usize>,
+    /// The maximum number of concurrent allocations per source IP.
+    #[arg(long, env)]
+    max_allocations_per_ip: Option<usize>,
+    /// The maximum number of concurrent channel bindings per username.
+    #[arg(long, env)]
+    max_channel_bindings_per_user: Option<usize>,
+    /// The maximum number of concurrent channel bindings per source IP.
+    #[arg(long, env)]
+    max_channel_bindings_per_ip: Option<usize>,
+    /// The maximum number of bytes per second relayed for a single username.
+    ///
+    /// Excess data is dropped.
+    #[arg(long, env)]
+    max_bandwidth_per_user: Option<u64>,
+    /// The maximum number of bytes per second relayed for a single source IP.
+    ///
+    /// Excess data is dropped.
+    #[arg(long, env)]
+    max_bandwidth_per_ip: Option<u64>,
+    /// The secret used to authenticate clients.
+    ///
+    /// If omitted, a random secret is generated on startup or restored from `--state-file`.
+    /// Credentials handed out by the portal remain valid across restarts as long as the secret stays the same.
+    #[arg(long, env)]
+    auth_secret: Option<SecretString>,
+    /// How client credentials are derived from the auth secret.
+    ///
+    /// `turn-rest` accepts the same time-limited credentials as coturn's `use-auth-secret`.
+    /// This allows using the relay as a general-purpose TURN server without the portal and requires `--auth-secret`.
+    #[arg(
+        long,
+        env,
+        value_enum,
+        default_value_t = CredentialScheme::Firezone,
+        requires_if("turn-rest", "auth_secret")
+    )]
+    credential_scheme: CredentialScheme,
+    /// How long credentials minted with the previous auth secret remain valid after a rotation, in seconds.
+    ///
+    /// The portal or the admin API can rotate the auth secret at runtime.
+    /// Must be at most 30 days.
+    #[arg(long, env, default_value = "86400", value_parser = clap::value_parser!(u64).range(..=2_592_000))]
+    auth_secret_grace_period: u64,
+    /// The secret used to issue and validate nonces.
+    ///
+    /// Relays sharing the same secret accept each other's nonces.
+    /// If omitted, a random secret is generated on startup or restored from `--state-file`.
+    #[arg(long, env)]
+    nonce_secret: Option<SecretString>,
+    /// How long a nonce remains valid after it has been issued, in seconds.
+    ///
+    /// Must be between 1 second and 1 day.
+    #[arg(long, env, default_value = "600", value_parser = clap::value_parser!(u64).range(1..=86_400))]
+    nonce_lifetime: u64,
+    /// The server name that clients obtain access tokens for from a third-party authorization server (RFC 7635).
+    ///
+    /// We advertise it in 401 responses and only accept access tokens issued for it.
+    /// Requires `--access-token-keys`.
+    #[arg(long, env, requires = "access_token_keys")]
+    access_token_server_name: Option<String>,
+    /// The keys shared with the authorization server to decrypt access tokens, as `<key id>=<base64 key>`.
+    ///
+    /// Keys must be 16 (AES-128-GCM) or 32 bytes (AES-256-GCM) long.
+    /// Clients send the key id as their username.
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        requires = "access_token_server_name"
+    )]
+    access_token_keys: Vec<SecretString>,
+    /// Path to a file in which the relay persists its allocations, channel bindings and credentials.
+    ///
+    /// If the file exists on startup, its state is restored so existing allocations survive a restart.
+    /// The file contains the auth secret and is written with permissions `0600`.
+    #[arg(long, env)]
+    state_file: Option<PathBuf>,
+    /// How often to write the state to `--state-file`, in seconds.
+    ///
+    /// The state is additionally written when we start draining and on shutdown.
+    #[arg(long, env, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: u64,
     #[arg(
         long,
         env = "FIREZONE_API_URL",
@@ -52,6 +257,23 @@ struct Args {
         default_value = "wss://api.firezone.dev"
     )]
     api_url: Url,
+    /// Path to a file to which we append a JSON record for each allocation and channel binding event, or `-` for stdout.
+    ///
+    /// Records are written regardless of the log level and include the username, the client and relay addresses and the data relayed.
+    /// The file is created with permissions `0600`.
+    #[arg(long, env)]
+    audit_log: Option<PathBuf>,
+    /// Directory in which captures of relayed traffic started via the admin API are written as pcapng files.
+    ///
+    /// Captures contain the data relayed for an allocation or username and are created with permissions `0600`.
+    /// If omitted, captures are disabled.
+    #[arg(long, env)]
+    capture_dir: Option<PathBuf>,
+    /// Report the data relayed through each allocation to the portal once it is deleted.
+    ///
+    /// Usage is always logged, regardless of this setting.
+    #[arg(long, env)]
+    report_usage: bool,
     /// Token generated by the portal to authorize websocket connection.
     ///
     /// If omitted, we won't connect to the portal on startup.
@@ -92,7 +314,8 @@ enum LogFormat {
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    setup_tracing(&args).await?;
+    let log_filter = setup_tracing(&args).await?;
+    let metrics_registry = setup_metrics(&args)?;
 
     let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
         (Some(ip4), Some(ip6)) => IpStack::Dual { ip4, ip6 },
@@ -102,13 +325,106 @@ async fn main() -> Result<()> {
             bail!("Must listen on at least one of IPv4 or IPv6")
         }
     };
+    for ip in public_addr
+        .as_v4()
+        .map(|ip4| IpAddr::from(*ip4))
+        .into_iter()
+        .chain(public_addr.as_v6().map(|ip6| IpAddr::from(*ip6)))
+    {
+        // Loopback addresses are fine, e.g. to test the relay locally.
+        if ip.is_unspecified() || !is_unicast(ip) {
+            bail!("The public address {ip} must be a unicast address")
+        }
+    }
+    if args.lowest_port >= args.highest_port {
+        bail!(
+            "The lowest allocation port {} must be less than the highest allocation port {}",
+            args.lowest_port,
+            args.highest_port
+        )
+    }
+    let listen_addrs = resolve_listen_addrs(public_addr, &args.listen_addrs)?;
+    let (nat_discovery, discovery_addrs) = match args.nat_discovery_port {
+        Some(port) => {
+            let (nat_discovery, discovery_addrs) = resolve_nat_discovery(
+                &listen_addrs,
+                port,
+                args.nat_discovery_ip4_addr,
+                args.nat_discovery_ip6_addr,
+            )?;
+
+            (Some(nat_discovery), discovery_addrs)
+        }
+        None => (None, Vec::new()),
+    };
 
-    let server = Server::new(
+    let mut server = Server::new(
         public_addr,
         make_rng(args.rng_seed),
         args.lowest_port,
         args.highest_port,
-    );
+    )
+    .with_user_limits(Limits {
+        max_allocations: args.max_allocations_per_user,
+        max_channel_bindings: args.max_channel_bindings_per_user,
+        max_bytes_per_second: args.max_bandwidth_per_user,
+    })
+    .with_ip_limits(Limits {
+        max_allocations: args.max_allocations_per_ip,
+        max_channel_bindings: args.max_channel_bindings_per_ip,
+        max_bytes_per_second: args.max_bandwidth_per_ip,
+    })
+    .with_alternate_servers(args.alternate_servers.clone())
+    .with_peer_filter(PeerFilter::new(
+        args.denied_peer_networks.clone(),
+        args.allowed_peer_networks.clone(),
+    ))
+    .with_credential_scheme(args.credential_scheme)
+    .with_nonce_lifetime(Duration::from_secs(args.nonce_lifetime))
+    .with_auth_secret_grace_period(Duration::from_secs(args.auth_secret_grace_period));
+
+    if let Some(nat_discovery) = nat_discovery {
+        server = server.with_nat_discovery(nat_discovery);
+    }
+
+    for addr in &listen_addrs {
+        server = server.with_listen_addr(*addr);
+    }
+
+    if let Some(server_name) = args.access_token_server_name.clone() {
+        server = server.with_access_token_keys(parse_access_token_keys(
+            server_name,
+            &args.access_token_keys,
+        )?);
+    }
+
+    if let Some(threshold) = args.load_shedding_threshold {
+        if !(0.0..=1.0).contains(&threshold) {
+            bail!("Load shedding threshold must be between 0.0 and 1.0")
+        }
+
+        server = server.with_load_shedding_threshold(threshold);
+    }
+
+    if let Some(path) = args.state_file.as_deref() {
+        match read_snapshot(path) {
+            Ok(Some(snapshot)) => server.restore(snapshot, SystemTime::now()),
+            Ok(None) => {
+                tracing::info!(path = %path.display(), "No state file found, starting fresh")
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), "Failed to read state file, starting fresh: {e:#}")
+            }
+        }
+    }
+
+    // Configured secrets always take precedence over the ones from the state file.
+    if let Some(auth_secret) = args.auth_secret.clone() {
+        server = server.with_auth_secret(auth_secret);
+    }
+    if let Some(nonce_secret) = args.nonce_secret.clone() {
+        server = server.with_nonce_secret(nonce_secret);
+    }
 
     let channel = if let Some(token) = args.token.as_ref() {
         let base_url = args.api_url.clone();
@@ -125,11 +441,76 @@ async fn main() -> Result<()> {
         None
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr)?;
+    let mut stream_listeners = Vec::new();
+    if let Some(port) = args.tcp_port {
+        stream_listeners.push((port, None));
+    }
+    if let (Some(cert_file), Some(key_file)) = (&args.tls_cert_file, &args.tls_key_file) {
+        let acceptor = stream::make_tls_acceptor(cert_file, key_file)?;
 
-    tokio::spawn(firezone_relay::health_check::serve(args.health_check_addr));
+        for port in &args.tls_ports {
+            stream_listeners.push((*port, Some(acceptor.clone())));
+        }
+    }
 
-    tracing::info!("Listening for incoming traffic on UDP port 3478");
+    let audit_log = args.audit_log.as_deref().map(open_audit_log).transpose()?;
+
+    let readiness = Readiness::default();
+    let (admin_request_sender, admin_request_receiver) = mpsc::channel(10);
+
+    let mut eventloop = Eventloop::new(
+        server,
+        channel,
+        listen_addrs.clone(),
+        args.udp_workers,
+        discovery_addrs.clone(),
+        stream_listeners,
+        readiness.clone(),
+        admin_request_receiver,
+        Duration::from_secs(args.drain_timeout),
+        args.state_file.clone(),
+        Duration::from_secs(args.snapshot_interval),
+        args.report_usage,
+        audit_log,
+        args.capture_dir.clone(),
+        log_filter,
+    )?;
+
+    tokio::spawn(firezone_relay::health_check::serve(
+        args.health_check_addr,
+        metrics_registry,
+        readiness,
+    ));
+
+    if let (Some(addr), Some(token)) = (args.admin_addr, args.admin_token.clone()) {
+        tracing::info!("Serving admin API on {addr}");
+
+        tokio::spawn(firezone_relay::admin::serve(
+            addr,
+            token,
+            admin_request_sender,
+        ));
+    }
+
+    for addr in &listen_addrs {
+        tracing::info!("Listening for incoming traffic on UDP {addr}");
+
+        if let Some(port) = args.tcp_port {
+            tracing::info!(
+                "Listening for incoming traffic on TCP {}",
+                SocketAddr::new(addr.ip(), port)
+            );
+        }
+        for port in &args.tls_ports {
+            tracing::info!(
+                "Listening for incoming traffic on TLS {}",
+                SocketAddr::new(addr.ip(), *port)
+            );
+        }
+    }
+    for (addr, _) in &discovery_addrs {
+        tracing::info!("Answering STUN binding requests for NAT behaviour discovery on UDP {addr}");
+    }
 
     future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -138,22 +519,28 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-/// Sets up our tracing infrastructure.
+/// Sets up our tracing infrastructure, returning a handle to change the filter of our logs.
 ///
 /// See [`log_layer`] for details on the base log layer.
 ///
 /// ## Integration with OTLP
 ///
 /// If the user has specified [`TraceCollector::Otlp`], we will set up an OTLP-exporter that connects to an OTLP collector specified at `Args.otlp_grpc_endpoint`.
-async fn setup_tracing(args: &Args) -> Result<()> {
+async fn setup_tracing(args: &Args) -> Result<LogFilterHandle> {
     // Use `tracing_core` directly for the temp logger because that one does not initialize a `log` logger.
     // A `log` Logger cannot be unset once set, so we can't use that for our temp logger during the setup.
     let temp_logger_guard = tracing_core::dispatcher::set_default(
-        &tracing_subscriber::registry().with(log_layer(args)).into(),
+        &tracing_subscriber::registry()
+            .with(log_layer(args, env_filter()))
+            .into(),
     );
 
+    let (log_filter, log_filter_handle) = reload::Layer::new(env_filter());
+
     let dispatch: Dispatch = match args.otlp_grpc_endpoint {
-        None => tracing_subscriber::registry().with(log_layer(args)).into(),
+        None => tracing_subscriber::registry()
+            .with(log_layer(args, log_filter))
+            .into(),
         Some(endpoint) => {
             let grpc_endpoint = format!("http://{endpoint}");
 
@@ -175,20 +562,8 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             tracing::trace!("Successfully initialized trace provider on tokio runtime");
 
-            let exporter = opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(grpc_endpoint);
-
-            opentelemetry_otlp::new_pipeline()
-                .metrics(opentelemetry::runtime::Tokio)
-                .with_exporter(exporter)
-                .build()
-                .context("Failed to create OTLP metrics pipeline")?;
-
-            tracing::trace!("Successfully initialized metric controller on tokio runtime");
-
             tracing_subscriber::registry()
-                .with(log_layer(args))
+                .with(log_layer(args, log_filter))
                 .with(
                     tracing_opentelemetry::layer()
                         .with_tracer(tracer)
@@ -204,7 +579,48 @@ async fn setup_tracing(args: &Args) -> Result<()> {
         .try_init()
         .context("Failed to initialize tracing")?;
 
-    Ok(())
+    Ok(log_filter_handle)
+}
+
+/// Sets up our metrics pipeline.
+///
+/// Metrics are always exposed in the Prometheus format via the returned [`prometheus::Registry`].
+/// If the user has specified `Args.otlp_grpc_endpoint`, we additionally report them to the OTLP collector.
+fn setup_metrics(args: &Args) -> Result<prometheus::Registry> {
+    let registry = prometheus::Registry::new();
+
+    // Our counters already carry a `_total` suffix, don't add another one.
+    let prometheus_exporter = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .without_counter_suffixes()
+        .build()
+        .context("Failed to create Prometheus exporter")?;
+
+    let mut provider = sdk::metrics::MeterProvider::builder().with_reader(prometheus_exporter);
+
+    if let Some(endpoint) = args.otlp_grpc_endpoint {
+        let otlp_exporter = opentelemetry_otlp::MetricsExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(format!("http://{endpoint}")),
+        )
+        .build_metrics_exporter(
+            Box::new(sdk::metrics::reader::DefaultTemporalitySelector::new()),
+            Box::new(sdk::metrics::reader::DefaultAggregationSelector::new()),
+        )
+        .context("Failed to create OTLP metrics exporter")?;
+
+        provider = provider.with_reader(
+            sdk::metrics::PeriodicReader::builder(otlp_exporter, opentelemetry::runtime::Tokio)
+                .build(),
+        );
+
+        tracing::trace!("Successfully initialized OTLP metrics exporter on tokio runtime");
+    }
+
+    opentelemetry::global::set_meter_provider(provider.build());
+
+    Ok(registry)
 }
 
 /// Constructs the base log layer.
@@ -214,7 +630,10 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 /// - human-centered formatting
 /// - JSON-formatting
 /// - Google Cloud optimised formatting
-fn log_layer<T>(args: &Args) -> Box<dyn Layer<T> + Send + Sync>
+fn log_layer<T>(
+    args: &Args,
+    filter: impl Filter<T> + Send + Sync + 'static,
+) -> Box<dyn Layer<T> + Send + Sync>
 where
     T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
@@ -231,7 +650,7 @@ where
             .boxed(),
     };
 
-    log_layer.with_filter(env_filter()).boxed()
+    log_layer.with_filter(filter).boxed()
 }
 
 fn env_filter() -> EnvFilter {
@@ -245,9 +664,7 @@ async fn connect_to_portal(
     token: &SecretString,
     mut url: Url,
     stamp_secret: &SecretString,
-) -> Result<Option<PhoenixChannel<(), ()>>> {
-    use secrecy::ExposeSecret;
-
+) -> Result<Option<PhoenixChannel<IngressMessages, ()>>> {
     if !url.path().is_empty() {
         tracing::warn!("Overwriting path component of portal URL with '/relay/websocket'");
     }
@@ -286,6 +703,156 @@ struct JoinMessage {
     stamp_secret: String,
 }
 
+/// Resolves the addresses of our main UDP sockets, exactly one for each address family we have a public address for.
+///
+/// Families without a configured listen address listen on port 3478 on all interfaces.
+fn resolve_listen_addrs(
+    public_addr: IpStack,
+    configured: &[SocketAddr],
+) -> Result<Vec<SocketAddr>> {
+    let mut ip4 = None;
+    let mut ip6 = None;
+
+    for addr in configured {
+        let (slot, has_public_addr) = match addr {
+            SocketAddr::V4(_) => (&mut ip4, public_addr.as_v4().is_some()),
+            SocketAddr::V6(_) => (&mut ip6, public_addr.as_v6().is_some()),
+        };
+
+        if !has_public_addr {
+            bail!(
+                "Cannot listen on {addr} without a public {} address",
+                addr.family()
+            )
+        }
+        if !is_unicast(addr.ip()) {
+            bail!("Cannot listen on {addr} because it is not a unicast address")
+        }
+        if slot.replace(*addr).is_some() {
+            bail!(
+                "Only one listen address per address family is supported, got a second one: {addr}"
+            )
+        }
+    }
+
+    let ip4 = public_addr
+        .as_v4()
+        .map(|_| ip4.unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3478))));
+    let ip6 = public_addr
+        .as_v6()
+        .map(|_| ip6.unwrap_or(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 3478))));
+
+    Ok(ip4.into_iter().chain(ip6).collect())
+}
+
+/// Whether clients can send datagrams to the given address, i.e. it is neither a multicast nor the broadcast address.
+fn is_unicast(ip: IpAddr) -> bool {
+    !ip.is_multicast() && ip != IpAddr::from(Ipv4Addr::BROADCAST)
+}
+
+/// Parses the `--access-token-keys` into the keys we decrypt access tokens with.
+fn parse_access_token_keys(server_name: String, keys: &[SecretString]) -> Result<AccessTokenKeys> {
+    keys.iter().try_fold(
+        AccessTokenKeys::new(server_name),
+        |access_token_keys, key| {
+            let (key_id, key) = key
+                .expose_secret()
+                .split_once('=')
+                .context("Access token keys must be formatted as `<key id>=<base64 key>`")?;
+            let key = BASE64_STANDARD
+                .decode(key)
+                .with_context(|| format!("Access token key '{key_id}' is not valid base64"))?;
+
+            access_token_keys.with_key(key_id.to_owned(), key)
+        },
+    )
+}
+
+/// Resolves our addresses for NAT behaviour discovery and the local addresses of the sockets that only answer STUN binding requests.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc5780#section-4.1>.
+fn resolve_nat_discovery(
+    listen_addrs: &[SocketAddr],
+    port: u16,
+    ip4: Option<Ipv4Addr>,
+    ip6: Option<Ipv6Addr>,
+) -> Result<(NatDiscovery, Vec<(SocketAddr, DiscoverySocket)>)> {
+    let primary_port = listen_addrs
+        .first()
+        .context("Need at least one listen address")?
+        .port();
+
+    if listen_addrs.iter().any(|addr| addr.port() != primary_port) {
+        bail!("NAT behaviour discovery requires the same listen port for IPv4 and IPv6")
+    }
+    if port == primary_port {
+        bail!("The NAT behaviour discovery port must differ from the listen port {primary_port}")
+    }
+
+    let mut nat_discovery = NatDiscovery::new(primary_port, port);
+    let mut discovery_addrs = Vec::new();
+
+    for listen_addr in listen_addrs {
+        let listen_ip = listen_addr.ip();
+
+        discovery_addrs.push((
+            SocketAddr::new(listen_ip, port),
+            DiscoverySocket {
+                alternate_ip: false,
+                alternate_port: true,
+            },
+        ));
+
+        let alternate_ip = match listen_addr {
+            SocketAddr::V4(_) => ip4.map(IpAddr::from),
+            SocketAddr::V6(_) => ip6.map(IpAddr::from),
+        };
+        let Some(alternate_ip) = alternate_ip else {
+            continue;
+        };
+
+        // Binding the alternate IP on our primary port would conflict with a wildcard listen address.
+        if listen_ip.is_unspecified() {
+            bail!("A second {} address for NAT behaviour discovery requires a listen address other than {listen_ip}", listen_addr.family())
+        }
+        if alternate_ip == listen_ip {
+            bail!("The second address for NAT behaviour discovery must differ from the listen address {listen_ip}")
+        }
+
+        discovery_addrs.push((
+            SocketAddr::new(alternate_ip, primary_port),
+            DiscoverySocket {
+                alternate_ip: true,
+                alternate_port: false,
+            },
+        ));
+        discovery_addrs.push((
+            SocketAddr::new(alternate_ip, port),
+            DiscoverySocket {
+                alternate_ip: true,
+                alternate_port: true,
+            },
+        ));
+    }
+
+    if let Some(ip4) = ip4 {
+        if !listen_addrs.iter().any(SocketAddr::is_ipv4) {
+            bail!("Cannot use {ip4} for NAT behaviour discovery without a public IPv4 address")
+        }
+
+        nat_discovery = nat_discovery.with_alternate_ip4(ip4);
+    }
+    if let Some(ip6) = ip6 {
+        if !listen_addrs.iter().any(SocketAddr::is_ipv6) {
+            bail!("Cannot use {ip6} for NAT behaviour discovery without a public IPv6 address")
+        }
+
+        nat_discovery = nat_discovery.with_alternate_ip6(ip6);
+    }
+
+    Ok((nat_discovery, discovery_addrs))
+}
+
 #[cfg(debug_assertions)]
 fn make_rng(seed: Option<u64>) -> StdRng {
     let Some(seed) = seed else {
@@ -307,58 +874,168 @@ fn make_rng(seed: Option<u64>) -> StdRng {
 }
 
 struct Eventloop<R> {
-    inbound_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
-    outbound_ip4_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
-    outbound_ip6_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    inbound_data_receiver: mpsc::Receiver<DatagramBatch>,
+    /// The channels to our main UDP socket tasks, one per worker.
+    outbound_ip4_data_senders: Vec<OutboundDatagramSender>,
+    outbound_ip6_data_senders: Vec<OutboundDatagramSender>,
+    /// Datagrams received on the sockets that only serve NAT behaviour discovery.
+    discovery_data_receiver: mpsc::Receiver<(DatagramBatch, DiscoverySocket)>,
+    /// The channels to the sockets that only serve NAT behaviour discovery.
+    discovery_data_senders: HashMap<(AddressFamily, DiscoverySocket), OutboundDatagramSender>,
+    stream_event_receiver: mpsc::Receiver<StreamEvent>,
+    /// Clients connected via TCP or TLS, indexed by their address.
+    streams: HashMap<SocketAddr, mpsc::Sender<Outbound>>,
     server: Server<R>,
-    channel: Option<PhoenixChannel<(), ()>>,
+    channel: Option<PhoenixChannel<IngressMessages, ()>>,
     allocations: HashMap<(AllocationId, AddressFamily), Allocation>,
-    relay_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, AllocationId)>,
-    relay_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr, AllocationId)>,
+    tcp_allocations: HashMap<(AllocationId, AddressFamily), TcpAllocation>,
+    peer_connection_sender: mpsc::Sender<PeerConnectionEvent>,
+    peer_connection_receiver: mpsc::Receiver<PeerConnectionEvent>,
+    /// Connections to peers of TCP allocations that wait for the client to bind them.
+    pending_peer_connections: HashMap<ConnectionId, TcpStream>,
+    /// Connections to peers of TCP allocations, together with the client connection they are spliced with.
+    spliced_peer_connections: HashMap<ConnectionId, SocketAddr>,
+    /// The addresses of our main UDP sockets, one per address family.
+    listen_addrs: Vec<SocketAddr>,
+    relay_data_sender: mpsc::Sender<(DatagramBatch, AllocationId)>,
+    relay_data_receiver: mpsc::Receiver<(DatagramBatch, AllocationId)>,
     sleep: Sleep,
+
+    readiness: Readiness,
+    admin_requests: mpsc::Receiver<AdminRequest>,
+    sigterm: Signal,
+    drain_timeout: Duration,
+    /// Fires once the drain timeout is reached.
+    drain_deadline: Sleep,
+
+    /// Where to persist the state of the server, if anywhere.
+    state_file: Option<PathBuf>,
+    snapshot_interval: tokio::time::Interval,
+
+    /// Whether to report the usage of deleted allocations to the portal.
+    report_usage: bool,
+    audit_log: Option<AuditLog<Box<dyn io::Write + Send>>>,
+    /// Where to write captures, if they are enabled.
+    capture_dir: Option<PathBuf>,
+    /// The files of captures in progress.
+    captures: HashMap<CaptureId, io::BufWriter<std::fs::File>>,
+    log_filter: LogFilterHandle,
 }
 
 impl<R> Eventloop<R>
 where
     R: Rng,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         server: Server<R>,
-        channel: Option<PhoenixChannel<(), ()>>,
-        public_address: IpStack,
+        channel: Option<PhoenixChannel<IngressMessages, ()>>,
+        listen_addrs: Vec<SocketAddr>,
+        udp_workers: u16,
+        discovery_addrs: Vec<(SocketAddr, DiscoverySocket)>,
+        stream_listeners: Vec<(u16, Option<TlsAcceptor>)>,
+        readiness: Readiness,
+        admin_requests: mpsc::Receiver<AdminRequest>,
+        drain_timeout: Duration,
+        state_file: Option<PathBuf>,
+        snapshot_interval: Duration,
+        report_usage: bool,
+        audit_log: Option<AuditLog<Box<dyn io::Write + Send>>>,
+        capture_dir: Option<PathBuf>,
+        log_filter: LogFilterHandle,
     ) -> Result<Self> {
         let (relay_data_sender, relay_data_receiver) = mpsc::channel(1);
         let (inbound_data_sender, inbound_data_receiver) = mpsc::channel(10);
-        let (outbound_ip4_data_sender, outbound_ip4_data_receiver) =
-            mpsc::channel::<(Vec<u8>, SocketAddr)>(10);
-        let (outbound_ip6_data_sender, outbound_ip6_data_receiver) =
-            mpsc::channel::<(Vec<u8>, SocketAddr)>(10);
+        let (discovery_data_sender, discovery_data_receiver) = mpsc::channel(10);
+        let (stream_event_sender, stream_event_receiver) = mpsc::channel(10);
+        let (peer_connection_sender, peer_connection_receiver) = mpsc::channel(10);
+
+        let mut outbound_ip4_data_senders = Vec::new();
+        let mut outbound_ip6_data_senders = Vec::new();
+
+        for addr in listen_addrs.iter().copied() {
+            for (port, tls) in stream_listeners.iter().cloned() {
+                tokio::spawn(stream::listen(
+                    SocketAddr::new(addr.ip(), port),
+                    tls,
+                    stream_event_sender.clone(),
+                ));
+            }
 
-        if public_address.as_v4().is_some() {
-            tokio::spawn(main_udp_socket_task(
-                AddressFamily::V4,
-                inbound_data_sender.clone(),
-                outbound_ip4_data_receiver,
-            ));
+            for _ in 0..udp_workers {
+                let socket = if udp_workers > 1 {
+                    UdpSocket::bind_reuse_port(addr)?
+                } else {
+                    UdpSocket::bind(addr)?
+                };
+                let (outbound_data_sender, outbound_data_receiver) =
+                    mpsc::channel(MAX_BUFFERED_OUTBOUND_DATAGRAMS);
+
+                match addr.family() {
+                    AddressFamily::V4 => outbound_ip4_data_senders.push(outbound_data_sender),
+                    AddressFamily::V6 => outbound_ip6_data_senders.push(outbound_data_sender),
+                }
+
+                tokio::spawn(main_udp_socket_task(
+                    socket,
+                    inbound_data_sender.clone(),
+                    outbound_data_receiver,
+                ));
+            }
         }
-        if public_address.as_v6().is_some() {
+
+        let mut discovery_data_senders = HashMap::new();
+
+        for (addr, socket) in discovery_addrs {
+            let (outbound_data_sender, outbound_data_receiver) =
+                mpsc::channel(MAX_BUFFERED_OUTBOUND_DATAGRAMS);
+
+            discovery_data_senders.insert((addr.family(), socket), outbound_data_sender);
+
             tokio::spawn(main_udp_socket_task(
-                AddressFamily::V6,
-                inbound_data_sender,
-                outbound_ip6_data_receiver,
+                UdpSocket::bind(addr)?,
+                discovery_data_sender
+                    .clone()
+                    .with(move |batch| future::ok((batch, socket))),
+                outbound_data_receiver,
             ));
         }
 
         Ok(Self {
             inbound_data_receiver,
-            outbound_ip4_data_sender,
-            outbound_ip6_data_sender,
+            outbound_ip4_data_senders,
+            outbound_ip6_data_senders,
+            discovery_data_receiver,
+            discovery_data_senders,
+            stream_event_receiver,
+            streams: Default::default(),
             server,
             channel,
             allocations: Default::default(),
+            tcp_allocations: Default::default(),
+            peer_connection_sender,
+            peer_connection_receiver,
+            pending_peer_connections: Default::default(),
+            spliced_peer_connections: Default::default(),
+            listen_addrs,
             relay_data_sender,
             relay_data_receiver,
             sleep: Sleep::default(),
+            readiness,
+            admin_requests,
+            sigterm: signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?,
+            drain_timeout,
+            drain_deadline: Sleep::default(),
+            state_file,
+            snapshot_interval: tokio::time::interval_at(
+                tokio::time::Instant::now() + snapshot_interval,
+                snapshot_interval,
+            ),
+            report_usage,
+            audit_log,
+            capture_dir,
+            captures: Default::default(),
+            log_filter,
         })
     }
 
@@ -376,10 +1053,32 @@ where
                         let span = tracing::error_span!("Command::SendMessage");
                         let _guard = span.enter();
 
-                        let sender = match recipient.family() {
-                            AddressFamily::V4 => &mut self.outbound_ip4_data_sender,
-                            AddressFamily::V6 => &mut self.outbound_ip6_data_sender,
+                        if let Some(stream) = self.streams.get_mut(&recipient) {
+                            if let Err(e) = stream.try_send(Outbound::Frame(payload)) {
+                                if e.is_disconnected() {
+                                    tracing::debug!(%recipient, "Stream connection has been closed");
+                                }
+
+                                if e.is_full() {
+                                    tracing::warn!(%recipient, "Dropping message because stream connection is full");
+                                }
+                            }
+
+                            continue;
+                        }
+
+                        let senders = match recipient.family() {
+                            AddressFamily::V4 => &mut self.outbound_ip4_data_senders,
+                            AddressFamily::V6 => &mut self.outbound_ip6_data_senders,
                         };
+                        if senders.is_empty() {
+                            tracing::debug!(%recipient, "No UDP socket for address family");
+                            continue;
+                        }
+
+                        // Always use the same socket for a client to not reorder its messages.
+                        let num_senders = senders.len();
+                        let sender = &mut senders[usize::from(recipient.port()) % num_senders];
 
                         if let Err(e) = sender.try_send((payload, recipient)) {
                             if e.is_disconnected() {
@@ -393,21 +1092,77 @@ where
                             }
                         }
                     }
-                    Command::CreateAllocation { id, family, port } => {
-                        let span =
-                            tracing::error_span!("Command::CreateAllocation", %id, %family, %port);
+                    Command::SendMessageFrom {
+                        payload,
+                        recipient,
+                        socket,
+                    } => {
+                        let Some(sender) = self
+                            .discovery_data_senders
+                            .get_mut(&(recipient.family(), socket))
+                        else {
+                            tracing::debug!(%recipient, ?socket, "No NAT behaviour discovery socket for address family");
+                            continue;
+                        };
+
+                        if let Err(e) = sender.try_send((payload, recipient)) {
+                            if e.is_disconnected() {
+                                return Poll::Ready(Err(anyhow!(
+                                    "Channel to NAT behaviour discovery socket task has been closed"
+                                )));
+                            }
+
+                            if e.is_full() {
+                                tracing::warn!(%recipient, ?socket, "Dropping message because channel to NAT behaviour discovery socket task is full");
+                            }
+                        }
+                    }
+                    Command::CreateAllocation {
+                        id,
+                        family,
+                        port,
+                        dont_fragment,
+                    } => {
+                        let span = tracing::error_span!("Command::CreateAllocation", %id, %family, %port, %dont_fragment);
                         let _guard = span.enter();
 
+                        let Some(ip) = self.listen_ip(family) else {
+                            tracing::warn!("No listen address for {family}");
+                            continue;
+                        };
+
                         self.allocations.insert(
                             (id, family),
-                            Allocation::new(self.relay_data_sender.clone(), id, family, port),
+                            Allocation::new(
+                                self.relay_data_sender.clone(),
+                                id,
+                                ip,
+                                port,
+                                dont_fragment,
+                            ),
+                        );
+                    }
+                    Command::CreateTcpAllocation { id, family, port } => {
+                        let span = tracing::error_span!("Command::CreateTcpAllocation", %id, %family, %port);
+                        let _guard = span.enter();
+
+                        let Some(ip) = self.listen_ip(family) else {
+                            tracing::warn!("No listen address for {family}");
+                            continue;
+                        };
+
+                        self.tcp_allocations.insert(
+                            (id, family),
+                            TcpAllocation::new(self.peer_connection_sender.clone(), id, ip, port),
                         );
                     }
                     Command::FreeAllocation { id, family } => {
                         let span = tracing::error_span!("Command::FreeAllocation", %id, %family);
                         let _guard = span.enter();
 
-                        if self.allocations.remove(&(id, family)).is_none() {
+                        if self.allocations.remove(&(id, family)).is_none()
+                            && self.tcp_allocations.remove(&(id, family)).is_none()
+                        {
                             tracing::debug!("Unknown allocation {id}");
                             continue;
                         };
@@ -451,34 +1206,198 @@ where
                             allocation.remove();
                         }
                     }
+                    Command::ConnectToPeer {
+                        id,
+                        connection,
+                        peer,
+                    } => {
+                        let span =
+                            tracing::error_span!("Command::ConnectToPeer", %id, %connection, %peer);
+                        let _guard = span.enter();
+
+                        let Some(allocation) = self.tcp_allocations.get(&(id, peer.family()))
+                        else {
+                            tracing::debug!(allocation = %id, family = %peer.family(), "Unknown TCP allocation");
+                            self.server.handle_peer_connection_failed(connection);
+                            continue;
+                        };
+
+                        allocation.connect(self.peer_connection_sender.clone(), connection, peer);
+                    }
+                    Command::SpliceConnection {
+                        connection,
+                        client,
+                        response,
+                    } => {
+                        let span =
+                            tracing::error_span!("Command::SpliceConnection", %connection, %client);
+                        let _guard = span.enter();
+
+                        let (Some(peer), Some(stream)) = (
+                            self.pending_peer_connections.remove(&connection),
+                            self.streams.get_mut(&client),
+                        ) else {
+                            tracing::debug!("Peer or client connection has been closed");
+
+                            // Closing the client connection also makes the server forget about the peer connection.
+                            self.streams.remove(&client);
+                            continue;
+                        };
+
+                        if stream
+                            .try_send(Outbound::Splice { response, peer })
+                            .is_err()
+                        {
+                            tracing::debug!("Failed to hand peer connection to client connection");
+
+                            self.streams.remove(&client);
+                            continue;
+                        }
+
+                        self.spliced_peer_connections.insert(connection, client);
+                    }
+                    Command::CloseConnection { connection } => {
+                        let span = tracing::error_span!("Command::CloseConnection", %connection);
+                        let _guard = span.enter();
+
+                        self.pending_peer_connections.remove(&connection);
+
+                        // Dropping the channel to the client connection closes it together with the spliced peer connection.
+                        if let Some(client) = self.spliced_peer_connections.remove(&connection) {
+                            self.streams.remove(&client);
+                        }
+                    }
                 }
 
                 continue; // Attempt to process more commands.
             }
 
-            // Priority 2: Handle time-sensitive tasks:
+            // Priority 2: Report what happened on the server.
+            if let Some(event) = self.server.next_event() {
+                if let Some(audit_log) = self.audit_log.as_mut() {
+                    if let Err(e) = audit_log.record(&event, now) {
+                        tracing::warn!("Failed to write audit log: {e}");
+                    }
+                }
+
+                match event {
+                    firezone_relay::Event::AllocationDeleted { usage, .. } => {
+                        if let Some(channel) = self.channel.as_mut().filter(|_| self.report_usage) {
+                            channel.send("relay", EgressMessages::AllocationUsage(usage));
+                        }
+                    }
+                    firezone_relay::Event::AllocationCreated { .. }
+                    | firezone_relay::Event::AllocationRefreshed { .. }
+                    | firezone_relay::Event::AllocationMoved { .. }
+                    | firezone_relay::Event::ChannelBound { .. } => {}
+                    firezone_relay::Event::CaptureData { id, bytes } => {
+                        let Some(file) = self.captures.get_mut(&id) else {
+                            continue;
+                        };
+
+                        if let Err(e) = file.write_all(&bytes) {
+                            tracing::warn!(capture = %id, "Failed to write capture: {e}");
+
+                            self.captures.remove(&id);
+                            self.server.stop_capture(id);
+                        }
+                    }
+                    firezone_relay::Event::CaptureFinished { id, reason } => {
+                        let Some(mut file) = self.captures.remove(&id) else {
+                            continue;
+                        };
+
+                        if let Err(e) = file.flush() {
+                            tracing::warn!(capture = %id, "Failed to write capture: {e}");
+                        }
+
+                        tracing::info!(capture = %id, ?reason, "Capture finished");
+                    }
+                }
+
+                continue;
+            }
+
+            // Priority 3: Handle time-sensitive tasks:
             if self.sleep.poll_unpin(cx).is_ready() {
                 self.server.handle_deadline_reached(now);
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 3: Handle relayed data (we prioritize latency for existing allocations over making new ones)
-            if let Poll::Ready(Some((data, sender, allocation))) =
+            // Priority 4: Handle relayed data (we prioritize latency for existing allocations over making new ones)
+            if let Poll::Ready(Some((batch, allocation))) =
                 self.relay_data_receiver.poll_next_unpin(cx)
             {
-                self.server.handle_relay_input(&data, sender, allocation);
+                for (data, sender) in batch.iter() {
+                    self.server
+                        .handle_relay_input(data, sender, allocation, now);
+                }
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 5: Accept new allocations / answer STUN requests etc
+            if let Poll::Ready(Some(batch)) = self.inbound_data_receiver.poll_next_unpin(cx) {
+                for (data, sender) in batch.iter() {
+                    self.server.handle_client_input(data, sender, now);
+                }
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 4: Accept new allocations / answer STUN requests etc
-            if let Poll::Ready(Some((buffer, sender))) =
-                self.inbound_data_receiver.poll_next_unpin(cx)
+            // Priority 6: Same as above but for the sockets that only serve NAT behaviour discovery
+            if let Poll::Ready(Some((batch, socket))) =
+                self.discovery_data_receiver.poll_next_unpin(cx)
             {
-                self.server.handle_client_input(&buffer, sender, now);
+                for (data, sender) in batch.iter() {
+                    self.server.handle_discovery_input(data, sender, socket);
+                }
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 5: Handle portal messages
+            // Priority 7: Same as above but for clients connected over TCP or TLS
+            if let Poll::Ready(Some(event)) = self.stream_event_receiver.poll_next_unpin(cx) {
+                match event {
+                    StreamEvent::Connected { peer, outbound } => {
+                        tracing::debug!(%peer, "New stream connection");
+
+                        self.streams.insert(peer, outbound);
+                        self.server.handle_client_connected(peer);
+                    }
+                    StreamEvent::Data { peer, frame } => {
+                        self.server.handle_client_input(&frame, peer, now);
+                    }
+                    StreamEvent::Disconnected { peer } => {
+                        tracing::debug!(%peer, "Stream connection closed");
+
+                        self.streams.remove(&peer);
+                        self.server.handle_client_disconnected(peer);
+                    }
+                }
+
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 8: Handle TCP connections of peers
+            if let Poll::Ready(Some(event)) = self.peer_connection_receiver.poll_next_unpin(cx) {
+                match event {
+                    PeerConnectionEvent::Accepted { id, peer, stream } => {
+                        if let Some(connection) = self.server.handle_peer_connection(id, peer, now)
+                        {
+                            self.pending_peer_connections.insert(connection, stream);
+                        }
+                    }
+                    PeerConnectionEvent::Connected { connection, stream } => {
+                        self.pending_peer_connections.insert(connection, stream);
+                        self.server.handle_peer_connected(connection, now);
+                    }
+                    PeerConnectionEvent::Failed { connection } => {
+                        self.server.handle_peer_connection_failed(connection);
+                    }
+                }
+
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 9: Handle portal messages
             match self.channel.as_mut().map(|c| c.poll(cx)) {
                 Some(Poll::Ready(Err(Error::Serde(e)))) => {
                     tracing::warn!("Failed to deserialize portal message: {e}");
@@ -506,34 +1425,322 @@ where
                     tracing::debug!("Heartbeat sent to portal");
                     continue;
                 }
-                Some(Poll::Ready(Ok(
-                    Event::InboundMessage { msg: (), .. } | Event::InboundReq { req: (), .. },
-                )))
-                | Some(Poll::Pending)
-                | None => {}
+                Some(Poll::Ready(Ok(Event::InboundMessage { msg, .. }))) => {
+                    if let Err(e) = self.handle_portal_message(msg, now) {
+                        tracing::warn!("Failed to handle portal message: {e:#}");
+                    }
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundReq { topic, req_id, req }))) => {
+                    let result = self.handle_portal_message(req, now);
+                    let channel = self
+                        .channel
+                        .as_mut()
+                        .expect("requests are only received from a channel");
+
+                    match result {
+                        Ok(reply) => channel.reply_ok(topic, req_id, reply),
+                        Err(e) => {
+                            tracing::warn!("Failed to handle portal request {req_id}: {e:#}");
+                            channel.reply_error(topic, req_id, format!("{e:#}"));
+                        }
+                    }
+                    continue;
+                }
+                Some(Poll::Pending) | None => {}
+            }
+
+            // Priority 10: Handle requests from the admin API
+            if let Poll::Ready(Some(request)) = self.admin_requests.poll_next_unpin(cx) {
+                // Failing to reply just means the HTTP request was aborted in the meantime.
+                match request {
+                    AdminRequest::ListAllocations { reply } => {
+                        let _ = reply.send(self.server.allocations());
+                    }
+                    AdminRequest::KillAllocation { id, reply } => {
+                        let _ = reply
+                            .send(self.server.kill_allocation(id, DeletionReason::AdminKilled));
+                    }
+                    AdminRequest::KillAllocationsOfUser { username, reply } => {
+                        let _ = reply.send(
+                            self.server
+                                .kill_allocations_of_user(&username, DeletionReason::AdminKilled),
+                        );
+                    }
+                    AdminRequest::RotateAuthSecret { secret, reply } => {
+                        self.server.rotate_auth_secret(secret, now);
+                        self.persist_state();
+
+                        let _ = reply.send(());
+                    }
+                    AdminRequest::StartCapture {
+                        filter,
+                        max_bytes,
+                        duration,
+                        reply,
+                    } => {
+                        let _ = reply.send(self.start_capture(filter, max_bytes, duration, now));
+                    }
+                    AdminRequest::StartDraining { reply } => {
+                        self.start_draining(now);
+
+                        let _ = reply.send(());
+                    }
+                    AdminRequest::StopCapture { id, reply } => {
+                        let _ = reply.send(self.server.stop_capture(id));
+                    }
+                }
+
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 11: Handle requests to drain
+            if matches!(self.sigterm.poll_recv(cx), Poll::Ready(Some(()))) {
+                self.start_draining(now);
+                continue;
+            }
+
+            if self.server.is_draining() {
+                if self.server.num_active_allocations() == 0 {
+                    tracing::info!("All allocations are gone, shutting down");
+                    self.persist_state();
+
+                    return Poll::Ready(Ok(()));
+                }
+
+                if self.drain_deadline.poll_unpin(cx).is_ready() {
+                    // The remaining allocations are restored from the state file once we are back up.
+                    if self.state_file.is_some() {
+                        tracing::info!(
+                            allocations = self.server.num_active_allocations(),
+                            "Drain timeout reached, persisting remaining allocations and shutting down"
+                        );
+                        self.persist_state();
+
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    // Deleting the remaining allocations lets us report them before we shut down once they are all gone.
+                    tracing::warn!(
+                        allocations = self.server.num_active_allocations(),
+                        "Drain timeout reached, deleting remaining allocations"
+                    );
+                    self.server.kill_all_allocations(DeletionReason::Drained);
+                    continue;
+                }
             }
 
+            // Priority 12: Periodically persist our state
+            if self.state_file.is_some() && self.snapshot_interval.poll_tick(cx).is_ready() {
+                self.persist_state();
+                continue;
+            }
+
+            // Any failure of the portal connection is fatal, thus it is always connected at this point.
+            self.readiness
+                .set(!self.server.is_draining() && self.server.has_available_ports());
+
             return Poll::Pending;
         }
     }
+
+    /// Acts on a message of the portal, returning what to reply if it was sent as a request.
+    fn handle_portal_message(
+        &mut self,
+        message: IngressMessages,
+        now: SystemTime,
+    ) -> Result<IngressReplies> {
+        match message {
+            IngressMessages::SiblingRelays(SiblingRelays { addresses }) => {
+                self.server.set_alternate_servers(addresses);
+            }
+            IngressMessages::RotateAuthSecret(RotateAuthSecret { secret }) => {
+                self.server
+                    .rotate_auth_secret(SecretString::new(secret), now);
+                self.persist_state();
+            }
+            IngressMessages::RevokeUser(RevokeUser { username }) => {
+                let allocations = self
+                    .server
+                    .kill_allocations_of_user(&username, DeletionReason::Revoked);
+
+                tracing::info!(%username, num_allocations = allocations.len(), "Revoked allocations of user");
+
+                return Ok(IngressReplies::RevokedAllocations { allocations });
+            }
+            IngressMessages::StartDraining(StartDraining {}) => {
+                self.start_draining(now);
+            }
+            IngressMessages::RotateNonceSecret(RotateNonceSecret { secret }) => {
+                self.server.rotate_nonce_secret(SecretString::new(secret));
+                self.persist_state();
+            }
+            IngressMessages::SetLogLevel(SetLogLevel { directives }) => {
+                let filter = EnvFilter::builder()
+                    .with_default_directive(LevelFilter::INFO.into())
+                    .parse(&directives)
+                    .context("Invalid log filter")?;
+                self.log_filter
+                    .reload(filter)
+                    .context("Failed to replace log filter")?;
+
+                tracing::info!(%directives, "Changed log filter");
+            }
+        }
+
+        Ok(IngressReplies::Done {})
+    }
+
+    /// Stops accepting new allocations and arms the drain timeout, unless we are already draining.
+    fn start_draining(&mut self, now: SystemTime) {
+        if self.server.is_draining() {
+            return;
+        }
+
+        tracing::info!(
+            allocations = self.server.num_active_allocations(),
+            timeout = ?self.drain_timeout,
+            "Draining relay"
+        );
+
+        self.server.start_draining();
+        Pin::new(&mut self.drain_deadline).reset(now + self.drain_timeout);
+        self.persist_state();
+    }
+
+    /// Starts a capture and creates the file its data is written to.
+    fn start_capture(
+        &mut self,
+        filter: CaptureFilter,
+        max_bytes: u64,
+        duration: Duration,
+        now: SystemTime,
+    ) -> Result<StartedCapture, StartCaptureError> {
+        let capture_dir = self
+            .capture_dir
+            .as_deref()
+            .ok_or(StartCaptureError::Disabled)?;
+        let id = self
+            .server
+            .start_capture(filter, max_bytes, duration, now)
+            .ok_or(StartCaptureError::UnknownAllocation)?;
+
+        let path = capture_dir.join(format!(
+            "{}-{}.pcapng",
+            now.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            id.value()
+        ));
+
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => {
+                tracing::info!(capture = %id, "Writing capture to {}", path.display());
+
+                self.captures.insert(id, io::BufWriter::new(file));
+
+                Ok(StartedCapture { id, file: path })
+            }
+            Err(e) => {
+                tracing::warn!(capture = %id, "Failed to create capture file at {}: {e}", path.display());
+
+                self.server.stop_capture(id);
+
+                Err(StartCaptureError::Io)
+            }
+        }
+    }
+
+    /// The IP of our listen address for the given family, which is also used by all allocations.
+    fn listen_ip(&self, family: AddressFamily) -> Option<IpAddr> {
+        self.listen_addrs
+            .iter()
+            .find(|addr| addr.family() == family)
+            .map(|addr| addr.ip())
+    }
+
+    fn persist_state(&self) {
+        let Some(path) = self.state_file.as_deref() else {
+            return;
+        };
+
+        match write_snapshot(path, &self.server.snapshot()) {
+            Ok(()) => tracing::debug!(path = %path.display(), "Persisted state"),
+            Err(e) => tracing::warn!(path = %path.display(), "Failed to persist state: {e:#}"),
+        }
+    }
+}
+
+/// Opens the audit log at the given path for appending, where `-` stands for stdout.
+fn open_audit_log(path: &Path) -> Result<AuditLog<Box<dyn io::Write + Send>>> {
+    if path == Path::new("-") {
+        return Ok(AuditLog::new(Box::new(io::stdout())));
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log at {}", path.display()))?;
+
+    Ok(AuditLog::new(Box::new(io::BufWriter::new(file))))
+}
+
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read state file"),
+    };
+    let snapshot = serde_json::from_slice(&bytes).context("Failed to parse state file")?;
+
+    Ok(Some(snapshot))
+}
+
+/// Writes the snapshot to a temporary file next to `path` and atomically moves it into place.
+///
+/// This ensures we never leave a half-written state file behind if we crash in the middle of writing.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .context("Failed to create temporary state file")?;
+    serde_json::to_writer(&mut file, snapshot).context("Failed to serialize state")?;
+    file.sync_all()
+        .context("Failed to sync temporary state file")?;
+
+    std::fs::rename(&tmp_path, path).context("Failed to move state file into place")?;
+
+    Ok(())
 }
 
 async fn main_udp_socket_task(
-    family: AddressFamily,
-    mut inbound_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
-    mut outbound_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    mut socket: UdpSocket,
+    inbound_data_sender: impl Sink<DatagramBatch, Error = mpsc::SendError>,
+    outbound_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
 ) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, 3478)?;
+    let mut outbound_data_receiver = outbound_data_receiver.ready_chunks(BATCH_SIZE);
+    let mut inbound_data_sender = std::pin::pin!(inbound_data_sender);
 
     loop {
         tokio::select! {
-            result = socket.recv() => {
-                let (data, sender) = result?;
-                inbound_data_sender.send((data.to_vec(), sender)).await?;
+            result = socket.recv_batch() => {
+                inbound_data_sender.send(result?).await?;
             }
-            maybe_item = outbound_data_receiver.next() => {
-                let (data, recipient) = maybe_item.context("Outbound data channel closed")?;
-                socket.send_to(data.as_ref(), recipient).await?;
+            maybe_datagrams = outbound_data_receiver.next() => {
+                let datagrams = maybe_datagrams.context("Outbound data channel closed")?;
+                socket.send_batch(&datagrams).await?;
             }
         }
     }
@@ -0,0 +1,230 @@
+use crate::{AllocationId, AllocationUsage};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Messages the portal can send to the relay.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum IngressMessages {
+    SiblingRelays(SiblingRelays),
+    RotateAuthSecret(RotateAuthSecret),
+    RevokeUser(RevokeUser),
+    StartDraining(StartDraining),
+    RotateNonceSecret(RotateNonceSecret),
+    SetLogLevel(SetLogLevel),
+}
+
+/// The other relays of the fleet.
+///
+/// Clients are redirected to these if we don't accept new allocations.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct SiblingRelays {
+    /// The STUN / TURN addresses of the sibling relays.
+    pub addresses: Vec<SocketAddr>,
+}
+
+/// Replaces the secret used to authenticate clients.
+///
+/// Credentials minted with the previous secret remain valid for a grace period.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RotateAuthSecret {
+    pub secret: String,
+}
+
+impl fmt::Debug for RotateAuthSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotateAuthSecret")
+            .field("secret", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// Deletes all allocations of a username, e.g. because the user has been disabled or is misbehaving.
+///
+/// This doesn't prevent the user from making new allocations until their credentials expire.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RevokeUser {
+    pub username: String,
+}
+
+/// Stop accepting new allocations and shut down once the existing ones are gone, like upon SIGTERM.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct StartDraining {}
+
+/// Replaces the secret used to issue and validate nonces.
+///
+/// Our nonces are stateless, the portal pushes the same secret to all relays so they accept each other's nonces.
+/// Nonces issued with the previous secret are rejected as stale.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RotateNonceSecret {
+    pub secret: String,
+}
+
+impl fmt::Debug for RotateNonceSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotateNonceSecret")
+            .field("secret", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// Replaces the filter of our logs, using the syntax of `RUST_LOG`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct SetLogLevel {
+    pub directives: String,
+}
+
+/// Our replies to the requests of the portal.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum IngressReplies {
+    /// The allocations deleted in response to a [`RevokeUser`] request.
+    RevokedAllocations { allocations: Vec<AllocationId> },
+    /// The request has been processed and there is nothing else to report.
+    Done {},
+}
+
+/// Messages the relay sends to the portal.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum EgressMessages {
+    /// Reports the data relayed through an allocation once it has been deleted.
+    AllocationUsage(AllocationUsage),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChannelUsage, Traffic};
+
+    #[test]
+    fn sibling_relays_message() {
+        let message = r#"{"event":"sibling_relays","payload":{"addresses":["203.0.113.1:3478","[2001:db8::1]:3478"]}}"#;
+
+        let message = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            message,
+            IngressMessages::SiblingRelays(SiblingRelays {
+                addresses: vec![
+                    "203.0.113.1:3478".parse().unwrap(),
+                    "[2001:db8::1]:3478".parse().unwrap()
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn rotate_auth_secret_message() {
+        let message = r#"{"event":"rotate_auth_secret","payload":{"secret":"4c98bf59c99b3e467ecd7cf9d6b3e527"}}"#;
+
+        let message = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            message,
+            IngressMessages::RotateAuthSecret(RotateAuthSecret {
+                secret: "4c98bf59c99b3e467ecd7cf9d6b3e527".to_owned()
+            })
+        );
+        assert!(!format!("{message:?}").contains("4c98bf59"));
+    }
+
+    #[test]
+    fn revoke_user_message() {
+        let message = r#"{"event":"revoke_user","payload":{"username":"1700000000:client"}}"#;
+
+        let message = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            message,
+            IngressMessages::RevokeUser(RevokeUser {
+                username: "1700000000:client".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn start_draining_message() {
+        let message = r#"{"event":"start_draining","payload":{}}"#;
+
+        let message = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(message, IngressMessages::StartDraining(StartDraining {}));
+    }
+
+    #[test]
+    fn rotate_nonce_secret_message() {
+        let message = r#"{"event":"rotate_nonce_secret","payload":{"secret":"9f2a4e0c1d8b7a6f"}}"#;
+
+        let message = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            message,
+            IngressMessages::RotateNonceSecret(RotateNonceSecret {
+                secret: "9f2a4e0c1d8b7a6f".to_owned()
+            })
+        );
+        assert!(!format!("{message:?}").contains("9f2a4e0c"));
+    }
+
+    #[test]
+    fn set_log_level_message() {
+        let message = r#"{"event":"set_log_level","payload":{"directives":"info,relay=debug"}}"#;
+
+        let message = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            message,
+            IngressMessages::SetLogLevel(SetLogLevel {
+                directives: "info,relay=debug".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn ingress_replies() {
+        let revoked = IngressReplies::RevokedAllocations {
+            allocations: vec![serde_json::from_str("1").unwrap()],
+        };
+
+        assert_eq!(
+            serde_json::to_string(&revoked).unwrap(),
+            r#"{"allocations":[1]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&IngressReplies::Done {}).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn allocation_usage_message() {
+        let message = EgressMessages::AllocationUsage(AllocationUsage {
+            id: serde_json::from_str("1").unwrap(),
+            client: "203.0.113.1:50000".parse().unwrap(),
+            username: "1700000000:client".to_owned(),
+            traffic: Traffic {
+                bytes_to_peer: 100,
+                packets_to_peer: 1,
+                bytes_to_client: 200,
+                packets_to_client: 2,
+            },
+            channels: vec![ChannelUsage {
+                number: 0x4000,
+                peer: "198.51.100.1:443".parse().unwrap(),
+                traffic: Traffic {
+                    bytes_to_peer: 100,
+                    packets_to_peer: 1,
+                    bytes_to_client: 0,
+                    packets_to_client: 0,
+                },
+            }],
+        });
+
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"event":"allocation_usage","payload":{"id":1,"client":"203.0.113.1:50000","username":"1700000000:client","traffic":{"bytes_to_peer":100,"packets_to_peer":1,"bytes_to_client":200,"packets_to_client":2},"channels":[{"number":16384,"peer":"198.51.100.1:443","traffic":{"bytes_to_peer":100,"packets_to_peer":1,"bytes_to_client":0,"packets_to_client":0}}]}}"#
+        );
+    }
+}
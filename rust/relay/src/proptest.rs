@@ -1,14 +1,14 @@
-use crate::Binding;
+use crate::{Binding, PeerFilter};
 use proptest::arbitrary::any;
 use proptest::collection::vec;
 use proptest::strategy::Just;
 use proptest::strategy::Strategy;
 use proptest::string::string_regex;
+use std::net::{IpAddr, SocketAddrV4};
 use std::ops::Add;
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, RequestedTransport};
 use stun_codec::TransactionId;
-use uuid::Uuid;
 
 pub fn transaction_id() -> impl Strategy<Value = TransactionId> {
     any::<[u8; 12]>().prop_map(TransactionId::new)
@@ -37,12 +37,15 @@ pub fn channel_payload() -> impl Strategy<Value = (Vec<u8>, usize)> {
     })
 }
 
-pub fn username_salt() -> impl Strategy<Value = String> {
-    string_regex("[a-zA-Z0-9]{10}").unwrap()
+/// A peer that clients may relay data to by default.
+pub fn peer() -> impl Strategy<Value = SocketAddrV4> {
+    any::<SocketAddrV4>().prop_filter("peer must be allowed by default", |peer| {
+        PeerFilter::default().is_allowed(IpAddr::V4(*peer.ip()))
+    })
 }
 
-pub fn nonce() -> impl Strategy<Value = Uuid> {
-    any::<u128>().prop_map(Uuid::from_u128)
+pub fn username_salt() -> impl Strategy<Value = String> {
+    string_regex("[a-zA-Z0-9]{10}").unwrap()
 }
 
 /// We let "now" begin somewhere around 2000 up until 2100.
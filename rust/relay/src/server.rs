@@ -1,13 +1,45 @@
+mod capture;
 mod channel_data;
 mod client_message;
-
+mod inspect;
+mod nat_discovery;
+mod pcapng;
+mod peer_filter;
+mod quota;
+mod rfc6062;
+mod rfc7635;
+mod rfc8489;
+mod snapshot;
+mod traffic;
+
+pub use crate::server::capture::{
+    CaptureEnd, CaptureFilter, CaptureId, MAX_CAPTURE_DURATION, MIN_CAPTURE_BYTES,
+};
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
-    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
+    Allocate, Binding, ChannelBind, ClientMessage, Connect, ConnectionBind, CreatePermission,
+    Refresh, SendIndication,
 };
+pub use crate::server::inspect::{AllocationInfo, ChannelInfo};
+pub use crate::server::nat_discovery::{DiscoverySocket, NatDiscovery};
+pub use crate::server::peer_filter::PeerFilter;
+pub use crate::server::quota::Limits;
+pub use crate::server::rfc6062::{
+    ConnectionAlreadyExists, ConnectionId, ConnectionTimeoutOrFailure,
+};
+pub use crate::server::rfc7635::{AccessToken, ThirdPartyAuthorization};
+pub use crate::server::rfc8489::{MessageIntegritySha256, PasswordAlgorithm, PasswordAlgorithms};
+pub use crate::server::snapshot::Snapshot;
+pub use crate::server::traffic::{AllocationUsage, ChannelUsage, Traffic};
 
-use crate::auth::{MessageIntegrityExt, Nonces, FIREZONE};
-use crate::net_ext::IpAddrExt;
+use crate::auth::{
+    AccessTokenKeys, AuthSecrets, CredentialScheme, Integrity, Nonces, OpenedAccessToken, FIREZONE,
+};
+use crate::net_ext::{IpAddrExt, SocketAddrExt};
+use crate::server::capture::Capture;
+use crate::server::quota::TokenBucket;
+use crate::server::rfc6062::{CONNECT, CONNECTION_ATTEMPT, CONNECTION_BIND};
+use crate::server::traffic::Direction;
 use crate::{IpStack, TimeEvents};
 use anyhow::Result;
 use bytecodec::EncodeExt;
@@ -16,31 +48,41 @@ use opentelemetry::metrics::{Counter, Unit, UpDownCounter};
 use opentelemetry::KeyValue;
 use rand::Rng;
 use secrecy::SecretString;
-use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{
-    ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+    AlternateServer, ErrorCode, Fingerprint, MessageIntegrity, Nonce, Realm, Software,
+    UnknownAttributes, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{
+    BadRequest, StaleNonce, TryAlternate, Unauthorized, UnknownAttribute,
 };
-use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
+    ChannelNumber, Data, DontFragment, EvenPort, Lifetime, RequestedTransport, ReservationToken,
+    XorPeerAddress, XorRelayAddress,
+};
+use stun_codec::rfc5766::errors::{
+    AllocationMismatch, AllocationQuotaReached, Forbidden, InsufficientCapacity,
+    UnsupportedTransportProtocol, WrongCredentials,
 };
-use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
+use stun_codec::rfc5780::attributes::{ChangeRequest, OtherAddress, ResponseOrigin};
+use stun_codec::rfc8016::attributes::MobilityTicket;
+use stun_codec::rfc8016::errors::MobilityForbidden;
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
 use stun_codec::rfc8656::errors::{AddressFamilyNotSupported, PeerAddressFamilyMismatch};
 use stun_codec::{Message, MessageClass, MessageEncoder, Method, TransactionId};
-use tracing::{field, log, Span};
-use uuid::Uuid;
+use tracing::{field, Span};
 
 /// A sans-IO STUN & TURN server.
 ///
-/// A [`Server`] is bound to an IPv4 address and assumes to only operate on UDP.
+/// A [`Server`] is bound to an IPv4 address and assumes that a client's [`SocketAddr`] uniquely identifies it, regardless of whether it connects via UDP, TCP or TLS.
 /// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
 /// we can index data simply by the sender's [`SocketAddr`].
 ///
@@ -50,6 +92,7 @@ pub struct Server<R> {
     encoder: MessageEncoder<Attribute>,
 
     public_address: IpStack,
+    peer_filter: PeerFilter,
 
     /// All client allocations, indexed by client's socket address.
     allocations: HashMap<SocketAddr, Allocation>,
@@ -59,23 +102,69 @@ pub struct Server<R> {
     lowest_port: u16,
     highest_port: u16,
 
-    channels_by_number: HashMap<u16, Channel>,
-    channel_numbers_by_peer: HashMap<SocketAddr, u16>,
+    /// Channel bindings, scoped to the allocation they were created on.
+    ///
+    /// Each allocation has its own channel number space, thus two clients can use the same channel number concurrently.
+    channels_by_allocation: HashMap<(AllocationId, u16), Channel>,
+    channel_numbers_by_peer: HashMap<(AllocationId, SocketAddr), u16>,
+
+    /// Clients connected via a stream-oriented transport (TCP or TLS).
+    ///
+    /// Only these can create TCP allocations or bind peer connections, see <https://www.rfc-editor.org/rfc/rfc6062#section-4>.
+    stream_clients: HashSet<SocketAddr>,
+    /// TCP connections between TCP allocations and their peers.
+    connections: HashMap<ConnectionId, Connection>,
+    next_connection_id: ConnectionId,
+
+    /// Ports reserved via an EVEN-PORT attribute, indexed by their RESERVATION-TOKEN.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-allocate-reque>.
+    reservations: HashMap<u64, u16>,
 
     pending_commands: VecDeque<Command>,
+    pending_events: VecDeque<Event>,
     next_allocation_id: AllocationId,
 
     rng: R,
 
-    auth_secret: SecretString,
+    auth_secrets: AuthSecrets,
+    /// How long we accept credentials minted with the previous secret after a rotation.
+    auth_secret_grace_period: Duration,
 
     nonces: Nonces,
+    /// The keys to decrypt access tokens with, if we accept them.
+    access_token_keys: Option<AccessTokenKeys>,
+
+    /// Limits applied to all allocations that were created with the same username.
+    user_limits: Limits,
+    /// Limits applied to all allocations that were created from the same source IP.
+    ip_limits: Limits,
+    bandwidth_by_user: HashMap<String, TokenBucket>,
+    bandwidth_by_ip: HashMap<IpAddr, TokenBucket>,
+
+    /// Whether we are draining, i.e. refusing new allocations while serving existing ones.
+    draining: bool,
+    /// Sibling relays we redirect clients to if we don't accept new allocations.
+    alternate_servers: Vec<SocketAddr>,
+    /// The fraction of our port range in use above which we redirect new allocations to a sibling relay.
+    load_shedding_threshold: Option<f64>,
+
+    /// Our addresses for NAT behaviour discovery, if enabled.
+    nat_discovery: Option<NatDiscovery>,
+
+    /// The addresses we listen on for clients, per address family.
+    listen_addrs: HashMap<AddressFamily, SocketAddr>,
+    /// Captures of relayed traffic in progress.
+    captures: HashMap<CaptureId, Capture>,
+    next_capture_id: CaptureId,
 
     time_events: TimeEvents<TimedAction>,
 
     allocations_up_down_counter: UpDownCounter<i64>,
+    channel_bindings_up_down_counter: UpDownCounter<i64>,
     data_relayed_counter: Counter<u64>,
     responses_counter: Counter<u64>,
+    auth_failures_counter: Counter<u64>,
 }
 
 /// The commands returned from a [`Server`].
@@ -87,15 +176,26 @@ pub enum Command {
         payload: Vec<u8>,
         recipient: SocketAddr,
     },
+    /// Like [`Command::SendMessage`] but from one of the sockets that only serve NAT behaviour discovery.
+    ///
+    /// Only emitted if NAT behaviour discovery is enabled, see [`Server::with_nat_discovery`].
+    SendMessageFrom {
+        payload: Vec<u8>,
+        recipient: SocketAddr,
+        socket: DiscoverySocket,
+    },
     /// Listen for traffic on the provided port [AddressFamily].
     ///
     /// Any incoming data should be handed to the [`Server`] via [`Server::handle_relay_input`].
     /// A single allocation can reference one of either [AddressFamily]s or both.
     /// Only the combination of [AllocationId] and [AddressFamily] is unique.
+    ///
+    /// If `dont_fragment` is set, datagrams sent to peers must not be fragmented.
     CreateAllocation {
         id: AllocationId,
         family: AddressFamily,
         port: u16,
+        dont_fragment: bool,
     },
     /// Free the allocation associated with the given [`AllocationId`] and [AddressFamily]
     FreeAllocation {
@@ -108,11 +208,121 @@ pub enum Command {
         data: Vec<u8>,
         receiver: SocketAddr,
     },
+    /// Accept TCP connections on the provided port and [AddressFamily] for a TCP allocation.
+    ///
+    /// Each accepted connection should be handed to the [`Server`] via [`Server::handle_peer_connection`].
+    /// Like [`Command::CreateAllocation`], it is freed with a [`Command::FreeAllocation`].
+    CreateTcpAllocation {
+        id: AllocationId,
+        family: AddressFamily,
+        port: u16,
+    },
+    /// Open a TCP connection from the given TCP allocation to the peer.
+    ///
+    /// The outcome should be reported via [`Server::handle_peer_connected`] or [`Server::handle_peer_connection_failed`].
+    ConnectToPeer {
+        id: AllocationId,
+        connection: ConnectionId,
+        peer: SocketAddr,
+    },
+    /// Send `response` to the client on its data connection and from then on, relay all data between this connection and the peer connection without looking at it.
+    ///
+    /// The response is part of this command because the client starts sending data as soon as it received it.
+    SpliceConnection {
+        connection: ConnectionId,
+        client: SocketAddr,
+        response: Vec<u8>,
+    },
+    /// Close the given peer connection and, if it is spliced, the client's data connection.
+    CloseConnection { connection: ConnectionId },
     /// At the latest, the [`Server`] needs to be woken at the specified deadline to execute time-based actions correctly.
     Wake { deadline: SystemTime },
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// The events emitted by a [`Server`].
+///
+/// Unlike [`Command`]s, events are purely informational and can be ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A client created a new allocation.
+    AllocationCreated {
+        id: AllocationId,
+        client: SocketAddr,
+        username: String,
+        /// The addresses on which we relay data for this allocation.
+        relay_addresses: Vec<SocketAddr>,
+        lifetime: Duration,
+    },
+    /// A client extended the lifetime of its allocation.
+    AllocationRefreshed {
+        id: AllocationId,
+        client: SocketAddr,
+        username: String,
+        lifetime: Duration,
+    },
+    /// An allocation moved to a new client address using a mobility ticket.
+    AllocationMoved {
+        id: AllocationId,
+        username: String,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+    /// A client bound a new channel to a peer.
+    ChannelBound {
+        id: AllocationId,
+        client: SocketAddr,
+        username: String,
+        number: u16,
+        peer: SocketAddr,
+    },
+    /// An allocation was deleted, together with the data that was relayed through it over its lifetime.
+    AllocationDeleted {
+        usage: AllocationUsage,
+        reason: DeletionReason,
+        relay_addresses: Vec<SocketAddr>,
+    },
+    /// Data to append to the file of a capture, in the pcapng format.
+    ///
+    /// The first data of each capture is the file header.
+    CaptureData { id: CaptureId, bytes: Vec<u8> },
+    /// A capture ended, no more data will follow for it.
+    CaptureFinished { id: CaptureId, reason: CaptureEnd },
+}
+
+/// Why an allocation was deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionReason {
+    /// The client didn't refresh the allocation before its lifetime passed.
+    Expired,
+    /// The client deleted the allocation by refreshing it with a lifetime of 0.
+    ClientDeleted,
+    /// The client closed the TCP or TLS connection the allocation was created on.
+    ClientDisconnected,
+    /// An operator deleted the allocation via the admin API.
+    AdminKilled,
+    /// The portal revoked the user of the allocation.
+    Revoked,
+    /// We shut down before the allocation was gone because draining timed out.
+    Drained,
+    /// Relaying data for the allocation failed, e.g. because its port could not be bound.
+    Failed,
+}
+
+impl DeletionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeletionReason::Expired => "expired",
+            DeletionReason::ClientDeleted => "client_deleted",
+            DeletionReason::ClientDisconnected => "client_disconnected",
+            DeletionReason::AdminKilled => "admin_killed",
+            DeletionReason::Revoked => "revoked",
+            DeletionReason::Drained => "drained",
+            DeletionReason::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct AllocationId(u64);
 
 impl AllocationId {
@@ -134,17 +344,57 @@ impl fmt::Display for AllocationId {
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-requested-transport>.
 const UDP_TRANSPORT: u8 = 17;
 
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.1>.
+const TCP_TRANSPORT: u8 = 6;
+
+/// How long a peer connection waits for the client to bind it via a ConnectionBind request.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.3>.
+const CONNECTION_BIND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we keep a port reserved for a subsequent allocation request with the corresponding RESERVATION-TOKEN.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-allocate-reque>.
+const RESERVATION_DURATION: Duration = Duration::from_secs(30);
+
 /// The duration of a channel binding.
 ///
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
 const CHANNEL_BINDING_DURATION: Duration = Duration::from_secs(600);
 
+/// How long a channel number and peer address remain reserved after a channel binding expired.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
+const CHANNEL_REUSE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long we accept credentials minted with the previous secret after a rotation by default.
+const DEFAULT_AUTH_SECRET_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The port on which clients reach us unless configured otherwise via [`Server::with_listen_addr`].
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#section-4>.
+const DEFAULT_LISTEN_PORT: u16 = 3478;
+
+/// The lifetime of a permission.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-permissions>.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
+/// The `SOFTWARE` attribute we include in all responses.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-14.14>.
+const SOFTWARE: Software =
+    Software::new_static(concat!("firezone-relay/", env!("CARGO_PKG_VERSION")));
+
 impl<R> Server<R>
 where
     R: Rng,
 {
     /// Constructs a new [`Server`].
     ///
+    /// The public address must be a unicast address, which the binary validates on startup together with the listen addresses.
+    /// Loopback addresses are accepted, e.g. to test the relay locally.
+    ///
     /// # Port configuration
     ///
     /// The [TURN RFC](https://www.rfc-editor.org/rfc/rfc8656#section-7.2-6) recommends using the port range `49152 - 65535`.
@@ -157,18 +407,24 @@ where
         lowest_port: u16,
         highest_port: u16,
     ) -> Self {
-        // TODO: Validate that local IP isn't multicast / loopback etc.
-
         let meter = opentelemetry_api::global::meter("relay");
 
         let allocations_up_down_counter = meter
             .i64_up_down_counter("allocations_total")
             .with_description("The number of active allocations")
             .init();
+        let channel_bindings_up_down_counter = meter
+            .i64_up_down_counter("channel_bindings_total")
+            .with_description("The number of active channel bindings")
+            .init();
         let responses_counter = meter
             .u64_counter("responses_total")
             .with_description("The number of responses")
             .init();
+        let auth_failures_counter = meter
+            .u64_counter("auth_failures_total")
+            .with_description("The number of requests that failed authentication")
+            .init();
         let data_relayed_counter = meter
             .u64_counter("data_relayed_bytes")
             .with_description("The number of bytes relayed")
@@ -179,34 +435,215 @@ where
             decoder: Default::default(),
             encoder: Default::default(),
             public_address: public_address.into(),
+            peer_filter: PeerFilter::default(),
             allocations: Default::default(),
             clients_by_allocation: Default::default(),
             allocations_by_port: Default::default(),
             lowest_port,
             highest_port,
-            channels_by_number: Default::default(),
+            channels_by_allocation: Default::default(),
             channel_numbers_by_peer: Default::default(),
+            stream_clients: Default::default(),
+            connections: Default::default(),
+            next_connection_id: ConnectionId::new(1),
+            reservations: Default::default(),
             pending_commands: Default::default(),
+            pending_events: Default::default(),
             next_allocation_id: AllocationId(1),
-            auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+            auth_secrets: AuthSecrets::new(SecretString::from(hex::encode(rng.gen::<[u8; 32]>()))),
+            auth_secret_grace_period: DEFAULT_AUTH_SECRET_GRACE_PERIOD,
+            nonces: Nonces::new(
+                SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+                Nonces::DEFAULT_LIFETIME,
+            ),
+            access_token_keys: None,
             rng,
             time_events: TimeEvents::default(),
-            nonces: Default::default(),
+            user_limits: Limits::default(),
+            ip_limits: Limits::default(),
+            bandwidth_by_user: Default::default(),
+            bandwidth_by_ip: Default::default(),
+            draining: false,
+            alternate_servers: Vec::new(),
+            load_shedding_threshold: None,
+            nat_discovery: None,
+            listen_addrs: Default::default(),
+            captures: Default::default(),
+            next_capture_id: CaptureId::new(1),
             allocations_up_down_counter,
+            channel_bindings_up_down_counter,
             responses_counter,
             data_relayed_counter,
+            auth_failures_counter,
         }
     }
 
+    /// Restrict the peers that clients can relay data to.
+    ///
+    /// Regardless of the filter, we never relay to our own public addresses.
+    pub fn with_peer_filter(mut self, peer_filter: PeerFilter) -> Self {
+        self.peer_filter = peer_filter;
+
+        self
+    }
+
+    /// Limit the resources that can be used with a single username.
+    pub fn with_user_limits(mut self, limits: Limits) -> Self {
+        self.user_limits = limits;
+
+        self
+    }
+
+    /// Limit the resources that can be used from a single source IP.
+    pub fn with_ip_limits(mut self, limits: Limits) -> Self {
+        self.ip_limits = limits;
+
+        self
+    }
+
+    /// Redirect clients to one of the given sibling relays in case we don't accept new allocations, e.g. because we are draining.
+    pub fn with_alternate_servers(mut self, alternate_servers: Vec<SocketAddr>) -> Self {
+        self.set_alternate_servers(alternate_servers);
+
+        self
+    }
+
+    /// Redirect new allocations to a sibling relay once the given fraction of our port range is in use.
+    ///
+    /// Has no effect unless alternate servers are configured.
+    pub fn with_load_shedding_threshold(mut self, threshold: f64) -> Self {
+        self.load_shedding_threshold = Some(threshold);
+
+        self
+    }
+
+    /// Answer BINDING requests on additional addresses and support the attributes for NAT behaviour discovery.
+    ///
+    /// Requests received on the additional sockets should be handed to the [`Server`] via [`Server::handle_discovery_input`].
+    /// See <https://www.rfc-editor.org/rfc/rfc5780> for details.
+    pub fn with_nat_discovery(mut self, nat_discovery: NatDiscovery) -> Self {
+        self.nat_discovery = Some(nat_discovery);
+
+        self
+    }
+
+    /// The address we listen on for clients of its address family, which may differ from our public address.
+    ///
+    /// We never relay data to it and use its port, 3478 by default, to synthesize the headers of captured traffic between clients and us.
+    pub fn with_listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.listen_addrs.insert(addr.family(), addr);
+
+        self
+    }
+
+    /// Replaces the set of sibling relays, e.g. after the portal sent us an update.
+    pub fn set_alternate_servers(&mut self, alternate_servers: Vec<SocketAddr>) {
+        tracing::info!(target: "relay", ?alternate_servers, "Updated alternate servers");
+
+        self.alternate_servers = alternate_servers;
+    }
+
+    /// Stop accepting new allocations.
+    ///
+    /// Existing allocations continue to be served and can be refreshed.
+    /// New Allocate requests are redirected to an alternate server with a 300 (Try Alternate) response if one is configured, otherwise they are rejected with a 508 (Insufficient Capacity).
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// The number of currently active allocations.
+    pub fn num_active_allocations(&self) -> usize {
+        self.allocations.len()
+    }
+
+    /// Whether we have ports left for new allocations.
+    pub fn has_available_ports(&self) -> bool {
+        self.num_used_ports() < self.max_available_ports() as usize
+    }
+
+    /// Use the given secret to authenticate clients instead of a randomly generated one.
+    ///
+    /// Credentials handed out by the portal are derived from this secret, thus configuring it allows them to remain valid across restarts.
+    pub fn with_auth_secret(mut self, auth_secret: SecretString) -> Self {
+        self.auth_secrets.set_current(auth_secret);
+
+        self
+    }
+
+    /// How long credentials minted with the previous secret remain valid after a rotation.
+    pub fn with_auth_secret_grace_period(mut self, grace_period: Duration) -> Self {
+        self.auth_secret_grace_period = grace_period;
+
+        self
+    }
+
+    /// How client credentials are derived from the auth secret.
+    ///
+    /// Defaults to [`CredentialScheme::Firezone`], i.e. credentials minted by the portal.
+    pub fn with_credential_scheme(mut self, scheme: CredentialScheme) -> Self {
+        self.auth_secrets.set_scheme(scheme);
+
+        self
+    }
+
+    /// The secret that credentials for new allocations should be minted with.
     pub fn auth_secret(&self) -> &SecretString {
-        &self.auth_secret
+        self.auth_secrets.current()
+    }
+
+    /// Replaces the secret used to authenticate clients.
+    ///
+    /// Credentials minted with the old secret remain valid for the configured grace period, thus existing allocations are not affected.
+    pub fn rotate_auth_secret(&mut self, auth_secret: SecretString, now: SystemTime) {
+        self.auth_secrets
+            .rotate(auth_secret, now, now + self.auth_secret_grace_period);
+
+        tracing::info!(target: "relay", grace_period = ?self.auth_secret_grace_period, "Rotated auth secret");
+    }
+
+    /// Use the given secret to issue and validate nonces instead of a randomly generated one.
+    ///
+    /// Relays sharing the same secret accept each other's nonces.
+    pub fn with_nonce_secret(mut self, nonce_secret: SecretString) -> Self {
+        self.nonces.set_secret(nonce_secret);
+
+        self
+    }
+
+    /// Replaces the secret used to issue and validate nonces, e.g. after the portal pushed a new one to all relays.
+    ///
+    /// Outstanding nonces become invalid, clients using them are told to retry with a fresh one via a 438 (Stale Nonce).
+    pub fn rotate_nonce_secret(&mut self, nonce_secret: SecretString) {
+        self.nonces.set_secret(nonce_secret);
+
+        tracing::info!(target: "relay", "Rotated nonce secret");
+    }
+
+    /// How long a nonce remains valid after it has been issued.
+    ///
+    /// Requests with an expired nonce are rejected with a 438 (Stale Nonce), prompting the client to retry with a fresh one.
+    pub fn with_nonce_lifetime(mut self, lifetime: Duration) -> Self {
+        self.nonces.set_lifetime(lifetime);
+
+        self
     }
 
-    /// Registers a new, valid nonce.
+    /// Accept access tokens issued by a third-party authorization server, in addition to long-term credentials.
     ///
-    /// Each nonce is valid for 10 requests.
-    pub fn add_nonce(&mut self, nonce: Uuid) {
-        self.nonces.add_new(nonce);
+    /// See <https://www.rfc-editor.org/rfc/rfc7635> for details.
+    pub fn with_access_token_keys(mut self, keys: AccessTokenKeys) -> Self {
+        self.access_token_keys = Some(keys);
+
+        self
+    }
+
+    /// Issue a nonce for the given client, as we do in 401 (Unauthorized) and 438 (Stale Nonce) responses.
+    pub fn issue_nonce(&self, client: SocketAddr, now: SystemTime) -> String {
+        self.nonces.issue(client, now)
     }
 
     /// Process the bytes received from a client.
@@ -229,7 +666,7 @@ where
             }
             // Could parse the bytes but message was semantically invalid (like missing attribute).
             Ok(Err(error_code)) => {
-                self.queue_error_response(sender, error_code);
+                self.queue_error_response(sender, error_code, now);
             }
             // Parsing the bytes failed.
             Err(client_message::Error::BadChannelData(ref error)) => {
@@ -262,24 +699,79 @@ where
             ClientMessage::CreatePermission(request) => {
                 self.handle_create_permission_request(request, sender, now)
             }
+            ClientMessage::Connect(request) => self.handle_connect_request(request, sender, now),
+            ClientMessage::ConnectionBind(request) => {
+                self.handle_connection_bind_request(request, sender, now)
+            }
             ClientMessage::Binding(request) => {
-                self.handle_binding_request(request, sender);
+                self.handle_binding_request(request, sender, DiscoverySocket::PRIMARY);
                 return;
             }
             ClientMessage::ChannelData(msg) => {
                 self.handle_channel_data_message(msg, sender, now);
                 return;
             }
+            ClientMessage::SendIndication(msg) => {
+                self.handle_send_indication(msg, sender, now);
+                return;
+            }
         };
 
         let Err(error_response) = result else {
             return;
         };
 
-        self.queue_error_response(sender, error_response)
+        self.queue_error_response(sender, error_response, now)
+    }
+
+    /// Process the bytes received on one of our NAT behaviour discovery sockets, other than the primary one.
+    ///
+    /// These sockets only answer BINDING requests, all other messages are dropped.
+    /// After calling this method, you should call [`Server::next_command`] until it returns `None`.
+    #[tracing::instrument(skip_all, fields(transaction_id, %sender, ?socket), level = "error")]
+    pub fn handle_discovery_input(
+        &mut self,
+        bytes: &[u8],
+        sender: SocketAddr,
+        socket: DiscoverySocket,
+    ) {
+        match self.decoder.decode(bytes) {
+            Ok(Ok(message)) => {
+                if let Some(id) = message.transaction_id() {
+                    Span::current().record("transaction_id", hex::encode(id.as_bytes()));
+                }
+
+                self.handle_discovery_message(message, sender, socket);
+            }
+            Ok(Err(error_response)) => {
+                self.send_message_from(error_response, sender, socket);
+            }
+            Err(_) => {
+                tracing::debug!("failed to decode message on NAT behaviour discovery socket")
+            }
+        }
+    }
+
+    pub fn handle_discovery_message(
+        &mut self,
+        message: ClientMessage,
+        sender: SocketAddr,
+        socket: DiscoverySocket,
+    ) {
+        let ClientMessage::Binding(request) = message else {
+            tracing::debug!("Dropping non-BINDING message on NAT behaviour discovery socket");
+            return;
+        };
+
+        self.handle_binding_request(request, sender, socket);
     }
 
-    fn queue_error_response(&mut self, sender: SocketAddr, mut error_response: Message<Attribute>) {
+    fn queue_error_response(
+        &mut self,
+        sender: SocketAddr,
+        mut error_response: Message<Attribute>,
+        now: SystemTime,
+    ) {
         // In case of a 401 or 438 response, attach a realm and nonce.
         if error_response
             .get_attribute::<ErrorCode>()
@@ -287,73 +779,128 @@ where
                 error == &ErrorCode::from(Unauthorized) || error == &ErrorCode::from(StaleNonce)
             })
         {
-            let new_nonce = Uuid::from_u128(self.rng.gen());
-
-            self.add_nonce(new_nonce);
-
-            error_response.add_attribute(Nonce::new(new_nonce.to_string()).unwrap());
+            error_response.add_attribute(Nonce::new(self.nonces.issue(sender, now)).unwrap());
             error_response.add_attribute((*FIREZONE).clone());
+            error_response.add_attribute(supported_password_algorithms());
+
+            if let Some(keys) = &self.access_token_keys {
+                error_response
+                    .add_attribute(ThirdPartyAuthorization::new(keys.server_name().to_owned()));
+            }
         }
 
         self.send_message(error_response, sender);
     }
 
     /// Process the bytes received from an allocation.
+    ///
+    /// Data is only relayed to the client if the sending peer has a permission on the allocation.
+    /// If a channel is bound to the peer, we relay the data as channel data, otherwise as a DATA indication.
     #[tracing::instrument(skip_all, fields(%sender, %allocation_id, recipient, channel), level = "error")]
     pub fn handle_relay_input(
         &mut self,
         bytes: &[u8],
         sender: SocketAddr,
         allocation_id: AllocationId,
+        now: SystemTime,
     ) {
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(bytes);
             tracing::trace!(target: "wire", %hex_bytes, "receiving bytes");
         }
 
-        let Some(recipient) = self.clients_by_allocation.get(&allocation_id) else {
+        let Some(recipient) = self.clients_by_allocation.get(&allocation_id).copied() else {
             tracing::debug!(target: "relay", "unknown allocation");
             return;
         };
 
         Span::current().record("recipient", field::display(&recipient));
 
-        let Some(channel_number) = self.channel_numbers_by_peer.get(&sender) else {
-            tracing::debug!(target: "relay", "no active channel, refusing to relay {} bytes", bytes.len());
-            return;
-        };
-
-        Span::current().record("channel", channel_number);
-
-        let Some(channel) = self.channels_by_number.get(channel_number) else {
-            debug_assert!(false, "unknown channel {}", channel_number);
+        let Some(allocation) = self.allocations.get(&recipient) else {
+            debug_assert!(false, "internal state mismatch");
             return;
         };
 
-        if !channel.bound {
-            tracing::debug!(target: "relay", "channel existed but is unbound");
+        if !allocation.has_permission(sender.ip(), now) {
+            tracing::debug!(target: "relay", "no permission, refusing to relay {} bytes", bytes.len());
             return;
         }
 
-        if channel.allocation != allocation_id {
-            tracing::debug!(target: "relay", "channel is not associated with allocation");
+        let channel_number = self
+            .channel_numbers_by_peer
+            .get(&(allocation_id, sender))
+            .filter(|number| {
+                self.channels_by_allocation
+                    .get(&(allocation_id, **number))
+                    .map_or(false, |channel| channel.bound)
+            })
+            .copied();
+
+        if !self.consume_bandwidth(recipient, bytes.len(), now) {
+            tracing::debug!(target: "relay", "Bandwidth quota exceeded, dropping {} bytes", bytes.len());
             return;
         }
 
         tracing::debug!(target: "relay", "Relaying {} bytes", bytes.len());
 
-        self.data_relayed_counter.add(bytes.len() as u64, &[]);
+        let Some(channel_number) = channel_number else {
+            let Ok(data) = Data::new(bytes.to_vec()) else {
+                tracing::debug!(target: "relay", "too much data for a DATA indication");
+                return;
+            };
+
+            let mut message = Message::new(
+                MessageClass::Indication,
+                DATA,
+                TransactionId::new(self.rng.gen()),
+            );
+            message.add_attribute(XorPeerAddress::new(sender));
+            message.add_attribute(data);
+
+            self.capture(
+                recipient,
+                sender,
+                Direction::ToClient,
+                || {
+                    MessageEncoder::<Attribute>::default()
+                        .encode_into_bytes(message.clone())
+                        .ok()
+                },
+                bytes,
+                now,
+            );
+            self.record_traffic(recipient, None, Direction::ToClient, bytes.len());
+            self.send_message(message, recipient);
+
+            return;
+        };
+
+        Span::current().record("channel", channel_number);
 
-        let data = ChannelData::new(*channel_number, bytes).to_bytes();
+        let data = ChannelData::new(channel_number, bytes).to_bytes();
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(&data);
             tracing::trace!(target: "wire", %hex_bytes, "sending bytes");
         }
 
+        self.capture(
+            recipient,
+            sender,
+            Direction::ToClient,
+            || Some(data.clone()),
+            bytes,
+            now,
+        );
+        self.record_traffic(
+            recipient,
+            Some(channel_number),
+            Direction::ToClient,
+            bytes.len(),
+        );
         self.pending_commands.push_back(Command::SendMessage {
             payload: data,
-            recipient: *recipient,
+            recipient,
         })
     }
 
@@ -369,11 +916,11 @@ where
                     };
 
                     if allocation.is_expired(now) {
-                        self.delete_allocation(id)
+                        self.delete_allocation(id, DeletionReason::Expired)
                     }
                 }
-                TimedAction::UnbindChannel(chan) => {
-                    let Some(channel) = self.channels_by_number.get_mut(&chan) else {
+                TimedAction::UnbindChannel(id, chan) => {
+                    let Some(channel) = self.channels_by_allocation.get_mut(&(id, chan)) else {
                         tracing::debug!(target: "relay", "Cannot expire non-existing channel binding {chan}");
 
                         continue;
@@ -383,92 +930,499 @@ where
                         tracing::info!(target: "relay", "Channel {chan} is now expired");
 
                         channel.bound = false;
+                        self.channel_bindings_up_down_counter.add(-1, &[]);
 
                         self.time_events.add(
-                            now + Duration::from_secs(5 * 60),
-                            TimedAction::DeleteChannel(chan),
+                            now + CHANNEL_REUSE_TIMEOUT,
+                            TimedAction::DeleteChannel(id, chan),
                         );
                     }
                 }
-                TimedAction::DeleteChannel(chan) => {
-                    self.delete_channel_binding(chan);
+                TimedAction::DeleteChannel(id, chan) => {
+                    self.delete_channel_binding(id, chan);
+                }
+                TimedAction::ExpireConnection(connection) => {
+                    if !self
+                        .connections
+                        .get(&connection)
+                        .map_or(false, |c| c.state == ConnectionState::Pending)
+                    {
+                        continue;
+                    }
+
+                    tracing::debug!(target: "relay", %connection, "Peer connection was not bound in time");
+
+                    self.delete_connection(connection);
+                }
+                TimedAction::ExpireReservation(token) => {
+                    let Some(port) = self.reservations.remove(&token) else {
+                        continue; // The reservation has been claimed.
+                    };
+
+                    tracing::debug!(target: "relay", %port, "Reservation expired");
+                }
+                TimedAction::ExpireCapture(id) => {
+                    if self
+                        .captures
+                        .get(&id)
+                        .map_or(false, |capture| capture.expires_at <= now)
+                    {
+                        self.finish_capture(id, CaptureEnd::Duration);
+                    }
                 }
             }
         }
     }
 
-    /// An allocation failed.
-    #[tracing::instrument(skip(self), fields(%allocation_id), level = "error")]
-    pub fn handle_allocation_failed(&mut self, allocation_id: AllocationId) {
-        self.delete_allocation(allocation_id)
+    /// A client connected via a stream-oriented transport (TCP or TLS).
+    pub fn handle_client_connected(&mut self, sender: SocketAddr) {
+        self.stream_clients.insert(sender);
     }
 
-    /// Return the next command to be executed.
-    pub fn next_command(&mut self) -> Option<Command> {
-        let num_commands = self.pending_commands.len();
-
-        if num_commands > 0 {
-            tracing::trace!(%num_commands, "Popping next command");
+    /// A client connected via a stream-oriented transport (TCP or TLS) closed its connection.
+    ///
+    /// An allocation is bound to the connection it was created on, thus we delete it.
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+    /// Likewise, a data connection of a TCP allocation takes its peer connection down with it.
+    #[tracing::instrument(skip(self), fields(%sender), level = "error")]
+    pub fn handle_client_disconnected(&mut self, sender: SocketAddr) {
+        self.stream_clients.remove(&sender);
+
+        if let Some(connection) = self.connections.iter().find_map(|(id, c)| {
+            (c.state == ConnectionState::Bound { client: sender }).then_some(*id)
+        }) {
+            self.delete_connection(connection);
         }
 
-        self.pending_commands.pop_front()
-    }
-
-    fn handle_binding_request(&mut self, message: Binding, sender: SocketAddr) {
-        let mut message = Message::new(
-            MessageClass::SuccessResponse,
-            BINDING,
-            message.transaction_id(),
-        );
-        message.add_attribute(XorMappedAddress::new(sender));
+        let Some(allocation) = self.allocations.get(&sender) else {
+            return;
+        };
 
-        self.send_message(message, sender);
+        self.delete_allocation(allocation.id, DeletionReason::ClientDisconnected)
     }
 
-    /// Handle a TURN allocate request.
+    /// A peer connected to the given TCP allocation.
     ///
-    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-allocate-reque> for details.
-    #[tracing::instrument(skip(self, request, now), fields(%sender, effective_lifetime = ?request.effective_lifetime().lifetime()), level = "error")]
-    fn handle_allocate_request(
+    /// Returns the [`ConnectionId`] of the new connection, to be used in a later [`Command::SpliceConnection`].
+    /// Returns `None` if the connection should be closed right away because the peer doesn't have a permission.
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.3>.
+    #[tracing::instrument(skip(self, now), fields(%allocation_id, %peer), level = "error")]
+    pub fn handle_peer_connection(
         &mut self,
-        request: Allocate,
-        sender: SocketAddr,
+        allocation_id: AllocationId,
+        peer: SocketAddr,
         now: SystemTime,
-    ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+    ) -> Option<ConnectionId> {
+        let client = self.clients_by_allocation.get(&allocation_id).copied()?;
+        let allocation = self.allocations.get(&client)?;
 
-        if self.allocations.contains_key(&sender) {
-            return Err(error_response(AllocationMismatch, &request));
+        if allocation.transport != Transport::Tcp {
+            debug_assert!(false, "peer connection on UDP allocation");
+            return None;
         }
 
-        if self.allocations_by_port.len() == self.max_available_ports() as usize {
-            return Err(error_response(InsufficientCapacity, &request));
+        if !allocation.has_permission(peer.ip(), now) {
+            tracing::debug!(target: "relay", "No permission, refusing peer connection");
+            return None;
         }
 
-        if request.requested_transport().protocol() != UDP_TRANSPORT {
-            return Err(error_response(BadRequest, &request));
+        if self
+            .connections
+            .values()
+            .any(|c| c.allocation == allocation_id && c.peer == peer)
+        {
+            tracing::debug!(target: "relay", "Peer is already connected");
+            return None;
         }
 
-        let (first_relay_address, maybe_second_relay_addr) = derive_relay_addresses(
-            self.public_address,
-            request.requested_address_family(),
-            request.additional_address_family(),
-        )
-        .map_err(|e| error_response(e, &request))?;
-
-        // TODO: Do we need to handle DONT-FRAGMENT?
-        // TODO: Do we need to handle EVEN/ODD-PORT?
-        let effective_lifetime = request.effective_lifetime();
-
-        let allocation = self.create_new_allocation(
-            now,
-            &effective_lifetime,
-            first_relay_address,
-            maybe_second_relay_addr,
+        let connection = self.next_connection_id.next();
+        self.connections.insert(
+            connection,
+            Connection {
+                allocation: allocation_id,
+                peer,
+                state: ConnectionState::Pending,
+            },
         );
+        self.expire_connection_at(connection, now + CONNECTION_BIND_TIMEOUT);
 
         let mut message = Message::new(
-            MessageClass::SuccessResponse,
+            MessageClass::Indication,
+            rfc6062::method(CONNECTION_ATTEMPT),
+            TransactionId::new(self.rng.gen()),
+        );
+        message.add_attribute(XorPeerAddress::new(peer));
+        message.add_attribute(connection);
+
+        tracing::info!(target: "relay", %connection, "Peer connected");
+
+        self.send_message(message, client);
+
+        Some(connection)
+    }
+
+    /// The TCP connection to a peer requested via [`Command::ConnectToPeer`] has been established.
+    #[tracing::instrument(skip(self, now), fields(%connection), level = "error")]
+    pub fn handle_peer_connected(&mut self, connection: ConnectionId, now: SystemTime) {
+        let Some(c) = self.connections.get_mut(&connection) else {
+            // The allocation has been deleted in the meantime.
+            self.pending_commands
+                .push_back(Command::CloseConnection { connection });
+            return;
+        };
+        let ConnectionState::Connecting { transaction_id } = c.state else {
+            debug_assert!(false, "connection is not connecting");
+            return;
+        };
+        let Some(client) = self.clients_by_allocation.get(&c.allocation).copied() else {
+            return;
+        };
+
+        c.state = ConnectionState::Pending;
+        self.expire_connection_at(connection, now + CONNECTION_BIND_TIMEOUT);
+
+        let mut message = Message::new(
+            MessageClass::SuccessResponse,
+            rfc6062::method(CONNECT),
+            transaction_id,
+        );
+        message.add_attribute(connection);
+
+        tracing::info!(target: "relay", "Connected to peer");
+
+        self.send_message(message, client);
+    }
+
+    /// The TCP connection to a peer requested via [`Command::ConnectToPeer`] failed or timed out.
+    #[tracing::instrument(skip(self), fields(%connection), level = "error")]
+    pub fn handle_peer_connection_failed(&mut self, connection: ConnectionId) {
+        let Some(c) = self.connections.remove(&connection) else {
+            return;
+        };
+        let ConnectionState::Connecting { transaction_id } = c.state else {
+            debug_assert!(false, "connection is not connecting");
+            return;
+        };
+        let Some(client) = self.clients_by_allocation.get(&c.allocation).copied() else {
+            return;
+        };
+
+        tracing::debug!(target: "relay", peer = %c.peer, "Failed to connect to peer");
+
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            rfc6062::method(CONNECT),
+            transaction_id,
+        );
+        message.add_attribute(ErrorCode::from(ConnectionTimeoutOrFailure));
+
+        self.send_message(message, client);
+    }
+
+    /// An allocation failed.
+    #[tracing::instrument(skip(self), fields(%allocation_id), level = "error")]
+    pub fn handle_allocation_failed(&mut self, allocation_id: AllocationId) {
+        self.delete_allocation(allocation_id, DeletionReason::Failed)
+    }
+
+    /// Deletes the given allocation regardless of its lifetime, e.g. because an operator asked us to.
+    ///
+    /// Returns `false` if there is no such allocation.
+    #[tracing::instrument(skip(self), fields(%allocation_id), level = "error")]
+    pub fn kill_allocation(&mut self, allocation_id: AllocationId, reason: DeletionReason) -> bool {
+        if !self.clients_by_allocation.contains_key(&allocation_id) {
+            return false;
+        }
+
+        tracing::info!(target: "relay", reason = reason.as_str(), "Killing allocation");
+
+        self.delete_allocation(allocation_id, reason);
+
+        true
+    }
+
+    /// Deletes all allocations that were created with the given username.
+    ///
+    /// Returns the IDs of the deleted allocations.
+    #[tracing::instrument(skip(self), level = "error")]
+    pub fn kill_allocations_of_user(
+        &mut self,
+        username: &str,
+        reason: DeletionReason,
+    ) -> Vec<AllocationId> {
+        let ids = self
+            .allocations
+            .values()
+            .filter(|a| a.username == username)
+            .map(|a| a.id)
+            .collect::<Vec<_>>();
+
+        for id in &ids {
+            self.kill_allocation(*id, reason);
+        }
+
+        ids
+    }
+
+    /// Deletes all allocations, e.g. because we are about to shut down.
+    ///
+    /// Returns the IDs of the deleted allocations.
+    #[tracing::instrument(skip(self), level = "error")]
+    pub fn kill_all_allocations(&mut self, reason: DeletionReason) -> Vec<AllocationId> {
+        let mut ids = self
+            .clients_by_allocation
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.0);
+
+        for id in &ids {
+            self.kill_allocation(*id, reason);
+        }
+
+        ids
+    }
+
+    /// Starts recording the traffic relayed for the given allocation or username.
+    ///
+    /// The capture emits [`Event::CaptureData`] until it reached `max_bytes` or `duration` has passed.
+    /// Returns `None` if the allocation to capture doesn't exist or the capture would end later than [`SystemTime`] can represent.
+    #[tracing::instrument(skip(self, now), level = "error")]
+    pub fn start_capture(
+        &mut self,
+        filter: CaptureFilter,
+        max_bytes: u64,
+        duration: Duration,
+        now: SystemTime,
+    ) -> Option<CaptureId> {
+        if let CaptureFilter::Allocation(id) = filter {
+            self.clients_by_allocation.get(&id)?;
+        }
+
+        let expires_at = now.checked_add(duration)?;
+        let id = self.next_capture_id.next();
+        let header = pcapng::file_header();
+
+        tracing::info!(target: "relay", capture = %id, "Starting capture");
+
+        self.captures.insert(
+            id,
+            Capture {
+                filter,
+                max_bytes,
+                written_bytes: header.len() as u64,
+                expires_at,
+            },
+        );
+        self.pending_events
+            .push_back(Event::CaptureData { id, bytes: header });
+
+        let wake_deadline = self
+            .time_events
+            .add(expires_at, TimedAction::ExpireCapture(id));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        Some(id)
+    }
+
+    /// Stops the given capture.
+    ///
+    /// Returns `false` if there is no such capture in progress.
+    pub fn stop_capture(&mut self, id: CaptureId) -> bool {
+        self.finish_capture(id, CaptureEnd::Stopped)
+    }
+
+    /// Return the next command to be executed.
+    pub fn next_command(&mut self) -> Option<Command> {
+        let num_commands = self.pending_commands.len();
+
+        if num_commands > 0 {
+            tracing::trace!(%num_commands, "Popping next command");
+        }
+
+        self.pending_commands.pop_front()
+    }
+
+    /// Return the next event that happened.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.pending_events.pop_front()
+    }
+
+    /// Handle a STUN binding request, received on the given socket.
+    ///
+    /// With NAT behaviour discovery enabled, we tell UDP clients which of our addresses the response comes from and the address that differs from the one they sent the request to in both IP and port.
+    /// See <https://www.rfc-editor.org/rfc/rfc5780#section-7.3> for details.
+    fn handle_binding_request(
+        &mut self,
+        request: Binding,
+        sender: SocketAddr,
+        socket: DiscoverySocket,
+    ) {
+        let response_socket = match self.binding_response_socket(&request, sender, socket) {
+            Ok(response_socket) => response_socket,
+            Err(error_response) => {
+                self.send_message_from(error_response, sender, socket);
+                return;
+            }
+        };
+
+        let mut message = Message::new(
+            MessageClass::SuccessResponse,
+            BINDING,
+            request.transaction_id(),
+        );
+        message.add_attribute(XorMappedAddress::new(sender));
+
+        if let Some(nat_discovery) = self
+            .nat_discovery
+            .filter(|_| !self.stream_clients.contains(&sender))
+        {
+            let family = sender.family();
+
+            if let Some(origin) =
+                nat_discovery.address(&self.public_address, family, response_socket)
+            {
+                message.add_attribute(ResponseOrigin::new(origin));
+            }
+            if let Some(other) =
+                nat_discovery.address(&self.public_address, family, socket.changed(true, true))
+            {
+                message.add_attribute(OtherAddress::new(other));
+            }
+        }
+
+        self.send_message_from(message, sender, response_socket);
+    }
+
+    /// The socket to send the response to a binding request from, as requested by its CHANGE-REQUEST attribute.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc5780#section-7.2>.
+    fn binding_response_socket(
+        &self,
+        request: &Binding,
+        sender: SocketAddr,
+        socket: DiscoverySocket,
+    ) -> Result<DiscoverySocket, Message<Attribute>> {
+        let Some(change_request) = request.change_request() else {
+            return Ok(socket);
+        };
+
+        // We can only respond from a different address over UDP.
+        if self.stream_clients.contains(&sender) {
+            return Err(error_response(BadRequest, request));
+        }
+
+        let can_change = self.nat_discovery.map_or(false, |nat_discovery| {
+            !change_request.ip() || nat_discovery.has_alternate_ip(sender.family())
+        });
+        if !can_change {
+            let mut response = error_response(UnknownAttribute, request);
+            response.add_attribute(UnknownAttributes::new(vec![
+                stun_codec::AttributeType::new(ChangeRequest::CODEPOINT),
+            ]));
+
+            return Err(response);
+        }
+
+        Ok(socket.changed(change_request.ip(), change_request.port()))
+    }
+
+    /// Handle a TURN allocate request.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-allocate-reque> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, effective_lifetime = ?request.effective_lifetime().lifetime()), level = "error")]
+    fn handle_allocate_request(
+        &mut self,
+        request: Allocate,
+        sender: SocketAddr,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        let username = self.verify_auth(&request, sender, now)?;
+
+        if self.allocations.contains_key(&sender) {
+            return Err(error_response(AllocationMismatch, &request));
+        }
+
+        if self.draining {
+            tracing::debug!(target: "relay", "Refusing new allocation because we are draining");
+
+            return Err(self.try_alternate_response(&request));
+        }
+
+        if exceeds(
+            self.num_allocations(|a| a.username == username),
+            self.user_limits.max_allocations,
+        ) || exceeds(
+            self.num_allocations(|a| a.source_ip == sender.ip()),
+            self.ip_limits.max_allocations,
+        ) {
+            tracing::debug!(target: "relay", %username, "Allocation quota reached");
+
+            return Err(error_response(AllocationQuotaReached, &request));
+        }
+
+        let at_capacity = !self.has_available_ports() || self.is_above_load_shedding_threshold();
+
+        // Reserved ports are already accounted for, claiming one doesn't need any capacity.
+        if at_capacity
+            && !self.alternate_servers.is_empty()
+            && request.reservation_token().is_none()
+        {
+            tracing::debug!(target: "relay", "Redirecting new allocation because we are at capacity");
+
+            return Err(self.try_alternate_response(&request));
+        }
+
+        if !self.has_available_ports() && request.reservation_token().is_none() {
+            return Err(error_response(InsufficientCapacity, &request));
+        }
+
+        let transport = match request.requested_transport().protocol() {
+            UDP_TRANSPORT => Transport::Udp,
+            // TCP allocations are only available to clients connected via TCP or TLS, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.1>.
+            TCP_TRANSPORT if self.stream_clients.contains(&sender) => Transport::Tcp,
+            TCP_TRANSPORT => return Err(error_response(BadRequest, &request)),
+            _ => return Err(error_response(UnsupportedTransportProtocol, &request)),
+        };
+
+        let (first_relay_address, maybe_second_relay_addr) = derive_relay_addresses(
+            self.public_address,
+            request.requested_address_family(),
+            request.additional_address_family(),
+        )
+        .map_err(|e| error_response(e, &request))?;
+
+        // Allocations of clients connected via TCP or TLS are deleted together with the connection, thus cannot move.
+        if request.mobility_ticket().is_some() && self.stream_clients.contains(&sender) {
+            return Err(error_response(MobilityForbidden, &request));
+        }
+
+        let reserve_next_port = request.even_port().map_or(false, EvenPort::is_requested);
+
+        let port = match request.reservation_token() {
+            Some(token) => self.reservations.remove(&token.token()),
+            None => self.find_unused_port(request.even_port().is_some(), reserve_next_port),
+        }
+        .ok_or(error_response(InsufficientCapacity, &request))?;
+
+        let effective_lifetime = request.effective_lifetime();
+
+        let mut allocation = self.create_new_allocation(
+            now,
+            &effective_lifetime,
+            port,
+            first_relay_address,
+            maybe_second_relay_addr,
+            username,
+            sender.ip(),
+            transport,
+            request.dont_fragment(),
+        );
+
+        let mut message = Message::new(
+            MessageClass::SuccessResponse,
             ALLOCATE,
             request.transaction_id(),
         );
@@ -496,31 +1450,66 @@ where
         self.pending_commands.push_back(Command::Wake {
             deadline: wake_deadline,
         });
-        self.pending_commands.push_back(Command::CreateAllocation {
-            id: allocation.id,
-            family: first_relay_address.family(),
-            port,
-        });
-        if let Some(second_relay_addr) = maybe_second_relay_addr {
-            self.pending_commands.push_back(Command::CreateAllocation {
-                id: allocation.id,
-                family: second_relay_addr.family(),
-                port,
+
+        if reserve_next_port {
+            let token = self.reserve_port(port + 1, now);
+
+            message.add_attribute(ReservationToken::new(token));
+        }
+
+        if request.mobility_ticket().is_some() {
+            let secret = self.rng.gen();
+
+            allocation.mobility_secret = Some(secret);
+            message.add_attribute(mobility_ticket(allocation.id, secret));
+        }
+
+        for family in [Some(first_relay_address), maybe_second_relay_addr]
+            .into_iter()
+            .flatten()
+            .map(|addr| addr.family())
+        {
+            self.pending_commands.push_back(match transport {
+                Transport::Udp => Command::CreateAllocation {
+                    id: allocation.id,
+                    family,
+                    port,
+                    dont_fragment: allocation.dont_fragment,
+                },
+                Transport::Tcp => Command::CreateTcpAllocation {
+                    id: allocation.id,
+                    family,
+                    port,
+                },
             });
         }
         self.send_message(message, sender);
 
+        self.pending_events.push_back(Event::AllocationCreated {
+            id: allocation.id,
+            client: sender,
+            username: allocation.username.clone(),
+            relay_addresses: [Some(first_relay_address), maybe_second_relay_addr]
+                .into_iter()
+                .flatten()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect(),
+            lifetime: effective_lifetime.lifetime(),
+        });
+
         if let Some(second_relay_addr) = maybe_second_relay_addr {
             tracing::info!(
                 target: "relay",
                 first_relay_address = field::display(first_relay_address),
                 second_relay_address = field::display(second_relay_addr),
+                ?transport,
                 "Created new allocation",
             )
         } else {
             tracing::info!(
                 target: "relay",
                 first_relay_address = field::display(first_relay_address),
+                ?transport,
                 "Created new allocation",
             )
         }
@@ -542,7 +1531,11 @@ where
         sender: SocketAddr,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        let username = self.verify_auth(&request, sender, now)?;
+
+        if let Some(ticket) = request.mobility_ticket() {
+            self.move_allocation(ticket, &request, &username, sender)?;
+        }
 
         // TODO: Verify that this is the correct error code.
         let allocation = self
@@ -555,7 +1548,7 @@ where
         if effective_lifetime.lifetime().is_zero() {
             let id = allocation.id;
 
-            self.delete_allocation(id);
+            self.delete_allocation(id, DeletionReason::ClientDeleted);
             self.send_message(
                 refresh_success_response(effective_lifetime, request.transaction_id()),
                 sender,
@@ -572,6 +1565,23 @@ where
             "Refreshed allocation",
         );
 
+        self.pending_events.push_back(Event::AllocationRefreshed {
+            id: allocation.id,
+            client: sender,
+            username: allocation.username.clone(),
+            lifetime: effective_lifetime.lifetime(),
+        });
+
+        let mut message = refresh_success_response(effective_lifetime, request.transaction_id());
+
+        // Each refresh hands out a new ticket and thereby invalidates the previous one, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.2>.
+        if request.mobility_ticket().is_some() {
+            let secret = self.rng.gen();
+
+            allocation.mobility_secret = Some(secret);
+            message.add_attribute(mobility_ticket(allocation.id, secret));
+        }
+
         let wake_deadline = self.time_events.add(
             allocation.expires_at,
             TimedAction::ExpireAllocation(allocation.id),
@@ -579,10 +1589,89 @@ where
         self.pending_commands.push_back(Command::Wake {
             deadline: wake_deadline,
         });
-        self.send_message(
-            refresh_success_response(effective_lifetime, request.transaction_id()),
-            sender,
-        );
+        self.send_message(message, sender);
+
+        Ok(())
+    }
+
+    /// Moves the allocation identified by the given [`MobilityTicket`] to `sender`, unless it is already there.
+    ///
+    /// Channels, permissions and the relayed port all stay with the allocation.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.2>.
+    fn move_allocation(
+        &mut self,
+        ticket: &MobilityTicket,
+        request: &Refresh,
+        username: &str,
+        sender: SocketAddr,
+    ) -> Result<(), Message<Attribute>> {
+        if self.stream_clients.contains(&sender) {
+            return Err(error_response(MobilityForbidden, request));
+        }
+
+        let (id, secret) =
+            parse_mobility_ticket(ticket).ok_or(error_response(BadRequest, request))?;
+        let client = *self
+            .clients_by_allocation
+            .get(&id)
+            .ok_or(error_response(AllocationMismatch, request))?;
+        let allocation = self
+            .allocations
+            .get(&client)
+            .expect("internal state mismatch");
+
+        match allocation.mobility_secret {
+            None => return Err(error_response(MobilityForbidden, request)),
+            Some(expected) if expected != secret => {
+                return Err(error_response(BadRequest, request))
+            }
+            Some(_) => {}
+        }
+
+        if allocation.username != username {
+            return Err(error_response(WrongCredentials, request));
+        }
+
+        if client == sender {
+            return Ok(());
+        }
+
+        if self.allocations.contains_key(&sender) {
+            return Err(error_response(AllocationMismatch, request));
+        }
+
+        let previous_ip = allocation.source_ip;
+
+        if previous_ip != sender.ip()
+            && exceeds(
+                self.num_allocations(|a| a.source_ip == sender.ip()),
+                self.ip_limits.max_allocations,
+            )
+        {
+            return Err(error_response(AllocationQuotaReached, request));
+        }
+
+        let mut allocation = self
+            .allocations
+            .remove(&client)
+            .expect("internal state mismatch");
+        allocation.source_ip = sender.ip();
+
+        self.pending_events.push_back(Event::AllocationMoved {
+            id,
+            username: allocation.username.clone(),
+            from: client,
+            to: sender,
+        });
+        self.allocations.insert(sender, allocation);
+        self.clients_by_allocation.insert(id, sender);
+
+        if self.num_allocations(|a| a.source_ip == previous_ip) == 0 {
+            self.bandwidth_by_ip.remove(&previous_ip);
+        }
+
+        tracing::info!(target: "relay", allocation = %id, from = %client, to = %sender, "Moved allocation");
 
         Ok(())
     }
@@ -597,14 +1686,20 @@ where
         sender: SocketAddr,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        self.verify_auth(&request, sender, now)?;
 
         let allocation = self
             .allocations
-            .get_mut(&sender)
+            .get(&sender)
             .ok_or(error_response(AllocationMismatch, &request))?;
+        let allocation_id = allocation.id;
 
-        Span::current().record("allocation", allocation.id.to_string());
+        Span::current().record("allocation", allocation_id.to_string());
+
+        // TCP allocations don't relay data through channels, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.6>.
+        if allocation.transport != Transport::Udp {
+            return Err(error_response(BadRequest, &request));
+        }
 
         // Note: `channel_number` is enforced to be in the correct range.
         let requested_channel = request.channel_number().value();
@@ -615,15 +1710,32 @@ where
             return Err(error_response(PeerAddressFamilyMismatch, &request));
         }
 
+        if !self.is_allowed_peer(peer_address.ip()) {
+            tracing::debug!(target: "relay", "Peer address is not allowed");
+
+            return Err(error_response(Forbidden, &request));
+        }
+
+        let allocation = self
+            .allocations
+            .get_mut(&sender)
+            .expect("allocation to exist because we just looked it up");
+
         // Ensure the same address isn't already bound to a different channel.
-        if let Some(number) = self.channel_numbers_by_peer.get(&peer_address) {
+        if let Some(number) = self
+            .channel_numbers_by_peer
+            .get(&(allocation_id, peer_address))
+        {
             if number != &requested_channel {
                 return Err(error_response(BadRequest, &request));
             }
         }
 
         // Ensure the channel is not already bound to a different address.
-        if let Some(channel) = self.channels_by_number.get_mut(&requested_channel) {
+        if let Some(channel) = self
+            .channels_by_allocation
+            .get_mut(&(allocation_id, requested_channel))
+        {
             if channel.peer_address != peer_address {
                 return Err(error_response(BadRequest, &request));
             }
@@ -631,12 +1743,13 @@ where
             // Binding requests for existing channels act as a refresh for the binding.
 
             channel.refresh(now);
+            allocation.add_permission(peer_address.ip(), now);
 
             tracing::info!(target: "relay", "Refreshed channel binding");
 
             self.time_events.add(
                 channel.expiry,
-                TimedAction::UnbindChannel(requested_channel),
+                TimedAction::UnbindChannel(allocation_id, requested_channel),
             );
             self.send_message(
                 channel_bind_success_response(request.transaction_id()),
@@ -649,14 +1762,40 @@ where
         // Channel binding does not exist yet, create it.
 
         // TODO: Any additional validations would go here.
-        // TODO: Capacity checking would go here.
 
-        let allocation_id = allocation.id;
+        let username = allocation.username.clone();
+
+        // There is no dedicated error code for channel quotas, thus we use 508 as recommended for all kinds of resource exhaustion.
+        if exceeds(
+            self.num_channel_bindings(|a| a.username == username),
+            self.user_limits.max_channel_bindings,
+        ) || exceeds(
+            self.num_channel_bindings(|a| a.source_ip == sender.ip()),
+            self.ip_limits.max_channel_bindings,
+        ) {
+            tracing::debug!(target: "relay", %username, "Channel binding quota reached");
+
+            return Err(error_response(InsufficientCapacity, &request));
+        }
+
+        let allocation = self
+            .allocations
+            .get_mut(&sender)
+            .expect("allocation to exist because we just looked it up");
+        allocation.add_permission(peer_address.ip(), now);
+
         self.create_channel_binding(requested_channel, peer_address, allocation_id, now);
         self.send_message(
             channel_bind_success_response(request.transaction_id()),
             sender,
         );
+        self.pending_events.push_back(Event::ChannelBound {
+            id: allocation_id,
+            client: sender,
+            username,
+            number: requested_channel,
+            peer: peer_address,
+        });
 
         tracing::info!(target: "relay", "Successfully bound channel");
 
@@ -666,43 +1805,262 @@ where
     /// Handle a TURN create permission request.
     ///
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-createpermissio> for details.
-    ///
-    /// This TURN server implementation does not support relaying data other than through channels.
-    /// Thus, creating a permission is a no-op that always succeeds.
-    #[tracing::instrument(skip(self, message, now), fields(%sender), level = "error")]
+    #[tracing::instrument(skip(self, request, now), fields(%sender, allocation), level = "error")]
     fn handle_create_permission_request(
         &mut self,
-        message: CreatePermission,
+        request: CreatePermission,
         sender: SocketAddr,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&message, now)?;
+        self.verify_auth(&request, sender, now)?;
+
+        let allocation = self
+            .allocations
+            .get(&sender)
+            .ok_or(error_response(AllocationMismatch, &request))?;
+
+        Span::current().record("allocation", allocation.id.to_string());
+
+        // Either all permissions are installed or none.
+        if request
+            .xor_peer_addresses()
+            .iter()
+            .any(|peer| !allocation.can_relay_to(peer.address()))
+        {
+            return Err(error_response(PeerAddressFamilyMismatch, &request));
+        }
+
+        if let Some(peer) = request
+            .xor_peer_addresses()
+            .iter()
+            .find(|peer| !self.is_allowed_peer(peer.address().ip()))
+        {
+            tracing::debug!(target: "relay", peer = %peer.address().ip(), "Peer address is not allowed");
+
+            return Err(error_response(Forbidden, &request));
+        }
+
+        let allocation = self
+            .allocations
+            .get_mut(&sender)
+            .expect("allocation to exist because we just looked it up");
+
+        for peer in request.xor_peer_addresses() {
+            allocation.add_permission(peer.address().ip(), now);
+
+            tracing::info!(target: "relay", peer = %peer.address().ip(), "Installed permission");
+        }
 
         self.send_message(
-            create_permission_success_response(message.transaction_id()),
+            create_permission_success_response(request.transaction_id()),
             sender,
         );
 
         Ok(())
     }
 
+    /// Handle a CONNECT request.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.2> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, peer = %request.xor_peer_address().address(), allocation), level = "error")]
+    fn handle_connect_request(
+        &mut self,
+        request: Connect,
+        sender: SocketAddr,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        self.verify_auth(&request, sender, now)?;
+
+        let allocation = self
+            .allocations
+            .get(&sender)
+            .ok_or(error_response(AllocationMismatch, &request))?;
+        let allocation_id = allocation.id;
+
+        Span::current().record("allocation", allocation_id.to_string());
+
+        if allocation.transport != Transport::Tcp {
+            return Err(error_response(BadRequest, &request));
+        }
+
+        let peer = request.xor_peer_address().address();
+
+        if !allocation.can_relay_to(peer) {
+            return Err(error_response(PeerAddressFamilyMismatch, &request));
+        }
+
+        if !self.is_allowed_peer(peer.ip()) {
+            tracing::debug!(target: "relay", "Peer address is not allowed");
+
+            return Err(error_response(Forbidden, &request));
+        }
+
+        if self
+            .connections
+            .values()
+            .any(|c| c.allocation == allocation_id && c.peer == peer)
+        {
+            return Err(error_response(ConnectionAlreadyExists, &request));
+        }
+
+        let connection = self.next_connection_id.next();
+        self.connections.insert(
+            connection,
+            Connection {
+                allocation: allocation_id,
+                peer,
+                state: ConnectionState::Connecting {
+                    transaction_id: request.transaction_id(),
+                },
+            },
+        );
+
+        tracing::info!(target: "relay", %connection, "Connecting to peer");
+
+        self.pending_commands.push_back(Command::ConnectToPeer {
+            id: allocation_id,
+            connection,
+            peer,
+        });
+
+        Ok(())
+    }
+
+    /// Handle a CONNECTION-BIND request.
+    ///
+    /// The request arrives on a new connection of the client which, once bound, only carries the data of the peer connection.
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.4> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, connection = %request.connection_id()), level = "error")]
+    fn handle_connection_bind_request(
+        &mut self,
+        request: ConnectionBind,
+        sender: SocketAddr,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        if !self.stream_clients.contains(&sender) || self.allocations.contains_key(&sender) {
+            return Err(error_response(BadRequest, &request));
+        }
+
+        let username = self.verify_auth(&request, sender, now)?;
+
+        let connection = request.connection_id();
+
+        let allocation = self
+            .connections
+            .get(&connection)
+            .filter(|c| c.state == ConnectionState::Pending)
+            .and_then(|c| self.get_allocation(&c.allocation))
+            .ok_or(error_response(BadRequest, &request))?;
+
+        if allocation.username != username {
+            return Err(error_response(WrongCredentials, &request));
+        }
+
+        self.connections
+            .get_mut(&connection)
+            .expect("connection to exist because we just looked it up")
+            .state = ConnectionState::Bound { client: sender };
+
+        let Some(response) = self.encode_message(Message::new(
+            MessageClass::SuccessResponse,
+            rfc6062::method(CONNECTION_BIND),
+            request.transaction_id(),
+        )) else {
+            return Ok(());
+        };
+
+        tracing::info!(target: "relay", "Bound peer connection");
+
+        self.pending_commands.push_back(Command::SpliceConnection {
+            connection,
+            client: sender,
+            response,
+        });
+
+        Ok(())
+    }
+
+    /// Handle a TURN send indication.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-send-indication> for details.
+    #[tracing::instrument(skip(self, indication, now), fields(%sender, recipient = %indication.xor_peer_address().address()), level = "error")]
+    fn handle_send_indication(
+        &mut self,
+        indication: SendIndication,
+        sender: SocketAddr,
+        now: SystemTime,
+    ) {
+        let Some(allocation) = self.allocations.get(&sender) else {
+            tracing::debug!(target: "relay", "No allocation, refusing to forward data");
+            return;
+        };
+
+        if allocation.transport != Transport::Udp {
+            tracing::debug!(target: "relay", "SEND indications are not supported on TCP allocations");
+            return;
+        }
+
+        let recipient = indication.xor_peer_address().address();
+
+        if !allocation.has_permission(recipient.ip(), now) {
+            tracing::debug!(target: "relay", "No permission, refusing to forward data");
+            return;
+        }
+
+        let allocation_id = allocation.id;
+        let data = indication.data();
+
+        if !self.consume_bandwidth(sender, data.len(), now) {
+            tracing::debug!(target: "relay", "Bandwidth quota exceeded, dropping {} bytes", data.len());
+            return;
+        }
+
+        tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
+
+        self.capture(
+            sender,
+            recipient,
+            Direction::ToPeer,
+            || Some(indication.to_bytes()),
+            data,
+            now,
+        );
+        self.record_traffic(sender, None, Direction::ToPeer, data.len());
+
+        self.pending_commands.push_back(Command::ForwardData {
+            id: allocation_id,
+            data: data.to_vec(),
+            receiver: recipient,
+        });
+    }
+
+    /// Relay channel data from a client to the peer bound to the channel.
+    ///
+    /// Channels are scoped to an allocation, thus the data is only relayed if the sender owns an allocation with the given channel bound.
     #[tracing::instrument(skip(self, message), fields(allocation_id, %sender, channel = %message.channel(), recipient), level = "error")]
     fn handle_channel_data_message(
         &mut self,
         message: ChannelData,
         sender: SocketAddr,
-        _: SystemTime,
+        now: SystemTime,
     ) {
         let channel_number = message.channel();
         let data = message.data();
 
-        let Some(channel) = self.channels_by_number.get(&channel_number) else {
-            tracing::debug!(target: "relay", "Channel does not exist, refusing to forward data");
+        let Some(allocation) = self.allocations.get(&sender) else {
+            tracing::debug!(target: "relay", "No allocation, refusing to forward data");
             return;
         };
 
-        // TODO: Do we need to enforce that only the creator of the channel can relay data?
-        // The sender of a UDP packet can be spoofed, so why would we bother?
+        Span::current().record("allocation_id", field::display(&allocation.id));
+
+        let Some(channel) = self
+            .channels_by_allocation
+            .get(&(allocation.id, channel_number))
+        else {
+            tracing::debug!(target: "relay", "Channel does not exist, refusing to forward data");
+            return;
+        };
 
         if !channel.bound {
             tracing::debug!(target: "relay", "Channel exists but is unbound");
@@ -710,78 +2068,178 @@ where
         }
 
         let recipient = channel.peer_address;
+        let allocation_id = channel.allocation;
         Span::current().record("recipient", field::display(&recipient));
 
-        tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
+        if !self.consume_bandwidth(sender, data.len(), now) {
+            tracing::debug!(target: "relay", "Bandwidth quota exceeded, dropping {} bytes", data.len());
+            return;
+        }
 
-        self.data_relayed_counter.add(data.len() as u64, &[]);
+        tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(data);
             tracing::trace!(target: "wire", %hex_bytes, "sending bytes");
         }
 
+        self.capture(
+            sender,
+            recipient,
+            Direction::ToPeer,
+            || Some(ChannelData::new(channel_number, data).to_bytes()),
+            data,
+            now,
+        );
+        self.record_traffic(sender, Some(channel_number), Direction::ToPeer, data.len());
         self.pending_commands.push_back(Command::ForwardData {
-            id: channel.allocation,
+            id: allocation_id,
             data: data.to_vec(),
             receiver: recipient,
         });
     }
 
-    fn verify_auth(
-        &mut self,
+    /// Verifies the credentials of a request, returning the identity of the client.
+    ///
+    /// Quotas and the ownership of allocations are tied to this identity.
+    fn verify_auth(
+        &mut self,
+        request: &(impl StunRequest + ProtectedRequest),
+        sender: SocketAddr,
+        now: SystemTime,
+    ) -> Result<String, Message<Attribute>> {
+        if request.message_integrity().is_none() && request.message_integrity_sha256().is_none() {
+            self.record_auth_failure("missing_message_integrity");
+            return Err(error_response(Unauthorized, request));
+        }
+        let username = request.username().map_err(|e| {
+            self.record_auth_failure("missing_username");
+            error_response(e, request)
+        })?;
+        let nonce = request.nonce().map_err(|e| {
+            self.record_auth_failure("missing_nonce");
+            error_response(e, request)
+        })?;
+
+        self.nonces
+            .verify(nonce.value(), sender, now)
+            .map_err(|e| {
+                self.record_auth_failure(e.reason());
+                error_response(StaleNonce, request)
+            })?;
+
+        if let Some(access_token) = request.access_token() {
+            return self.verify_access_token(request, access_token, username, now);
+        }
+
+        let password_algorithm = negotiate_password_algorithm(request).map_err(|e| {
+            self.record_auth_failure("password_algorithm_mismatch");
+            error_response(e, request)
+        })?;
+
+        // Prefer `MESSAGE-INTEGRITY-SHA256` if a client sends both, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.4>.
+        let message_integrity = match (
+            request.message_integrity_sha256(),
+            request.message_integrity(),
+        ) {
+            (Some(message_integrity), _) => {
+                Integrity::Sha256(message_integrity, password_algorithm)
+            }
+            (None, Some(message_integrity)) if password_algorithm == PasswordAlgorithm::Md5 => {
+                Integrity::Sha1(message_integrity)
+            }
+            // `stun_codec` can only verify `MESSAGE-INTEGRITY` with keys derived via MD5.
+            (None, _) => {
+                self.record_auth_failure("unsupported_password_algorithm");
+                return Err(error_response(BadRequest, request));
+            }
+        };
+
+        self.auth_secrets
+            .verify(&message_integrity, username.name(), now)
+            .map_err(|e| {
+                self.record_auth_failure(e.reason());
+                error_response(Unauthorized, request)
+            })?;
+
+        Ok(username.name().to_owned())
+    }
+
+    /// Verifies the message integrity of a request with the key from its access token, see <https://www.rfc-editor.org/rfc/rfc7635#section-5>.
+    ///
+    /// The username of such requests identifies the key the access token is encrypted with.
+    /// Each access token identifies its own session, which becomes the identity of the client.
+    fn verify_access_token(
+        &self,
         request: &(impl StunRequest + ProtectedRequest),
+        access_token: &AccessToken,
+        username: &Username,
         now: SystemTime,
-    ) -> Result<(), Message<Attribute>> {
-        let message_integrity = request
-            .message_integrity()
-            .map_err(|e| error_response(e, request))?;
-        let username = request.username().map_err(|e| error_response(e, request))?;
-        let nonce = request
-            .nonce()
-            .map_err(|e| error_response(e, request))?
-            .value()
-            .parse::<Uuid>()
-            .map_err(|e| {
-                log::debug!("failed to parse nonce: {e}");
+    ) -> Result<String, Message<Attribute>> {
+        let Some(keys) = &self.access_token_keys else {
+            self.record_auth_failure("access_tokens_disabled");
+            return Err(error_response(Unauthorized, request));
+        };
 
+        let OpenedAccessToken { mac_key, session } = keys
+            .open(username.name(), access_token.token(), now)
+            .map_err(|e| {
+                self.record_auth_failure(e.reason());
                 error_response(Unauthorized, request)
             })?;
 
-        self.nonces
-            .handle_nonce_used(nonce)
-            .map_err(|_| error_response(StaleNonce, request))?;
+        // Prefer `MESSAGE-INTEGRITY-SHA256` if a client sends both, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.4>.
+        let is_valid = match (
+            request.message_integrity_sha256(),
+            request.message_integrity(),
+        ) {
+            (Some(message_integrity), _) => message_integrity.check_key(&mac_key).is_ok(),
+            (None, Some(message_integrity)) => {
+                access_token.check_message_integrity(message_integrity, &mac_key)
+            }
+            (None, None) => false,
+        };
 
-        message_integrity
-            .verify(&self.auth_secret, username.name(), now)
-            .map_err(|_| error_response(Unauthorized, request))?;
+        if !is_valid {
+            self.record_auth_failure("invalid_message_integrity");
+            return Err(error_response(Unauthorized, request));
+        }
 
-        Ok(())
+        Ok(session)
+    }
+
+    /// Whether clients may relay data to the given peer.
+    ///
+    /// Relaying to ourselves is never useful and would allow clients to reach services bound to our public or listen addresses.
+    fn is_allowed_peer(&self, peer: IpAddr) -> bool {
+        let is_own_address = self.public_address.as_v4().map(|ip| IpAddr::V4(*ip)) == Some(peer)
+            || self.public_address.as_v6().map(|ip| IpAddr::V6(*ip)) == Some(peer)
+            || self.listen_addrs.values().any(|addr| addr.ip() == peer)
+            || self.nat_discovery.as_ref().map_or(false, |nat_discovery| {
+                nat_discovery.alternate_ips().any(|ip| ip == peer)
+            });
+
+        !is_own_address && self.peer_filter.is_allowed(peer)
+    }
+
+    fn record_auth_failure(&self, reason: &'static str) {
+        self.auth_failures_counter
+            .add(1, &[KeyValue::new("reason", reason)]);
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn create_new_allocation(
         &mut self,
         now: SystemTime,
         lifetime: &Lifetime,
+        port: u16,
         first_relay_addr: IpAddr,
         second_relay_addr: Option<IpAddr>,
+        username: String,
+        source_ip: IpAddr,
+        transport: Transport,
+        dont_fragment: bool,
     ) -> Allocation {
-        // First, find an unused port.
-
-        assert!(
-            self.allocations_by_port.len() < self.max_available_ports() as usize,
-            "No more ports available; this would loop forever"
-        );
-
-        let port = loop {
-            let candidate = self.rng.gen_range(self.lowest_port..self.highest_port);
-
-            if !self.allocations_by_port.contains_key(&candidate) {
-                break candidate;
-            }
-        };
-
-        // Second, grab a new allocation ID.
         let id = self.next_allocation_id.next();
 
         self.allocations_by_port.insert(port, id);
@@ -792,13 +2250,181 @@ where
             expires_at: now + lifetime.lifetime(),
             first_relay_addr,
             second_relay_addr,
+            permissions: Default::default(),
+            username,
+            source_ip,
+            traffic: Traffic::default(),
+            transport,
+            dont_fragment,
+            mobility_secret: None,
+        }
+    }
+
+    /// Finds a port that is neither allocated nor reserved, starting the search at a random port.
+    ///
+    /// With `even`, only even ports are considered and with `reserve_next`, the next higher port must be unused as well.
+    fn find_unused_port(&mut self, even: bool, reserve_next: bool) -> Option<u16> {
+        let num_ports = u32::from(self.max_available_ports());
+        let start = u32::from(self.rng.gen_range(0..self.max_available_ports()));
+
+        (0..num_ports)
+            .map(|offset| self.lowest_port + ((start + offset) % num_ports) as u16)
+            .filter(|port| !even || port % 2 == 0)
+            .find(|port| {
+                self.is_port_unused(*port)
+                    && (!reserve_next
+                        || (*port + 1 < self.highest_port && self.is_port_unused(*port + 1)))
+            })
+    }
+
+    fn is_port_unused(&self, port: u16) -> bool {
+        !self.allocations_by_port.contains_key(&port)
+            && !self.reservations.values().any(|p| *p == port)
+    }
+
+    /// Reserves the given port for [`RESERVATION_DURATION`] and returns the token to claim it with.
+    fn reserve_port(&mut self, port: u16, now: SystemTime) -> u64 {
+        let token = loop {
+            let candidate = self.rng.gen::<u64>();
+
+            if !self.reservations.contains_key(&candidate) {
+                break candidate;
+            }
+        };
+
+        self.reservations.insert(token, port);
+
+        let wake_deadline = self.time_events.add(
+            now + RESERVATION_DURATION,
+            TimedAction::ExpireReservation(token),
+        );
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        tracing::debug!(target: "relay", %port, "Reserved port");
+
+        token
+    }
+
+    fn num_allocations(&self, filter: impl Fn(&Allocation) -> bool) -> usize {
+        self.allocations.values().filter(|a| filter(a)).count()
+    }
+
+    fn num_channel_bindings(&self, filter: impl Fn(&Allocation) -> bool) -> usize {
+        self.channels_by_allocation
+            .keys()
+            .filter(|(id, _)| self.get_allocation(id).map_or(false, &filter))
+            .count()
+    }
+
+    /// Consume `bytes` from the bandwidth quota of the given client's username and source IP.
+    ///
+    /// Returns `false` if either quota is exhausted, in which case the data must be dropped.
+    fn consume_bandwidth(&mut self, client: SocketAddr, bytes: usize, now: SystemTime) -> bool {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return false;
+        };
+
+        if self.user_limits.max_bytes_per_second.is_none()
+            && self.ip_limits.max_bytes_per_second.is_none()
+        {
+            return true;
+        }
+
+        // This runs for every relayed packet, avoid allocating unless we see a username for the first time.
+        let user_bucket = match self.user_limits.max_bytes_per_second {
+            Some(rate) => {
+                if !self.bandwidth_by_user.contains_key(&allocation.username) {
+                    self.bandwidth_by_user
+                        .insert(allocation.username.clone(), TokenBucket::new(rate, now));
+                }
+
+                self.bandwidth_by_user.get_mut(&allocation.username)
+            }
+            None => None,
+        };
+        let ip_bucket = self.ip_limits.max_bytes_per_second.map(|rate| {
+            self.bandwidth_by_ip
+                .entry(allocation.source_ip)
+                .or_insert_with(|| TokenBucket::new(rate, now))
+        });
+        let mut buckets = [user_bucket, ip_bucket];
+
+        if !buckets
+            .iter_mut()
+            .flatten()
+            .all(|bucket| bucket.has_capacity(bytes, now))
+        {
+            return false;
+        }
+
+        for bucket in buckets.into_iter().flatten() {
+            bucket.consume(bytes);
+        }
+
+        true
+    }
+
+    /// Accounts for data relayed through the allocation of the given client and, if used, the channel.
+    fn record_traffic(
+        &mut self,
+        client: SocketAddr,
+        channel: Option<u16>,
+        direction: Direction,
+        bytes: usize,
+    ) {
+        self.data_relayed_counter.add(
+            bytes as u64,
+            &[KeyValue::new("direction", direction.as_str())],
+        );
+
+        let Some(allocation) = self.allocations.get_mut(&client) else {
+            return;
+        };
+        allocation.traffic.record(direction, bytes);
+
+        if let Some(channel) = channel.and_then(|number| {
+            self.channels_by_allocation
+                .get_mut(&(allocation.id, number))
+        }) {
+            channel.traffic.record(direction, bytes);
+        }
+    }
+
+    /// Redirect the client to a randomly picked alternate server or reject the request if we don't have any.
+    fn try_alternate_response(&mut self, request: &Allocate) -> Message<Attribute> {
+        if self.alternate_servers.is_empty() {
+            return error_response(InsufficientCapacity, request);
         }
+
+        let alternate_server =
+            self.alternate_servers[self.rng.gen_range(0..self.alternate_servers.len())];
+
+        let mut message = error_response(TryAlternate, request);
+        message.add_attribute(AlternateServer::new(alternate_server));
+
+        message
+    }
+
+    fn is_above_load_shedding_threshold(&self) -> bool {
+        let Some(threshold) = self.load_shedding_threshold else {
+            return false;
+        };
+
+        let load = self.num_used_ports() as f64 / self.max_available_ports() as f64;
+
+        load >= threshold
     }
 
     fn max_available_ports(&self) -> u16 {
         self.highest_port - self.lowest_port
     }
 
+    fn num_used_ports(&self) -> usize {
+        self.allocations_by_port.len() + self.reservations.len()
+    }
+
     fn create_channel_binding(
         &mut self,
         requested_channel: u16,
@@ -806,27 +2432,78 @@ where
         id: AllocationId,
         now: SystemTime,
     ) {
-        self.channels_by_number.insert(
-            requested_channel,
+        self.channels_by_allocation.insert(
+            (id, requested_channel),
             Channel {
                 expiry: now + CHANNEL_BINDING_DURATION,
                 peer_address,
                 allocation: id,
                 bound: true,
+                traffic: Traffic::default(),
             },
         );
         self.channel_numbers_by_peer
-            .insert(peer_address, requested_channel);
+            .insert((id, peer_address), requested_channel);
+        self.channel_bindings_up_down_counter.add(1, &[]);
     }
 
     fn send_message(&mut self, message: Message<Attribute>, recipient: SocketAddr) {
+        let Some(bytes) = self.encode_message(message) else {
+            return;
+        };
+
+        self.pending_commands.push_back(Command::SendMessage {
+            payload: bytes,
+            recipient,
+        });
+    }
+
+    fn send_message_from(
+        &mut self,
+        message: Message<Attribute>,
+        recipient: SocketAddr,
+        socket: DiscoverySocket,
+    ) {
+        if socket == DiscoverySocket::PRIMARY {
+            self.send_message(message, recipient);
+            return;
+        }
+
+        let Some(bytes) = self.encode_message(message) else {
+            return;
+        };
+
+        self.pending_commands.push_back(Command::SendMessageFrom {
+            payload: bytes,
+            recipient,
+            socket,
+        });
+    }
+
+    /// Encodes a message, adding the attributes required in all responses and recording metrics along the way.
+    fn encode_message(&mut self, mut message: Message<Attribute>) -> Option<Vec<u8>> {
+        if matches!(
+            message.class(),
+            MessageClass::SuccessResponse | MessageClass::ErrorResponse
+        ) {
+            message.add_attribute(SOFTWARE);
+
+            // The fingerprint covers all preceding attributes and must thus come last.
+            let Ok(fingerprint) = Fingerprint::new(&message) else {
+                debug_assert!(false, "Encoding should never fail");
+                return None;
+            };
+            message.add_attribute(fingerprint);
+        }
+
         let method = message.method();
         let class = message.class();
+        let error_code = message.get_attribute::<ErrorCode>().map(|e| e.code());
         tracing::trace!(target: "relay",  method = %message.method(), class = %message.class(), "Sending message");
 
         let Ok(bytes) = self.encoder.encode_into_bytes(message) else {
             debug_assert!(false, "Encoding should never fail");
-            return;
+            return None;
         };
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
@@ -834,16 +2511,11 @@ where
             tracing::trace!(target: "wire", %hex_bytes, "sending bytes");
         }
 
-        self.pending_commands.push_back(Command::SendMessage {
-            payload: bytes,
-            recipient,
-        });
-
         // record metrics
         let response_class = match class {
             MessageClass::SuccessResponse => "success",
             MessageClass::ErrorResponse => "error",
-            _ => return,
+            _ => return Some(bytes),
         };
         let message_type = match method {
             BINDING => "binding",
@@ -851,15 +2523,21 @@ where
             REFRESH => "refresh",
             CHANNEL_BIND => "channelbind",
             CREATE_PERMISSION => "createpermission",
-            _ => return,
+            method if method.as_u16() == CONNECT => "connect",
+            method if method.as_u16() == CONNECTION_BIND => "connectionbind",
+            _ => return Some(bytes),
         };
-        self.responses_counter.add(
-            1,
-            &[
-                KeyValue::new("response_class", response_class),
-                KeyValue::new("message_type", message_type),
-            ],
-        );
+        let mut attributes = vec![
+            KeyValue::new("response_class", response_class),
+            KeyValue::new("message_type", message_type),
+        ];
+        if let Some(error_code) = error_code {
+            attributes.push(KeyValue::new("error_code", i64::from(error_code)));
+        }
+
+        self.responses_counter.add(1, &attributes);
+
+        Some(bytes)
     }
 
     fn get_allocation(&self, id: &AllocationId) -> Option<&Allocation> {
@@ -868,7 +2546,15 @@ where
             .and_then(|client| self.allocations.get(client))
     }
 
-    fn delete_allocation(&mut self, id: AllocationId) {
+    /// The data relayed via each channel of the given allocation, ordered by channel number.
+    fn channel_usage(&self, id: AllocationId) -> Vec<ChannelUsage> {
+        channels_of(&self.channels_by_allocation, id)
+            .into_iter()
+            .map(|(number, channel)| channel.usage(number))
+            .collect()
+    }
+
+    fn delete_allocation(&mut self, id: AllocationId, reason: DeletionReason) {
         let Some(client) = self.clients_by_allocation.remove(&id) else {
             tracing::debug!("Unknown allocation");
 
@@ -883,6 +2569,50 @@ where
 
         self.allocations_by_port.remove(&port);
 
+        let mut captures = self
+            .captures
+            .iter()
+            .filter(|(_, capture)| capture.filter == CaptureFilter::Allocation(id))
+            .map(|(capture, _)| *capture)
+            .collect::<Vec<_>>();
+        captures.sort();
+        for capture in captures {
+            self.finish_capture(capture, CaptureEnd::AllocationDeleted);
+        }
+
+        if self.num_allocations(|a| a.username == allocation.username) == 0 {
+            self.bandwidth_by_user.remove(&allocation.username);
+        }
+        if self.num_allocations(|a| a.source_ip == allocation.source_ip) == 0 {
+            self.bandwidth_by_ip.remove(&allocation.source_ip);
+        }
+
+        let num_bound_channels = self
+            .channels_by_allocation
+            .iter()
+            .filter(|((allocation, _), channel)| *allocation == id && channel.bound)
+            .count();
+        self.channel_bindings_up_down_counter
+            .add(-(num_bound_channels as i64), &[]);
+
+        let channels = self.channel_usage(id);
+
+        self.channels_by_allocation
+            .retain(|(allocation, _), _| *allocation != id);
+        self.channel_numbers_by_peer
+            .retain(|(allocation, _), _| *allocation != id);
+
+        let mut connections = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.allocation == id)
+            .map(|(connection, _)| *connection)
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|c| c.value());
+        for connection in connections {
+            self.delete_connection(connection);
+        }
+
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
             id,
@@ -895,21 +2625,200 @@ where
             })
         }
 
-        tracing::info!(target: "relay", %port, "Deleted allocation");
+        let traffic = allocation.traffic;
+
+        tracing::info!(
+            target: "relay",
+            %port,
+            username = %allocation.username,
+            reason = reason.as_str(),
+            bytes_to_peer = traffic.bytes_to_peer,
+            packets_to_peer = traffic.packets_to_peer,
+            bytes_to_client = traffic.bytes_to_client,
+            packets_to_client = traffic.packets_to_client,
+            "Deleted allocation"
+        );
+
+        self.pending_events.push_back(Event::AllocationDeleted {
+            relay_addresses: allocation.relay_addresses(),
+            usage: AllocationUsage {
+                id,
+                client,
+                username: allocation.username,
+                traffic,
+                channels,
+            },
+            reason,
+        });
+    }
+
+    /// Records a datagram relayed for the allocation of `client` in all captures that match it.
+    ///
+    /// Each datagram is recorded twice: Once as the channel data or indication between the client and us, as encoded by `client_frame`, and once between the allocation and the peer.
+    /// Both legs are written as UDP, even for clients connected via TCP or TLS.
+    fn capture(
+        &mut self,
+        client: SocketAddr,
+        peer: SocketAddr,
+        direction: Direction,
+        client_frame: impl FnOnce() -> Option<Vec<u8>>,
+        payload: &[u8],
+        now: SystemTime,
+    ) {
+        if self.captures.is_empty() {
+            return;
+        }
+
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+
+        let mut captures = self
+            .captures
+            .iter()
+            .filter(|(_, capture)| capture.matches(allocation.id, &allocation.username))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if captures.is_empty() {
+            return;
+        }
+        captures.sort();
+
+        let relay_addr = [
+            Some(allocation.first_relay_addr),
+            allocation.second_relay_addr,
+        ]
+        .into_iter()
+        .flatten()
+        .find(|ip| ip.family() == peer.family())
+        .map(|ip| SocketAddr::new(ip, allocation.port));
+        let listen_addr = match client.family() {
+            AddressFamily::V4 => self.public_address.as_v4().map(|ip| IpAddr::V4(*ip)),
+            AddressFamily::V6 => self.public_address.as_v6().map(|ip| IpAddr::V6(*ip)),
+        }
+        .map(|ip| {
+            let port = self
+                .listen_addrs
+                .get(&client.family())
+                .map_or(DEFAULT_LISTEN_PORT, |addr| addr.port());
+
+            SocketAddr::new(ip, port)
+        });
+
+        let peer_leg = relay_addr.and_then(|relay_addr| match direction {
+            Direction::ToPeer => pcapng::enhanced_packet_block(relay_addr, peer, payload, now),
+            Direction::ToClient => pcapng::enhanced_packet_block(peer, relay_addr, payload, now),
+        });
+        let client_leg = listen_addr
+            .zip(client_frame())
+            .and_then(|(listen_addr, frame)| match direction {
+                Direction::ToPeer => {
+                    pcapng::enhanced_packet_block(client, listen_addr, &frame, now)
+                }
+                Direction::ToClient => {
+                    pcapng::enhanced_packet_block(listen_addr, client, &frame, now)
+                }
+            });
+
+        let bytes = match direction {
+            Direction::ToPeer => [client_leg, peer_leg],
+            Direction::ToClient => [peer_leg, client_leg],
+        }
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect::<Vec<_>>();
+        if bytes.is_empty() {
+            return;
+        }
+
+        for id in captures {
+            let capture = self
+                .captures
+                .get_mut(&id)
+                .expect("capture to exist because we just looked it up");
+
+            if capture.written_bytes + bytes.len() as u64 > capture.max_bytes {
+                self.finish_capture(id, CaptureEnd::MaxBytes);
+                continue;
+            }
+
+            capture.written_bytes += bytes.len() as u64;
+            self.pending_events.push_back(Event::CaptureData {
+                id,
+                bytes: bytes.clone(),
+            });
+        }
+    }
+
+    fn finish_capture(&mut self, id: CaptureId, reason: CaptureEnd) -> bool {
+        let Some(capture) = self.captures.remove(&id) else {
+            return false;
+        };
+
+        tracing::info!(target: "relay", capture = %id, ?reason, bytes = capture.written_bytes, "Finished capture");
+
+        self.pending_events
+            .push_back(Event::CaptureFinished { id, reason });
+
+        true
+    }
+
+    fn delete_connection(&mut self, connection: ConnectionId) {
+        if self.connections.remove(&connection).is_none() {
+            return;
+        }
+
+        tracing::info!(target: "relay", %connection, "Closing peer connection");
+
+        self.pending_commands
+            .push_back(Command::CloseConnection { connection });
+    }
+
+    fn expire_connection_at(&mut self, connection: ConnectionId, deadline: SystemTime) {
+        let wake_deadline = self
+            .time_events
+            .add(deadline, TimedAction::ExpireConnection(connection));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
     }
 
-    fn delete_channel_binding(&mut self, chan: u16) {
-        let Some(channel) = self.channels_by_number.get(&chan) else {
+    fn delete_channel_binding(&mut self, id: AllocationId, chan: u16) {
+        let Some(channel) = self.channels_by_allocation.remove(&(id, chan)) else {
             return;
         };
 
-        let addr = channel.peer_address;
+        if channel.bound {
+            self.channel_bindings_up_down_counter.add(-1, &[]);
+        }
 
-        self.channel_numbers_by_peer.remove(&addr);
-        self.channels_by_number.remove(&chan);
+        self.channel_numbers_by_peer
+            .remove(&(id, channel.peer_address));
     }
 }
 
+/// Encodes a [`MobilityTicket`] for the given allocation.
+///
+/// The secret prevents other clients of the same user from guessing tickets of each other's allocations.
+fn mobility_ticket(id: AllocationId, secret: u64) -> MobilityTicket {
+    let mut ticket = Vec::with_capacity(16);
+    ticket.extend_from_slice(&id.0.to_be_bytes());
+    ticket.extend_from_slice(&secret.to_be_bytes());
+
+    MobilityTicket::new(ticket).expect("ticket to be less than 65536 bytes")
+}
+
+fn parse_mobility_ticket(ticket: &MobilityTicket) -> Option<(AllocationId, u64)> {
+    let ticket = <[u8; 16]>::try_from(ticket.data()).ok()?;
+    let (id, secret) = ticket.split_at(8);
+
+    Some((
+        AllocationId(u64::from_be_bytes(id.try_into().ok()?)),
+        u64::from_be_bytes(secret.try_into().ok()?),
+    ))
+}
+
 fn refresh_success_response(
     effective_lifetime: Lifetime,
     transaction_id: TransactionId,
@@ -932,6 +2841,7 @@ fn create_permission_success_response(transaction_id: TransactionId) -> Message<
 }
 
 /// Represents an allocation of a client.
+#[derive(Clone, Serialize, Deserialize)]
 struct Allocation {
     id: AllocationId,
     /// Data arriving on this port will be forwarded to the client iff there is an active data channel.
@@ -940,8 +2850,62 @@ struct Allocation {
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
+
+    /// The peers that are allowed to send data to this allocation, indexed by their IP address.
+    ///
+    /// Each permission expires at the given time unless it is refreshed via a CreatePermission or ChannelBind request.
+    permissions: HashMap<IpAddr, SystemTime>,
+
+    /// The username this allocation was created with.
+    username: String,
+    /// The IP address of the client that created this allocation.
+    source_ip: IpAddr,
+
+    /// The data relayed through this allocation so far.
+    ///
+    /// Data relayed through the peer connections of TCP allocations is not accounted for.
+    #[serde(default)]
+    traffic: Traffic,
+
+    #[serde(default)]
+    transport: Transport,
+
+    /// Whether datagrams to peers must be sent with the DF bit set, see <https://www.rfc-editor.org/rfc/rfc8656#name-dont-fragment>.
+    #[serde(default)]
+    dont_fragment: bool,
+
+    /// The secret part of the latest [`MobilityTicket`] handed out for this allocation, if the client requested mobility.
+    #[serde(default)]
+    mobility_secret: Option<u64>,
+}
+
+/// The transport protocol between an allocation and its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+enum Transport {
+    #[default]
+    Udp,
+    /// See <https://www.rfc-editor.org/rfc/rfc6062>.
+    Tcp,
+}
+
+/// A TCP connection between a TCP allocation and a peer.
+struct Connection {
+    allocation: AllocationId,
+    peer: SocketAddr,
+    state: ConnectionState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    /// We are connecting to the peer on behalf of the client's CONNECT request.
+    Connecting { transaction_id: TransactionId },
+    /// The connection to the peer is established and waits for the client to bind it.
+    Pending,
+    /// The connection is spliced with the given data connection of the client.
+    Bound { client: SocketAddr },
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct Channel {
     /// When the channel expires.
     expiry: SystemTime,
@@ -962,9 +2926,21 @@ struct Channel {
     ///
     /// With the data structure still existing while the channel is unbound, our existing validations cover the above requirement.
     bound: bool,
+
+    /// The data relayed through this channel so far.
+    #[serde(default)]
+    traffic: Traffic,
 }
 
 impl Channel {
+    fn usage(&self, number: u16) -> ChannelUsage {
+        ChannelUsage {
+            number,
+            peer: self.peer_address,
+            traffic: self.traffic,
+        }
+    }
+
     fn refresh(&mut self, now: SystemTime) {
         self.expiry = now + CHANNEL_BINDING_DURATION;
     }
@@ -985,13 +2961,59 @@ impl Allocation {
     fn is_expired(&self, now: SystemTime) -> bool {
         self.expires_at <= now
     }
+
+    /// The addresses on which we relay data for this allocation.
+    fn relay_addresses(&self) -> Vec<SocketAddr> {
+        [Some(self.first_relay_addr), self.second_relay_addr]
+            .into_iter()
+            .flatten()
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .collect()
+    }
+
+    /// Installs or refreshes a permission for the given peer.
+    ///
+    /// Expired permissions are purged along the way to keep the table bounded.
+    fn add_permission(&mut self, peer: IpAddr, now: SystemTime) {
+        self.permissions.retain(|_, expiry| *expiry > now);
+        self.permissions.insert(peer, now + PERMISSION_LIFETIME);
+    }
+
+    fn has_permission(&self, peer: IpAddr, now: SystemTime) -> bool {
+        self.permissions
+            .get(&peer)
+            .map_or(false, |expiry| *expiry > now)
+    }
 }
 
 #[derive(PartialEq)]
 enum TimedAction {
     ExpireAllocation(AllocationId),
-    UnbindChannel(u16),
-    DeleteChannel(u16),
+    UnbindChannel(AllocationId, u16),
+    DeleteChannel(AllocationId, u16),
+    ExpireConnection(ConnectionId),
+    ExpireReservation(u64),
+    ExpireCapture(CaptureId),
+}
+
+/// Whether another resource would exceed the given limit, given the current `usage`.
+/// The channels of the given allocation, ordered by their number.
+fn channels_of<'a>(
+    channels: impl IntoIterator<Item = (&'a (AllocationId, u16), &'a Channel)>,
+    id: AllocationId,
+) -> Vec<(u16, &'a Channel)> {
+    let mut channels = channels
+        .into_iter()
+        .filter(|((allocation, _), _)| *allocation == id)
+        .map(|((_, number), channel)| (*number, channel))
+        .collect::<Vec<_>>();
+    channels.sort_by_key(|(number, _)| *number);
+
+    channels
+}
+
+fn exceeds(usage: usize, limit: Option<usize>) -> bool {
+    limit.map_or(false, |limit| usage >= limit)
 }
 
 fn error_response(
@@ -1008,6 +3030,30 @@ fn error_response(
     message
 }
 
+/// The password algorithms we support, in order of preference.
+fn supported_password_algorithms() -> PasswordAlgorithms {
+    PasswordAlgorithms::new(vec![PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5])
+}
+
+/// Negotiate the password algorithm for a request as per <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.4>.
+///
+/// Clients that don't know about password algorithms send neither attribute and use MD5.
+/// All others must echo the algorithms we offered, which protects against downgrade attacks.
+fn negotiate_password_algorithm(
+    request: &impl ProtectedRequest,
+) -> Result<PasswordAlgorithm, BadRequest> {
+    match (request.password_algorithms(), request.password_algorithm()) {
+        (None, None) => Ok(PasswordAlgorithm::Md5),
+        (Some(algorithms), Some(algorithm))
+            if algorithms == &supported_password_algorithms()
+                && algorithms.algorithms().contains(&algorithm) =>
+        {
+            Ok(algorithm)
+        }
+        _ => Err(BadRequest),
+    }
+}
+
 /// Derive the relay address for the client based on the request and the supported IP stack of the relay server.
 ///
 /// By default, a client gets an IPv4 address.
@@ -1065,14 +3111,21 @@ macro_rules! impl_stun_request_for {
     };
 }
 
+impl_stun_request_for!(Binding, BINDING);
 impl_stun_request_for!(Allocate, ALLOCATE);
 impl_stun_request_for!(ChannelBind, CHANNEL_BIND);
 impl_stun_request_for!(CreatePermission, CREATE_PERMISSION);
 impl_stun_request_for!(Refresh, REFRESH);
+impl_stun_request_for!(Connect, rfc6062::method(CONNECT));
+impl_stun_request_for!(ConnectionBind, rfc6062::method(CONNECTION_BIND));
 
 /// Private helper trait to make [`Server::verify_auth`] more ergonomic to use.
 trait ProtectedRequest {
-    fn message_integrity(&self) -> Result<&MessageIntegrity, Unauthorized>;
+    fn message_integrity(&self) -> Option<&MessageIntegrity>;
+    fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256>;
+    fn access_token(&self) -> Option<&AccessToken>;
+    fn password_algorithms(&self) -> Option<&PasswordAlgorithms>;
+    fn password_algorithm(&self) -> Option<PasswordAlgorithm>;
     fn username(&self) -> Result<&Username, Unauthorized>;
     fn nonce(&self) -> Result<&Nonce, Unauthorized>;
 }
@@ -1080,8 +3133,24 @@ trait ProtectedRequest {
 macro_rules! impl_protected_request_for {
     ($t:ty) => {
         impl ProtectedRequest for $t {
-            fn message_integrity(&self) -> Result<&MessageIntegrity, Unauthorized> {
-                self.message_integrity().ok_or(Unauthorized)
+            fn message_integrity(&self) -> Option<&MessageIntegrity> {
+                self.message_integrity()
+            }
+
+            fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+                self.message_integrity_sha256()
+            }
+
+            fn access_token(&self) -> Option<&AccessToken> {
+                self.access_token()
+            }
+
+            fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+                self.password_algorithms()
+            }
+
+            fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+                self.password_algorithm()
             }
 
             fn username(&self) -> Result<&Username, Unauthorized> {
@@ -1099,6 +3168,8 @@ impl_protected_request_for!(Allocate);
 impl_protected_request_for!(ChannelBind);
 impl_protected_request_for!(CreatePermission);
 impl_protected_request_for!(Refresh);
+impl_protected_request_for!(Connect);
+impl_protected_request_for!(ConnectionBind);
 
 // Define an enum of all attributes that we care about for our server.
 stun_codec::define_attribute_enums!(
@@ -1118,7 +3189,25 @@ stun_codec::define_attribute_enums!(
         Realm,
         Username,
         RequestedAddressFamily,
-        AdditionalAddressFamily
+        AdditionalAddressFamily,
+        Data,
+        AlternateServer,
+        Software,
+        Fingerprint,
+        MessageIntegritySha256,
+        PasswordAlgorithms,
+        PasswordAlgorithm,
+        ConnectionId,
+        EvenPort,
+        DontFragment,
+        ReservationToken,
+        MobilityTicket,
+        ChangeRequest,
+        ResponseOrigin,
+        OtherAddress,
+        UnknownAttributes,
+        AccessToken,
+        ThirdPartyAuthorization
     ]
 );
 
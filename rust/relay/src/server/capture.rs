@@ -0,0 +1,78 @@
+use crate::AllocationId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Identifies a capture started via [`Server::start_capture`](crate::Server::start_capture).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CaptureId(u64);
+
+impl CaptureId {
+    pub(crate) fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn next(&mut self) -> Self {
+        let id = *self;
+
+        self.0 += 1;
+
+        id
+    }
+}
+
+impl fmt::Display for CaptureId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CAP-{}", self.0)
+    }
+}
+
+/// Which relayed traffic a capture records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureFilter {
+    /// The traffic of a single allocation, until it is deleted.
+    Allocation(AllocationId),
+    /// The traffic of all allocations of a username, including ones created after the capture started.
+    Username(String),
+}
+
+/// The smallest `max_bytes` of a capture, as every capture file starts with a header of this size.
+pub const MIN_CAPTURE_BYTES: u64 = super::pcapng::FILE_HEADER_LEN as u64;
+
+/// The longest `duration` of a capture.
+pub const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Why a capture ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureEnd {
+    /// The capture reached its maximum size.
+    MaxBytes,
+    /// The capture reached its maximum duration.
+    Duration,
+    /// The captured allocation was deleted.
+    AllocationDeleted,
+    /// The capture was stopped via [`Server::stop_capture`](crate::Server::stop_capture).
+    Stopped,
+}
+
+/// A capture in progress.
+pub(crate) struct Capture {
+    pub(crate) filter: CaptureFilter,
+    /// The maximum number of bytes to write, including the file header.
+    pub(crate) max_bytes: u64,
+    pub(crate) written_bytes: u64,
+    pub(crate) expires_at: SystemTime,
+}
+
+impl Capture {
+    pub(crate) fn matches(&self, allocation: AllocationId, username: &str) -> bool {
+        match &self.filter {
+            CaptureFilter::Allocation(id) => *id == allocation,
+            CaptureFilter::Username(name) => name == username,
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use crate::auth::{generate_password, split_username, systemtime_from_unix, FIREZONE};
 use crate::server::channel_data::ChannelData;
-use crate::server::UDP_TRANSPORT;
+use crate::server::rfc6062::{self, ConnectionId, CONNECT, CONNECTION_BIND};
+use crate::server::rfc7635::AccessToken;
+use crate::server::rfc8489::{MessageIntegritySha256, PasswordAlgorithm, PasswordAlgorithms};
+use crate::server::{TCP_TRANSPORT, UDP_TRANSPORT};
 use crate::Attribute;
-use bytecodec::DecodeExt;
+use bytecodec::{DecodeExt, EncodeExt};
 use secrecy::SecretString;
 use std::io;
 use std::time::Duration;
@@ -10,14 +13,16 @@ use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Nonce, Userna
 use stun_codec::rfc5389::errors::BadRequest;
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress,
+    ChannelNumber, Data, DontFragment, EvenPort, Lifetime, RequestedTransport, ReservationToken,
+    XorPeerAddress,
 };
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH, SEND};
+use stun_codec::rfc5780::attributes::ChangeRequest;
+use stun_codec::rfc8016::attributes::MobilityTicket;
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
-use stun_codec::{Message, MessageClass, Method, TransactionId};
-use uuid::Uuid;
+use stun_codec::{Message, MessageClass, MessageEncoder, Method, TransactionId};
 
 /// The maximum lifetime of an allocation.
 const MAX_ALLOCATION_LIFETIME: Duration = Duration::from_secs(3600);
@@ -65,9 +70,18 @@ impl Decoder {
                     (CHANNEL_BIND, Request) => {
                         Ok(ChannelBind::parse(&message).map(ClientMessage::ChannelBind))
                     }
-                    (CREATE_PERMISSION, Request) => Ok(Ok(ClientMessage::CreatePermission(
-                        CreatePermission::parse(&message),
+                    (CREATE_PERMISSION, Request) => {
+                        Ok(CreatePermission::parse(&message).map(ClientMessage::CreatePermission))
+                    }
+                    (SEND, Indication) => Ok(Ok(ClientMessage::SendIndication(
+                        SendIndication::parse(&message)?,
                     ))),
+                    (method, Request) if method.as_u16() == CONNECT => {
+                        Ok(Connect::parse(&message).map(ClientMessage::Connect))
+                    }
+                    (method, Request) if method.as_u16() == CONNECTION_BIND => {
+                        Ok(ConnectionBind::parse(&message).map(ClientMessage::ConnectionBind))
+                    }
                     (_, Request) => Ok(Err(bad_request(&message))),
                     (method, class) => {
                         Err(Error::DecodeStun(bytecodec::Error::from(io::Error::new(
@@ -95,6 +109,9 @@ pub enum ClientMessage<'a> {
     Refresh(Refresh),
     ChannelBind(ChannelBind),
     CreatePermission(CreatePermission),
+    SendIndication(SendIndication),
+    Connect(Connect),
+    ConnectionBind(ConnectionBind),
 }
 
 impl<'a> ClientMessage<'a> {
@@ -105,6 +122,9 @@ impl<'a> ClientMessage<'a> {
             ClientMessage::Refresh(request) => Some(request.transaction_id),
             ClientMessage::ChannelBind(request) => Some(request.transaction_id),
             ClientMessage::CreatePermission(request) => Some(request.transaction_id),
+            ClientMessage::SendIndication(indication) => Some(indication.transaction_id),
+            ClientMessage::Connect(request) => Some(request.transaction_id),
+            ClientMessage::ConnectionBind(request) => Some(request.transaction_id),
             ClientMessage::ChannelData(_) => None,
         }
     }
@@ -113,33 +133,64 @@ impl<'a> ClientMessage<'a> {
 #[derive(Debug)]
 pub struct Binding {
     transaction_id: TransactionId,
+    change_request: Option<ChangeRequest>,
 }
 
 impl Binding {
     pub fn new(transaction_id: TransactionId) -> Self {
-        Self { transaction_id }
+        Self {
+            transaction_id,
+            change_request: None,
+        }
+    }
+
+    pub fn new_with_change_request(
+        transaction_id: TransactionId,
+        change_ip: bool,
+        change_port: bool,
+    ) -> Self {
+        Self {
+            transaction_id,
+            change_request: Some(ChangeRequest::new(change_ip, change_port)),
+        }
     }
 
     pub fn parse(message: &Message<Attribute>) -> Self {
         let transaction_id = message.transaction_id();
+        let change_request = message.get_attribute::<ChangeRequest>().cloned();
 
-        Binding { transaction_id }
+        Binding {
+            transaction_id,
+            change_request,
+        }
     }
 
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
+
+    pub fn change_request(&self) -> Option<&ChangeRequest> {
+        self.change_request.as_ref()
+    }
 }
 
 pub struct Allocate {
     transaction_id: TransactionId,
     message_integrity: Option<MessageIntegrity>,
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    access_token: Option<AccessToken>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    password_algorithm: Option<PasswordAlgorithm>,
     requested_transport: RequestedTransport,
     lifetime: Option<Lifetime>,
     username: Option<Username>,
     nonce: Option<Nonce>,
     requested_address_family: Option<RequestedAddressFamily>,
     additional_address_family: Option<AdditionalAddressFamily>,
+    even_port: Option<EvenPort>,
+    reservation_token: Option<ReservationToken>,
+    dont_fragment: bool,
+    mobility_ticket: Option<MobilityTicket>,
 }
 
 impl Allocate {
@@ -148,109 +199,131 @@ impl Allocate {
         lifetime: Option<Lifetime>,
         username: Username,
         relay_secret: &SecretString,
-        nonce: Uuid,
+        nonce: &str,
     ) -> Self {
-        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+        // IPv4 is the default.
+        Self::new_authenticated_udp(
             transaction_id,
-            &lifetime,
-            &username,
+            lifetime,
+            username,
             relay_secret,
             nonce,
-            None,
-        );
+            vec![],
+        )
+    }
 
-        Self {
+    pub fn new_authenticated_udp_ip6(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: &str,
+    ) -> Self {
+        Self::new_authenticated_udp(
             transaction_id,
-            message_integrity: Some(message_integrity),
-            requested_transport,
             lifetime,
-            username: Some(username),
-            nonce: Some(nonce),
-            requested_address_family: None, // IPv4 is the default.
-            additional_address_family: None,
-        }
+            username,
+            relay_secret,
+            nonce,
+            vec![RequestedAddressFamily::new(AddressFamily::V6).into()],
+        )
     }
 
-    pub fn new_authenticated_udp_ip6(
+    /// Creates an authenticated allocation request for UDP that carries the given `attributes` in addition to the mandatory ones.
+    pub fn new_authenticated_udp(
         transaction_id: TransactionId,
         lifetime: Option<Lifetime>,
         username: Username,
         relay_secret: &SecretString,
-        nonce: Uuid,
+        nonce: &str,
+        attributes: Vec<Attribute>,
     ) -> Self {
-        let requested_address_family = RequestedAddressFamily::new(AddressFamily::V6);
-
-        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+        Self::new_authenticated(
             transaction_id,
-            &lifetime,
-            &username,
+            lifetime,
+            username,
             relay_secret,
             nonce,
-            Some(requested_address_family.clone()),
-        );
+            UDP_TRANSPORT,
+            attributes,
+        )
+    }
 
-        Self {
+    pub fn new_authenticated_tcp_implicit_ip4(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: &str,
+    ) -> Self {
+        Self::new_authenticated(
             transaction_id,
-            message_integrity: Some(message_integrity),
-            requested_transport,
             lifetime,
-            username: Some(username),
-            nonce: Some(nonce),
-            requested_address_family: Some(requested_address_family),
-            additional_address_family: None,
-        }
+            username,
+            relay_secret,
+            nonce,
+            TCP_TRANSPORT,
+            vec![],
+        )
     }
 
     pub fn new_unauthenticated_udp(
         transaction_id: TransactionId,
         lifetime: Option<Lifetime>,
     ) -> Self {
-        let requested_transport = RequestedTransport::new(UDP_TRANSPORT);
+        let message = Self::make_message(transaction_id, lifetime, UDP_TRANSPORT, vec![]);
 
-        let mut message =
-            Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
-        message.add_attribute(requested_transport.clone());
+        Self::from_message(&message)
+    }
 
-        if let Some(lifetime) = &lifetime {
-            message.add_attribute(lifetime.clone());
-        }
+    pub fn new_authenticated_udp_access_token(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        key_id: Username,
+        access_token: Vec<u8>,
+        mac_key: &[u8],
+        nonce: &str,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
 
-        Self {
+        let mut message = Self::make_message(
             transaction_id,
-            message_integrity: None,
-            requested_transport,
             lifetime,
-            username: None,
-            nonce: None,
-            requested_address_family: None,
-            additional_address_family: None,
-        }
+            UDP_TRANSPORT,
+            vec![
+                key_id.into(),
+                nonce.into(),
+                AccessToken::new(access_token).into(),
+            ],
+        );
+        let message_integrity_sha256 =
+            MessageIntegritySha256::new_with_key(&message, mac_key).unwrap();
+        message.add_attribute(message_integrity_sha256);
+
+        Self::from_message(&message)
     }
 
-    fn make_attributes(
+    fn new_authenticated(
         transaction_id: TransactionId,
-        lifetime: &Option<Lifetime>,
-        username: &Username,
+        lifetime: Option<Lifetime>,
+        username: Username,
         relay_secret: &SecretString,
-        nonce: Uuid,
-        requested_address_family: Option<RequestedAddressFamily>,
-    ) -> (RequestedTransport, Nonce, MessageIntegrity) {
-        let requested_transport = RequestedTransport::new(UDP_TRANSPORT);
-        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
-
-        let mut message =
-            Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
-        message.add_attribute(requested_transport.clone());
-        message.add_attribute(username.clone());
-        message.add_attribute(nonce.clone());
-
-        if let Some(requested_address_family) = requested_address_family {
-            message.add_attribute(requested_address_family);
-        }
+        nonce: &str,
+        transport: u8,
+        additional_attributes: Vec<Attribute>,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
 
-        if let Some(lifetime) = &lifetime {
-            message.add_attribute(lifetime.clone());
-        }
+        let mut message = Self::make_message(
+            transaction_id,
+            lifetime,
+            transport,
+            [
+                vec![username.clone().into(), nonce.into()],
+                additional_attributes,
+            ]
+            .concat(),
+        );
 
         let (expiry, salt) = split_username(username.name()).expect("a valid username");
         let expiry_systemtime = systemtime_from_unix(expiry);
@@ -258,14 +331,46 @@ impl Allocate {
         let password = generate_password(relay_secret, expiry_systemtime, salt);
 
         let message_integrity =
-            MessageIntegrity::new_long_term_credential(&message, username, &FIREZONE, &password)
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
                 .unwrap();
-        (requested_transport, nonce, message_integrity)
+        message.add_attribute(message_integrity);
+
+        Self::from_message(&message)
+    }
+
+    /// Creates the request without any message integrity, which needs to be added last.
+    fn make_message(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        transport: u8,
+        attributes: Vec<Attribute>,
+    ) -> Message<Attribute> {
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
+        message.add_attribute(RequestedTransport::new(transport));
+
+        for attribute in attributes {
+            message.add_attribute(attribute);
+        }
+
+        if let Some(lifetime) = lifetime {
+            message.add_attribute(lifetime);
+        }
+
+        message
+    }
+
+    fn from_message(message: &Message<Attribute>) -> Self {
+        Self::parse(message).expect("a valid allocate request")
     }
 
     pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let message_integrity_sha256 = message.get_attribute::<MessageIntegritySha256>().cloned();
+        let access_token = AccessToken::parse(message);
+        let password_algorithms = message.get_attribute::<PasswordAlgorithms>().cloned();
+        let password_algorithm = message.get_attribute::<PasswordAlgorithm>().copied();
         let nonce = message.get_attribute::<Nonce>().cloned();
         let requested_transport = message
             .get_attribute::<RequestedTransport>()
@@ -275,16 +380,58 @@ impl Allocate {
         let username = message.get_attribute::<Username>().cloned();
         let requested_address_family = message.get_attribute::<RequestedAddressFamily>().cloned();
         let additional_address_family = message.get_attribute::<AdditionalAddressFamily>().cloned();
+        let even_port = message.get_attribute::<EvenPort>().cloned();
+        let reservation_token = message.get_attribute::<ReservationToken>().cloned();
+        let dont_fragment = message.get_attribute::<DontFragment>().is_some();
+        let mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
+
+        // Clients can only request mobility when allocating, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.1>.
+        if mobility_ticket
+            .as_ref()
+            .map_or(false, |t| !t.data().is_empty())
+        {
+            return Err(bad_request(message));
+        }
+
+        // A reserved port can only be claimed for an allocation with the default address family, see <https://www.rfc-editor.org/rfc/rfc8656#section-7.2>.
+        if reservation_token.is_some()
+            && (even_port.is_some()
+                || requested_address_family.is_some()
+                || additional_address_family.is_some())
+        {
+            return Err(bad_request(message));
+        }
+
+        if even_port.as_ref().map_or(false, EvenPort::is_requested)
+            && additional_address_family.is_some()
+        {
+            return Err(bad_request(message));
+        }
+
+        // See <https://www.rfc-editor.org/rfc/rfc6062#section-5.1>.
+        if requested_transport.protocol() == TCP_TRANSPORT
+            && (even_port.is_some() || reservation_token.is_some())
+        {
+            return Err(bad_request(message));
+        }
 
         Ok(Allocate {
             transaction_id,
             message_integrity,
+            message_integrity_sha256,
+            access_token,
+            password_algorithms,
+            password_algorithm,
             requested_transport,
             lifetime,
             username,
             nonce,
             requested_address_family,
             additional_address_family,
+            even_port,
+            reservation_token,
+            dont_fragment,
+            mobility_ticket,
         })
     }
 
@@ -296,6 +443,22 @@ impl Allocate {
         self.message_integrity.as_ref()
     }
 
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+
+    pub fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+        self.password_algorithms.as_ref()
+    }
+
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        self.password_algorithm
+    }
+
     pub fn requested_transport(&self) -> &RequestedTransport {
         &self.requested_transport
     }
@@ -319,14 +482,35 @@ impl Allocate {
     pub fn additional_address_family(&self) -> Option<&AdditionalAddressFamily> {
         self.additional_address_family.as_ref()
     }
+
+    pub fn even_port(&self) -> Option<&EvenPort> {
+        self.even_port.as_ref()
+    }
+
+    pub fn reservation_token(&self) -> Option<&ReservationToken> {
+        self.reservation_token.as_ref()
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.dont_fragment
+    }
+
+    pub fn mobility_ticket(&self) -> Option<&MobilityTicket> {
+        self.mobility_ticket.as_ref()
+    }
 }
 
 pub struct Refresh {
     transaction_id: TransactionId,
     message_integrity: Option<MessageIntegrity>,
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    access_token: Option<AccessToken>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    password_algorithm: Option<PasswordAlgorithm>,
     lifetime: Option<Lifetime>,
     username: Option<Username>,
     nonce: Option<Nonce>,
+    mobility_ticket: Option<MobilityTicket>,
 }
 
 impl Refresh {
@@ -335,9 +519,27 @@ impl Refresh {
         lifetime: Option<Lifetime>,
         username: Username,
         relay_secret: &SecretString,
-        nonce: Uuid,
+        nonce: &str,
     ) -> Self {
-        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+        Self::new_with_mobility_ticket(
+            transaction_id,
+            lifetime,
+            username,
+            relay_secret,
+            nonce,
+            None,
+        )
+    }
+
+    pub fn new_with_mobility_ticket(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: &str,
+        mobility_ticket: Option<MobilityTicket>,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
 
         let mut message = Message::<Attribute>::new(MessageClass::Request, REFRESH, transaction_id);
         message.add_attribute(username.clone());
@@ -347,6 +549,10 @@ impl Refresh {
             message.add_attribute(lifetime.clone());
         }
 
+        if let Some(mobility_ticket) = &mobility_ticket {
+            message.add_attribute(mobility_ticket.clone());
+        }
+
         let (expiry, salt) = split_username(username.name()).expect("a valid username");
         let expiry_systemtime = systemtime_from_unix(expiry);
 
@@ -359,25 +565,40 @@ impl Refresh {
         Self {
             transaction_id,
             message_integrity: Some(message_integrity),
+            message_integrity_sha256: None,
+            access_token: None,
+            password_algorithms: None,
+            password_algorithm: None,
             lifetime,
             username: Some(username),
             nonce: Some(nonce),
+            mobility_ticket,
         }
     }
 
     pub fn parse(message: &Message<Attribute>) -> Self {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let message_integrity_sha256 = message.get_attribute::<MessageIntegritySha256>().cloned();
+        let access_token = AccessToken::parse(message);
+        let password_algorithms = message.get_attribute::<PasswordAlgorithms>().cloned();
+        let password_algorithm = message.get_attribute::<PasswordAlgorithm>().copied();
         let nonce = message.get_attribute::<Nonce>().cloned();
         let lifetime = message.get_attribute::<Lifetime>().cloned();
         let username = message.get_attribute::<Username>().cloned();
+        let mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
 
         Refresh {
             transaction_id,
             message_integrity,
+            message_integrity_sha256,
+            access_token,
+            password_algorithms,
+            password_algorithm,
             lifetime,
             username,
             nonce,
+            mobility_ticket,
         }
     }
 
@@ -389,6 +610,22 @@ impl Refresh {
         self.message_integrity.as_ref()
     }
 
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+
+    pub fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+        self.password_algorithms.as_ref()
+    }
+
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        self.password_algorithm
+    }
+
     pub fn effective_lifetime(&self) -> Lifetime {
         compute_effective_lifetime(self.lifetime.as_ref())
     }
@@ -400,12 +637,20 @@ impl Refresh {
     pub fn nonce(&self) -> Option<&Nonce> {
         self.nonce.as_ref()
     }
+
+    pub fn mobility_ticket(&self) -> Option<&MobilityTicket> {
+        self.mobility_ticket.as_ref()
+    }
 }
 
 pub struct ChannelBind {
     transaction_id: TransactionId,
     channel_number: ChannelNumber,
     message_integrity: Option<MessageIntegrity>,
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    access_token: Option<AccessToken>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    password_algorithm: Option<PasswordAlgorithm>,
     nonce: Option<Nonce>,
     xor_peer_address: XorPeerAddress,
     username: Option<Username>,
@@ -418,9 +663,9 @@ impl ChannelBind {
         xor_peer_address: XorPeerAddress,
         username: Username,
         relay_secret: &SecretString,
-        nonce: Uuid,
+        nonce: &str,
     ) -> Self {
-        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
 
         let mut message =
             Message::<Attribute>::new(MessageClass::Request, CHANNEL_BIND, transaction_id);
@@ -442,6 +687,10 @@ impl ChannelBind {
             transaction_id,
             channel_number,
             message_integrity: Some(message_integrity),
+            message_integrity_sha256: None,
+            access_token: None,
+            password_algorithms: None,
+            password_algorithm: None,
             xor_peer_address,
             username: Some(username),
             nonce: Some(nonce),
@@ -455,6 +704,10 @@ impl ChannelBind {
             .copied()
             .ok_or(bad_request(message))?;
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let message_integrity_sha256 = message.get_attribute::<MessageIntegritySha256>().cloned();
+        let access_token = AccessToken::parse(message);
+        let password_algorithms = message.get_attribute::<PasswordAlgorithms>().cloned();
+        let password_algorithm = message.get_attribute::<PasswordAlgorithm>().copied();
         let nonce = message.get_attribute::<Nonce>().cloned();
         let username = message.get_attribute::<Username>().cloned();
         let xor_peer_address = message
@@ -466,6 +719,10 @@ impl ChannelBind {
             transaction_id,
             channel_number,
             message_integrity,
+            message_integrity_sha256,
+            access_token,
+            password_algorithms,
+            password_algorithm,
             nonce,
             xor_peer_address,
             username,
@@ -484,6 +741,22 @@ impl ChannelBind {
         self.message_integrity.as_ref()
     }
 
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+
+    pub fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+        self.password_algorithms.as_ref()
+    }
+
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        self.password_algorithm
+    }
+
     pub fn xor_peer_address(&self) -> &XorPeerAddress {
         &self.xor_peer_address
     }
@@ -500,25 +773,329 @@ impl ChannelBind {
 pub struct CreatePermission {
     transaction_id: TransactionId,
     message_integrity: Option<MessageIntegrity>,
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    access_token: Option<AccessToken>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    password_algorithm: Option<PasswordAlgorithm>,
+    xor_peer_addresses: Vec<XorPeerAddress>,
     username: Option<Username>,
     nonce: Option<Nonce>,
 }
 
 impl CreatePermission {
-    pub fn parse(message: &Message<Attribute>) -> Self {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_addresses: Vec<XorPeerAddress>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: &str,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, CREATE_PERMISSION, transaction_id);
+        message.add_attribute(username.clone());
+        for xor_peer_address in &xor_peer_addresses {
+            message.add_attribute(xor_peer_address.clone());
+        }
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            message_integrity_sha256: None,
+            access_token: None,
+            password_algorithms: None,
+            password_algorithm: None,
+            xor_peer_addresses,
+            username: Some(username),
+            nonce: Some(nonce),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let message_integrity_sha256 = message.get_attribute::<MessageIntegritySha256>().cloned();
+        let access_token = AccessToken::parse(message);
+        let password_algorithms = message.get_attribute::<PasswordAlgorithms>().cloned();
+        let password_algorithm = message.get_attribute::<PasswordAlgorithm>().copied();
         let username = message.get_attribute::<Username>().cloned();
         let nonce = message.get_attribute::<Nonce>().cloned();
+        let xor_peer_addresses = message
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::XorPeerAddress(address) => Some(address.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if xor_peer_addresses.is_empty() {
+            return Err(bad_request(message));
+        }
+
+        Ok(CreatePermission {
+            transaction_id,
+            message_integrity,
+            message_integrity_sha256,
+            access_token,
+            password_algorithms,
+            password_algorithm,
+            xor_peer_addresses,
+            username,
+            nonce,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        self.message_integrity.as_ref()
+    }
+
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+
+    pub fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+        self.password_algorithms.as_ref()
+    }
+
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        self.password_algorithm
+    }
+
+    pub fn xor_peer_addresses(&self) -> &[XorPeerAddress] {
+        &self.xor_peer_addresses
+    }
+
+    pub fn username(&self) -> Option<&Username> {
+        self.username.as_ref()
+    }
+
+    pub fn nonce(&self) -> Option<&Nonce> {
+        self.nonce.as_ref()
+    }
+}
+
+/// A CONNECT request, used by clients to open a TCP connection from their TCP allocation to a peer.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.2>.
+pub struct Connect {
+    transaction_id: TransactionId,
+    message_integrity: Option<MessageIntegrity>,
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    access_token: Option<AccessToken>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    password_algorithm: Option<PasswordAlgorithm>,
+    xor_peer_address: XorPeerAddress,
+    username: Option<Username>,
+    nonce: Option<Nonce>,
+}
+
+impl Connect {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: &str,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
+
+        let mut message = Message::<Attribute>::new(
+            MessageClass::Request,
+            rfc6062::method(CONNECT),
+            transaction_id,
+        );
+        message.add_attribute(username.clone());
+        message.add_attribute(xor_peer_address.clone());
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            message_integrity_sha256: None,
+            access_token: None,
+            password_algorithms: None,
+            password_algorithm: None,
+            xor_peer_address,
+            username: Some(username),
+            nonce: Some(nonce),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
+        let transaction_id = message.transaction_id();
+        let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let message_integrity_sha256 = message.get_attribute::<MessageIntegritySha256>().cloned();
+        let access_token = AccessToken::parse(message);
+        let password_algorithms = message.get_attribute::<PasswordAlgorithms>().cloned();
+        let password_algorithm = message.get_attribute::<PasswordAlgorithm>().copied();
+        let nonce = message.get_attribute::<Nonce>().cloned();
+        let username = message.get_attribute::<Username>().cloned();
+        let xor_peer_address = message
+            .get_attribute::<XorPeerAddress>()
+            .ok_or(bad_request(message))?
+            .clone();
 
-        CreatePermission {
+        Ok(Connect {
             transaction_id,
             message_integrity,
+            message_integrity_sha256,
+            access_token,
+            password_algorithms,
+            password_algorithm,
+            xor_peer_address,
             username,
             nonce,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        self.message_integrity.as_ref()
+    }
+
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+
+    pub fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+        self.password_algorithms.as_ref()
+    }
+
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        self.password_algorithm
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn username(&self) -> Option<&Username> {
+        self.username.as_ref()
+    }
+
+    pub fn nonce(&self) -> Option<&Nonce> {
+        self.nonce.as_ref()
+    }
+}
+
+/// A CONNECTION-BIND request, sent by clients on a new TCP connection to the relay to associate it with a peer connection.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.4>.
+pub struct ConnectionBind {
+    transaction_id: TransactionId,
+    message_integrity: Option<MessageIntegrity>,
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    access_token: Option<AccessToken>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    password_algorithm: Option<PasswordAlgorithm>,
+    connection_id: ConnectionId,
+    username: Option<Username>,
+    nonce: Option<Nonce>,
+}
+
+impl ConnectionBind {
+    pub fn new(
+        transaction_id: TransactionId,
+        connection_id: ConnectionId,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: &str,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.to_owned()).expect("nonce to be less than 128 characters");
+
+        let mut message = Message::<Attribute>::new(
+            MessageClass::Request,
+            rfc6062::method(CONNECTION_BIND),
+            transaction_id,
+        );
+        message.add_attribute(username.clone());
+        message.add_attribute(connection_id);
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            message_integrity_sha256: None,
+            access_token: None,
+            password_algorithms: None,
+            password_algorithm: None,
+            connection_id,
+            username: Some(username),
+            nonce: Some(nonce),
         }
     }
 
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
+        let transaction_id = message.transaction_id();
+        let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let message_integrity_sha256 = message.get_attribute::<MessageIntegritySha256>().cloned();
+        let access_token = AccessToken::parse(message);
+        let password_algorithms = message.get_attribute::<PasswordAlgorithms>().cloned();
+        let password_algorithm = message.get_attribute::<PasswordAlgorithm>().copied();
+        let nonce = message.get_attribute::<Nonce>().cloned();
+        let username = message.get_attribute::<Username>().cloned();
+        let connection_id = message
+            .get_attribute::<ConnectionId>()
+            .copied()
+            .ok_or(bad_request(message))?;
+
+        Ok(ConnectionBind {
+            transaction_id,
+            message_integrity,
+            message_integrity_sha256,
+            access_token,
+            password_algorithms,
+            password_algorithm,
+            connection_id,
+            username,
+            nonce,
+        })
+    }
+
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
@@ -527,6 +1104,26 @@ impl CreatePermission {
         self.message_integrity.as_ref()
     }
 
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+
+    pub fn password_algorithms(&self) -> Option<&PasswordAlgorithms> {
+        self.password_algorithms.as_ref()
+    }
+
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        self.password_algorithm
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
     pub fn username(&self) -> Option<&Username> {
         self.username.as_ref()
     }
@@ -536,6 +1133,76 @@ impl CreatePermission {
     }
 }
 
+/// A SEND indication, used by clients to relay data to a peer without a channel.
+///
+/// Indications cannot be authenticated, thus the peer must have a permission on the client's allocation.
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-send-indication>.
+pub struct SendIndication {
+    transaction_id: TransactionId,
+    xor_peer_address: XorPeerAddress,
+    data: Data,
+}
+
+impl SendIndication {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        data: Data,
+    ) -> Self {
+        Self {
+            transaction_id,
+            xor_peer_address,
+            data,
+        }
+    }
+
+    /// Parses a SEND indication.
+    ///
+    /// Indications are never answered, thus a malformed indication is a decoding error rather than an error response.
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Error> {
+        let transaction_id = message.transaction_id();
+        let (Some(xor_peer_address), Some(data)) = (
+            message.get_attribute::<XorPeerAddress>().cloned(),
+            message.get_attribute::<Data>().cloned(),
+        ) else {
+            return Err(Error::DecodeStun(bytecodec::Error::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SEND indication is missing XOR-PEER-ADDRESS or DATA",
+            ))));
+        };
+
+        Ok(SendIndication {
+            transaction_id,
+            xor_peer_address,
+            data,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data.data()
+    }
+
+    /// Encodes the indication as a client would send it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Indication, SEND, self.transaction_id);
+        message.add_attribute(self.xor_peer_address.clone());
+        message.add_attribute(self.data.clone());
+
+        MessageEncoder::default()
+            .encode_into_bytes(message)
+            .expect("encoding a SEND indication never fails")
+    }
+}
+
 /// Computes the effective lifetime of an allocation.
 fn compute_effective_lifetime(requested_lifetime: Option<&Lifetime>) -> Lifetime {
     let Some(requested) = requested_lifetime else {
@@ -598,4 +1265,34 @@ mod tests {
 
         assert_eq!(effective_lifetime.lifetime(), MAX_ALLOCATION_LIFETIME)
     }
+
+    #[test]
+    fn request_with_invalid_fingerprint_is_bad_request() {
+        use bytecodec::EncodeExt;
+        use stun_codec::rfc5389::attributes::Fingerprint;
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, BINDING, TransactionId::new([0; 12]));
+        message.add_attribute(Fingerprint::new(&message).unwrap());
+        let mut bytes = stun_codec::MessageEncoder::new()
+            .encode_into_bytes(message)
+            .unwrap();
+
+        let mut decoder = Decoder::default();
+        assert!(matches!(
+            decoder.decode(&bytes),
+            Ok(Ok(ClientMessage::Binding(_)))
+        ));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        let Ok(Err(response)) = decoder.decode(&bytes) else {
+            panic!("expected an error response");
+        };
+        assert_eq!(
+            response.get_attribute::<ErrorCode>().map(|e| e.code()),
+            Some(400)
+        );
+    }
 }
@@ -0,0 +1,78 @@
+use crate::server::{channels_of, AllocationId, Server, Traffic};
+use rand::Rng;
+use serde::{Serialize, Serializer};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// A read-only view of an allocation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AllocationInfo {
+    pub id: AllocationId,
+    /// The address of the client that owns the allocation.
+    pub client: SocketAddr,
+    pub username: String,
+    /// The addresses on which we relay data for this allocation.
+    pub relay_addresses: Vec<SocketAddr>,
+    #[serde(serialize_with = "unix_timestamp")]
+    pub expires_at: SystemTime,
+    pub channels: Vec<ChannelInfo>,
+    pub traffic: Traffic,
+}
+
+/// A read-only view of a channel binding.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChannelInfo {
+    pub number: u16,
+    pub peer: SocketAddr,
+    #[serde(serialize_with = "unix_timestamp")]
+    pub expires_at: SystemTime,
+    /// Unbound channels no longer relay data but their number remains reserved for a while.
+    pub bound: bool,
+    pub traffic: Traffic,
+}
+
+impl<R> Server<R>
+where
+    R: Rng,
+{
+    /// Returns a view of all active allocations and their channel bindings.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        self.allocations
+            .iter()
+            .map(|(client, allocation)| {
+                let channels = channels_of(&self.channels_by_allocation, allocation.id)
+                    .into_iter()
+                    .map(|(number, channel)| ChannelInfo {
+                        number,
+                        peer: channel.peer_address,
+                        expires_at: channel.expiry,
+                        bound: channel.bound,
+                        traffic: channel.traffic,
+                    })
+                    .collect();
+
+                AllocationInfo {
+                    id: allocation.id,
+                    client: *client,
+                    username: allocation.username.clone(),
+                    relay_addresses: allocation.relay_addresses(),
+                    expires_at: allocation.expires_at,
+                    channels,
+                    traffic: allocation.traffic,
+                }
+            })
+            .collect()
+    }
+}
+
+fn unix_timestamp<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    serializer.serialize_u64(seconds)
+}
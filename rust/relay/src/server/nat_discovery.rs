@@ -0,0 +1,162 @@
+use crate::IpStack;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use stun_codec::rfc8656::attributes::AddressFamily;
+
+/// Our addresses for NAT behaviour discovery as per <https://www.rfc-editor.org/rfc/rfc5780>.
+///
+/// In addition to our primary port, we answer BINDING requests on an alternate port and, if configured, on an alternate IP.
+/// Clients compare the mapped addresses they learn from these to classify the mapping behaviour of their NAT.
+/// Using CHANGE-REQUEST, they can ask us to respond from a different address to classify its filtering behaviour.
+#[derive(Debug, Clone, Copy)]
+pub struct NatDiscovery {
+    primary_port: u16,
+    alternate_port: u16,
+    alternate_ip4: Option<Ipv4Addr>,
+    alternate_ip6: Option<Ipv6Addr>,
+}
+
+impl NatDiscovery {
+    /// Answers BINDING requests on `alternate_port` in addition to `primary_port`, the port we serve TURN on.
+    pub fn new(primary_port: u16, alternate_port: u16) -> Self {
+        Self {
+            primary_port,
+            alternate_port,
+            alternate_ip4: None,
+            alternate_ip6: None,
+        }
+    }
+
+    /// Additionally answer BINDING requests on the given, second public IPv4 address.
+    pub fn with_alternate_ip4(mut self, ip4: Ipv4Addr) -> Self {
+        self.alternate_ip4 = Some(ip4);
+
+        self
+    }
+
+    /// Additionally answer BINDING requests on the given, second public IPv6 address.
+    pub fn with_alternate_ip6(mut self, ip6: Ipv6Addr) -> Self {
+        self.alternate_ip6 = Some(ip6);
+
+        self
+    }
+
+    pub(crate) fn has_alternate_ip(&self, family: AddressFamily) -> bool {
+        match family {
+            AddressFamily::V4 => self.alternate_ip4.is_some(),
+            AddressFamily::V6 => self.alternate_ip6.is_some(),
+        }
+    }
+
+    pub(crate) fn alternate_ips(&self) -> impl Iterator<Item = IpAddr> {
+        [
+            self.alternate_ip4.map(IpAddr::from),
+            self.alternate_ip6.map(IpAddr::from),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// The public address of the given socket, if we have one for this address family.
+    pub(crate) fn address(
+        &self,
+        public_address: &IpStack,
+        family: AddressFamily,
+        socket: DiscoverySocket,
+    ) -> Option<SocketAddr> {
+        let ip = match (family, socket.alternate_ip) {
+            (AddressFamily::V4, false) => IpAddr::from(*public_address.as_v4()?),
+            (AddressFamily::V4, true) => IpAddr::from(self.alternate_ip4?),
+            (AddressFamily::V6, false) => IpAddr::from(*public_address.as_v6()?),
+            (AddressFamily::V6, true) => IpAddr::from(self.alternate_ip6?),
+        };
+        let port = if socket.alternate_port {
+            self.alternate_port
+        } else {
+            self.primary_port
+        };
+
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+/// Identifies one of the (up to) four UDP sockets per address family that answer BINDING requests.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc5780#section-4.1>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DiscoverySocket {
+    /// Whether the socket is bound to our alternate instead of our primary IP.
+    pub alternate_ip: bool,
+    /// Whether the socket is bound to our alternate instead of our primary port.
+    pub alternate_port: bool,
+}
+
+impl DiscoverySocket {
+    /// The socket we also serve TURN on.
+    pub const PRIMARY: Self = Self {
+        alternate_ip: false,
+        alternate_port: false,
+    };
+
+    /// The socket that differs from this one in the IP and / or port, as requested by a CHANGE-REQUEST attribute.
+    pub(crate) fn changed(self, ip: bool, port: bool) -> Self {
+        Self {
+            alternate_ip: self.alternate_ip ^ ip,
+            alternate_port: self.alternate_port ^ port,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_IP4: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+    const ALTERNATE_IP4: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 2);
+
+    #[test]
+    fn addresses_combine_ips_and_ports() {
+        let discovery = NatDiscovery::new(3478, 3479).with_alternate_ip4(ALTERNATE_IP4);
+        let public_address = IpStack::Ip4(PUBLIC_IP4);
+
+        let address = |socket| discovery.address(&public_address, AddressFamily::V4, socket);
+
+        assert_eq!(
+            address(DiscoverySocket::PRIMARY),
+            Some(SocketAddr::from((PUBLIC_IP4, 3478)))
+        );
+        assert_eq!(
+            address(DiscoverySocket::PRIMARY.changed(false, true)),
+            Some(SocketAddr::from((PUBLIC_IP4, 3479)))
+        );
+        assert_eq!(
+            address(DiscoverySocket::PRIMARY.changed(true, false)),
+            Some(SocketAddr::from((ALTERNATE_IP4, 3478)))
+        );
+        assert_eq!(
+            address(DiscoverySocket::PRIMARY.changed(true, true)),
+            Some(SocketAddr::from((ALTERNATE_IP4, 3479)))
+        );
+    }
+
+    #[test]
+    fn no_alternate_ip_addresses_without_alternate_ip() {
+        let discovery = NatDiscovery::new(3478, 3479);
+        let socket = DiscoverySocket::PRIMARY.changed(true, false);
+
+        assert!(!discovery.has_alternate_ip(AddressFamily::V4));
+        assert_eq!(
+            discovery.address(&IpStack::Ip4(PUBLIC_IP4), AddressFamily::V4, socket),
+            None
+        );
+    }
+
+    #[test]
+    fn changing_twice_returns_to_original_socket() {
+        let socket = DiscoverySocket {
+            alternate_ip: true,
+            alternate_port: false,
+        };
+
+        assert_eq!(socket.changed(true, true).changed(true, true), socket);
+    }
+}
@@ -0,0 +1,244 @@
+//! Encoding of relayed datagrams as [pcapng](https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html) blocks.
+//!
+//! We don't capture the actual packets on the wire but synthesize IP and UDP headers for the payloads we relay.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+/// Raw IPv4 or IPv6 packets, depending on the version in the first header.
+const LINKTYPE_RAW: u16 = 101;
+
+const UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// The length of the [`file_header`].
+pub(crate) const FILE_HEADER_LEN: usize = 48;
+
+/// The blocks every capture file starts with: A section header and the description of a single interface for raw IP packets.
+pub(crate) fn file_header() -> Vec<u8> {
+    let mut section_header = Vec::new();
+    section_header.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    section_header.extend_from_slice(&1u16.to_le_bytes()); // Major version
+    section_header.extend_from_slice(&0u16.to_le_bytes()); // Minor version
+    section_header.extend_from_slice(&u64::MAX.to_le_bytes()); // Section length is unspecified.
+
+    let mut interface_description = Vec::new();
+    interface_description.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    interface_description.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+    interface_description.extend_from_slice(&0u32.to_le_bytes()); // No snap length
+
+    let mut header = block(SECTION_HEADER_BLOCK, &section_header);
+    header.extend(block(INTERFACE_DESCRIPTION_BLOCK, &interface_description));
+
+    header
+}
+
+/// A block for a UDP datagram with the given payload, or `None` if `source` and `destination` are of different address families.
+///
+/// Timestamps use the default resolution of microseconds since the UNIX epoch.
+pub(crate) fn enhanced_packet_block(
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: &[u8],
+    now: SystemTime,
+) -> Option<Vec<u8>> {
+    let packet = udp_packet(source, destination, payload)?;
+    let timestamp = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let mut body = Vec::with_capacity(20 + packet.len() + 3);
+    body.extend_from_slice(&0u32.to_le_bytes()); // Interface ID
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // Captured length
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // Original length
+    body.extend_from_slice(&packet);
+    body.resize(body.len().next_multiple_of(4), 0);
+
+    Some(block(ENHANCED_PACKET_BLOCK, &body))
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total_length = (12 + body.len()) as u32;
+
+    let mut block = Vec::with_capacity(total_length as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total_length.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total_length.to_le_bytes());
+
+    block
+}
+
+fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let udp_length = (8 + payload.len()) as u16;
+
+    let mut packet = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![
+                0x45, // Version 4, header length of 5 words
+                0,    // DSCP & ECN
+            ];
+            header.extend_from_slice(&(20 + udp_length).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0, 0, TTL, UDP, 0, 0]); // Identification, flags, fragment offset, TTL, protocol and checksum
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            let checksum = !fold(sum(&header));
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            header
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut header = vec![0x60, 0, 0, 0]; // Version 6, traffic class & flow label
+            header.extend_from_slice(&udp_length.to_be_bytes());
+            header.extend_from_slice(&[UDP, TTL]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            header
+        }
+        _ => return None,
+    };
+    let ip_header_length = packet.len();
+
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_length.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    let checksum = udp_checksum(source.ip(), destination.ip(), &packet[ip_header_length..]);
+    packet[ip_header_length + 6..ip_header_length + 8].copy_from_slice(&checksum.to_be_bytes());
+
+    Some(packet)
+}
+
+/// The UDP checksum over the pseudo header and the datagram, see <https://www.rfc-editor.org/rfc/rfc768> and <https://www.rfc-editor.org/rfc/rfc8200#section-8.1>.
+fn udp_checksum(source: IpAddr, destination: IpAddr, datagram: &[u8]) -> u16 {
+    let mut pseudo_header = Vec::new();
+    for ip in [source, destination] {
+        match ip {
+            IpAddr::V4(ip) => pseudo_header.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => pseudo_header.extend_from_slice(&ip.octets()),
+        }
+    }
+    pseudo_header.extend_from_slice(&[0, UDP]);
+    pseudo_header.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+
+    match !fold(sum(&pseudo_header) + sum(datagram)) {
+        0 => 0xFFFF, // A checksum of 0 means "no checksum", thus it is transmitted as all ones.
+        checksum => checksum,
+    }
+}
+
+/// The sum of the given bytes as big-endian 16-bit words, padding an odd trailing byte with zero.
+fn sum(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(word.get(1).copied().unwrap_or(0)))
+        .sum()
+}
+
+/// Folds a sum into the 16-bit ones' complement sum.
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn file_header_has_expected_length() {
+        assert_eq!(file_header().len(), FILE_HEADER_LEN);
+    }
+
+    #[test]
+    fn ip4_packet_has_valid_checksums() {
+        let packet = udp_packet(
+            "192.0.2.1:50000".parse().unwrap(),
+            "198.51.100.1:3478".parse().unwrap(),
+            b"hello",
+        )
+        .unwrap();
+
+        assert_eq!(packet.len(), 20 + 8 + 5);
+        assert_eq!(fold(sum(&packet[..20])), 0xFFFF);
+        assert_eq!(
+            fold(sum(&packet[12..20]) + u32::from(UDP) + 13 + sum(&packet[20..])),
+            0xFFFF
+        );
+    }
+
+    #[test]
+    fn ip6_packet_has_valid_checksum() {
+        let packet = udp_packet(
+            "[2001:db8::1]:50000".parse().unwrap(),
+            "[2001:db8::2]:3478".parse().unwrap(),
+            b"hello",
+        )
+        .unwrap();
+
+        assert_eq!(packet.len(), 40 + 8 + 5);
+        assert_eq!(&packet[4..6], &13u16.to_be_bytes());
+        assert_eq!(
+            fold(sum(&packet[8..40]) + u32::from(UDP) + 13 + sum(&packet[40..])),
+            0xFFFF
+        );
+    }
+
+    #[test]
+    fn mixed_address_families_are_not_encoded() {
+        let block = enhanced_packet_block(
+            "192.0.2.1:50000".parse().unwrap(),
+            "[2001:db8::2]:3478".parse().unwrap(),
+            b"hello",
+            SystemTime::UNIX_EPOCH,
+        );
+
+        assert_eq!(block, None);
+    }
+
+    #[test]
+    fn blocks_are_padded_to_32_bits() {
+        let block = enhanced_packet_block(
+            "192.0.2.1:50000".parse().unwrap(),
+            "198.51.100.1:3478".parse().unwrap(),
+            b"hello",
+            SystemTime::UNIX_EPOCH + Duration::from_micros(0x1_0000_0002),
+        )
+        .unwrap();
+
+        assert_eq!(block.len(), 32 + 36);
+        assert_eq!(&block[..4], &ENHANCED_PACKET_BLOCK.to_le_bytes());
+        assert_eq!(&block[4..8], &68u32.to_le_bytes());
+        assert_eq!(&block[64..], &68u32.to_le_bytes());
+        assert_eq!(&block[12..16], &1u32.to_le_bytes()); // Timestamp (high)
+        assert_eq!(&block[16..20], &2u32.to_le_bytes()); // Timestamp (low)
+        assert_eq!(&block[20..24], &33u32.to_le_bytes()); // Captured length
+    }
+
+    #[test]
+    fn file_header_describes_raw_ip_interface() {
+        let header = file_header();
+
+        assert_eq!(header.len(), 28 + 20);
+        assert_eq!(&header[..4], &SECTION_HEADER_BLOCK.to_le_bytes());
+        assert_eq!(&header[8..12], &BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(&header[28..32], &INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+        assert_eq!(&header[36..38], &LINKTYPE_RAW.to_le_bytes());
+    }
+}
@@ -0,0 +1,149 @@
+use ip_network::IpNetwork;
+use std::net::IpAddr;
+
+/// Decides which peers clients may relay data to.
+///
+/// Without any restrictions, clients could use the relay to reach services that are only reachable from the relay itself, like cloud metadata endpoints or anything listening on loopback.
+/// Peers within a denied network are rejected, unless they are also within an allowed network.
+#[derive(Debug, Clone)]
+pub struct PeerFilter {
+    denied: Vec<IpNetwork>,
+    allowed: Vec<IpNetwork>,
+}
+
+impl Default for PeerFilter {
+    fn default() -> Self {
+        Self {
+            denied: Self::default_denied_networks(),
+            allowed: Vec::new(),
+        }
+    }
+}
+
+impl PeerFilter {
+    /// Denies the given networks in addition to [`PeerFilter::default_denied_networks`], except for peers within `allowed`.
+    pub fn new(denied: Vec<IpNetwork>, allowed: Vec<IpNetwork>) -> Self {
+        Self {
+            denied: Self::default_denied_networks()
+                .into_iter()
+                .chain(denied)
+                .collect(),
+            allowed,
+        }
+    }
+
+    /// The networks we never relay to unless explicitly allowed.
+    pub fn default_denied_networks() -> Vec<IpNetwork> {
+        [
+            // "This" network.
+            "0.0.0.0/8",
+            // Loopback.
+            "127.0.0.0/8",
+            // Link-local, includes the metadata endpoint of AWS, GCP and Azure.
+            "169.254.0.0/16",
+            // Private networks (RFC 1918), which likely include the relay's own network.
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            // Shared address space for carrier-grade NAT (RFC 6598).
+            "100.64.0.0/10",
+            // Metadata endpoint of Alibaba Cloud.
+            "100.100.100.200/32",
+            // Multicast.
+            "224.0.0.0/4",
+            // Broadcast.
+            "255.255.255.255/32",
+            // Unspecified.
+            "::/128",
+            // Loopback.
+            "::1/128",
+            // IPv4-mapped, NAT64 (RFC 6052) and 6to4 (RFC 3056) addresses, which would otherwise bypass the IPv4 rules.
+            "::ffff:0:0/96",
+            "64:ff9b::/96",
+            "2002::/16",
+            // Unique local addresses (RFC 4193), the IPv6 equivalent of private networks.
+            "fc00::/7",
+            // Link-local.
+            "fe80::/10",
+            // IPv6 metadata endpoint of AWS.
+            "fd00:ec2::254/128",
+            // Multicast.
+            "ff00::/8",
+        ]
+        .into_iter()
+        .map(|network| network.parse().expect("static networks to be valid"))
+        .collect()
+    }
+
+    pub fn is_allowed(&self, peer: IpAddr) -> bool {
+        self.allowed.iter().any(|network| network.contains(peer))
+            || !self.denied.iter().any(|network| network.contains(peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denies_loopback_link_local_private_and_metadata_by_default() {
+        let filter = PeerFilter::default();
+
+        for peer in [
+            "127.0.0.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "224.0.0.1",
+            "::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "fe80::1",
+            "fd00:ec2::254",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "fd12:3456::1",
+        ] {
+            assert!(!filter.is_allowed(peer.parse().unwrap()), "{peer}");
+        }
+    }
+
+    #[test]
+    fn allows_global_addresses_by_default() {
+        let filter = PeerFilter::default();
+
+        for peer in ["203.0.113.1", "172.32.0.1", "2001:db8::1"] {
+            assert!(filter.is_allowed(peer.parse().unwrap()), "{peer}");
+        }
+    }
+
+    #[test]
+    fn allowed_networks_take_precedence() {
+        let filter = PeerFilter::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["10.1.0.0/16".parse().unwrap()],
+        );
+
+        assert!(!filter.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(filter.is_allowed("10.1.0.1".parse().unwrap()));
+        assert!(!filter.is_allowed("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn private_networks_can_be_allowed() {
+        let filter = PeerFilter::new(
+            Vec::new(),
+            vec![
+                "192.168.0.0/16".parse().unwrap(),
+                "fc00::/7".parse().unwrap(),
+            ],
+        );
+
+        assert!(filter.is_allowed("192.168.1.1".parse().unwrap()));
+        assert!(filter.is_allowed("fd12:3456::1".parse().unwrap()));
+        assert!(!filter.is_allowed("10.0.0.1".parse().unwrap()));
+    }
+}
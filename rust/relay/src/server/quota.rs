@@ -0,0 +1,108 @@
+use std::time::SystemTime;
+
+/// Limits on the resources a single client may use on the relay.
+///
+/// Depending on how they are configured on the [`Server`](crate::Server), these are applied per username or per source IP.
+/// `None` means the resource is unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of concurrent allocations.
+    pub max_allocations: Option<usize>,
+    /// The maximum number of concurrent channel bindings across all allocations.
+    pub max_channel_bindings: Option<usize>,
+    /// The maximum number of bytes per second relayed across all allocations, in both directions.
+    pub max_bytes_per_second: Option<u64>,
+}
+
+/// A token bucket for limiting the bandwidth of relayed data.
+///
+/// The bucket holds at most one second worth of tokens, i.e. clients can burst up to their configured rate.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    bytes_per_second: u64,
+    tokens: f64,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_second: u64, now: SystemTime) -> Self {
+        Self {
+            bytes_per_second,
+            tokens: bytes_per_second as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Whether the bucket currently holds enough tokens to relay `bytes`.
+    pub(crate) fn has_capacity(&mut self, bytes: usize, now: SystemTime) -> bool {
+        self.refill(now);
+
+        self.tokens >= bytes as f64
+    }
+
+    /// Remove the tokens for `bytes` from the bucket.
+    ///
+    /// Must only be called after [`TokenBucket::has_capacity`] returned `true`.
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.tokens = (self.tokens - bytes as f64).max(0.0);
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        // Time might go backwards, in which case we don't refill at all.
+        let Ok(elapsed) = now.duration_since(self.last_refill) else {
+            return;
+        };
+
+        let capacity = self.bytes_per_second as f64;
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * capacity).min(capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn new_bucket_allows_burst_of_one_second() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        assert!(bucket.has_capacity(1000, now));
+        assert!(!bucket.has_capacity(1001, now));
+    }
+
+    #[test]
+    fn consuming_tokens_limits_further_data() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        bucket.consume(800);
+
+        assert!(bucket.has_capacity(200, now));
+        assert!(!bucket.has_capacity(201, now));
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        bucket.consume(1000);
+
+        assert!(bucket.has_capacity(500, now + Duration::from_millis(500)));
+        assert!(!bucket.has_capacity(1001, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn time_going_backwards_does_not_refill() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        bucket.consume(1000);
+
+        assert!(!bucket.has_capacity(1, now - Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,196 @@
+//! Methods, attributes and error codes defined in [RFC 6062](https://www.rfc-editor.org/rfc/rfc6062) that `stun_codec` doesn't support.
+
+use bytecodec::fixnum::{U32beDecoder, U32beEncoder};
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode, TryTaggedDecode};
+use core::fmt;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::{Attribute, AttributeType, Method};
+
+/// The codepoint of the `Connect` method, see <https://www.rfc-editor.org/rfc/rfc6062#section-6.1>.
+pub const CONNECT: u16 = 0x000A;
+
+/// The codepoint of the `ConnectionBind` method, see <https://www.rfc-editor.org/rfc/rfc6062#section-6.1>.
+pub const CONNECTION_BIND: u16 = 0x000B;
+
+/// The codepoint of the `ConnectionAttempt` method, see <https://www.rfc-editor.org/rfc/rfc6062#section-6.1>.
+pub const CONNECTION_ATTEMPT: u16 = 0x000C;
+
+/// Turns one of the above codepoints into a [`Method`].
+pub fn method(codepoint: u16) -> Method {
+    Method::new(codepoint).expect("codepoints of RFC 6062 to be valid methods")
+}
+
+/// The `CONNECTION-ID` attribute, uniquely identifying a TCP connection between a relay and a peer.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.2.1>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u32);
+
+impl ConnectionId {
+    pub const CODEPOINT: u16 = 0x002A;
+
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    pub(crate) fn next(&mut self) -> Self {
+        let id = *self;
+
+        self.0 = self.0.wrapping_add(1);
+
+        id
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CID-{}", self.0)
+    }
+}
+
+impl Attribute for ConnectionId {
+    type Decoder = ConnectionIdDecoder;
+    type Encoder = ConnectionIdEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionIdDecoder(U32beDecoder);
+
+impl Decode for ConnectionIdDecoder {
+    type Item = ConnectionId;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.0.finish_decoding().map(ConnectionId)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for ConnectionIdDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attr_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attr_type.as_u16() == ConnectionId::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionIdEncoder(U32beEncoder);
+
+impl Encode for ConnectionIdEncoder {
+    type Item = ConnectionId;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.0.start_encoding(item.0)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for ConnectionIdEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+/// `446`: "Connection Already Exists".
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.3>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionAlreadyExists;
+
+impl ConnectionAlreadyExists {
+    pub const CODEPOINT: u16 = 446;
+}
+
+impl From<ConnectionAlreadyExists> for ErrorCode {
+    fn from(_: ConnectionAlreadyExists) -> Self {
+        ErrorCode::new(
+            ConnectionAlreadyExists::CODEPOINT,
+            "Connection Already Exists".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+/// `447`: "Connection Timeout or Failure".
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.3>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionTimeoutOrFailure;
+
+impl ConnectionTimeoutOrFailure {
+    pub const CODEPOINT: u16 = 447;
+}
+
+impl From<ConnectionTimeoutOrFailure> for ErrorCode {
+    fn from(_: ConnectionTimeoutOrFailure) -> Self {
+        ErrorCode::new(
+            ConnectionTimeoutOrFailure::CODEPOINT,
+            "Connection Timeout or Failure".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Attribute;
+    use bytecodec::{DecodeExt, EncodeExt};
+    use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+
+    #[test]
+    fn connection_id_roundtrips() {
+        let mut message = Message::<Attribute>::new(
+            MessageClass::Indication,
+            method(CONNECTION_ATTEMPT),
+            TransactionId::new([0; 12]),
+        );
+        message.add_attribute(ConnectionId::new(0xdeadbeef));
+
+        let bytes = MessageEncoder::new().encode_into_bytes(message).unwrap();
+        assert_eq!(
+            &bytes[20..],
+            [0x00, 0x2A, 0x00, 0x04, 0xde, 0xad, 0xbe, 0xef]
+        );
+
+        let message = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&bytes)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.method().as_u16(), CONNECTION_ATTEMPT);
+        assert_eq!(
+            message.get_attribute::<ConnectionId>(),
+            Some(&ConnectionId::new(0xdeadbeef))
+        );
+    }
+}
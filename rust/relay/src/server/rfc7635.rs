@@ -0,0 +1,206 @@
+//! Attributes defined in [RFC 7635](https://www.rfc-editor.org/rfc/rfc7635) that `stun_codec` doesn't support.
+
+use crate::auth::constant_time_eq;
+use crate::server::rfc8489::{impl_decode, impl_encode, message_into_bytes};
+use crate::Attribute as ServerAttribute;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode, TryTaggedDecode};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::io;
+use stun_codec::rfc5389::attributes::MessageIntegrity;
+use stun_codec::{Attribute, AttributeType, Message};
+
+/// The `ACCESS-TOKEN` attribute, carrying a self-contained token issued by a third-party authorization server.
+///
+/// The token contains the key for the message integrity of the request, see <https://www.rfc-editor.org/rfc/rfc7635#section-6.2>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AccessToken {
+    token: Vec<u8>,
+    /// The bytes covered by the `MESSAGE-INTEGRITY` attribute of the request, set in [`AccessToken::parse`].
+    message_integrity_input: Vec<u8>,
+}
+
+impl AccessToken {
+    pub const CODEPOINT: u16 = 0x001B;
+
+    pub fn new(token: Vec<u8>) -> Self {
+        Self {
+            token,
+            message_integrity_input: Vec::new(),
+        }
+    }
+
+    /// Extracts the access token of a request, remembering the bytes covered by its `MESSAGE-INTEGRITY` attribute.
+    ///
+    /// `stun_codec` doesn't expose these, so we re-encode all attributes preceding it.
+    /// Attributes we don't know about are thus not covered and the message integrity of requests that include them won't be valid.
+    pub fn parse(message: &Message<ServerAttribute>) -> Option<Self> {
+        let mut access_token = message.get_attribute::<AccessToken>()?.clone();
+
+        let mut preceding = Message::<ServerAttribute>::new(
+            message.class(),
+            message.method(),
+            message.transaction_id(),
+        );
+        for attribute in message
+            .attributes()
+            .take_while(|a| !matches!(a, ServerAttribute::MessageIntegrity(_)))
+        {
+            preceding.add_attribute(attribute.clone());
+        }
+        access_token.message_integrity_input = message_into_bytes(preceding, 20).ok()?;
+
+        Some(access_token)
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Checks whether the `MESSAGE-INTEGRITY` of the request is valid for the `mac_key` of this token.
+    pub fn check_message_integrity(
+        &self,
+        message_integrity: &MessageIntegrity,
+        key: &[u8],
+    ) -> bool {
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&self.message_integrity_input);
+
+        constant_time_eq(&message_integrity.hmac_sha1(), &mac.finalize().into_bytes())
+    }
+}
+
+impl Attribute for AccessToken {
+    type Decoder = AccessTokenDecoder;
+    type Encoder = AccessTokenEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The `THIRD-PARTY-AUTHORIZATION` attribute, telling clients which server name to obtain an access token for.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc7635#section-6.1>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ThirdPartyAuthorization(String);
+
+impl ThirdPartyAuthorization {
+    pub const CODEPOINT: u16 = 0x802E;
+
+    pub fn new(server_name: String) -> Self {
+        Self(server_name)
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Attribute for ThirdPartyAuthorization {
+    type Decoder = ThirdPartyAuthorizationDecoder;
+    type Encoder = ThirdPartyAuthorizationEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+impl_decode!(AccessTokenDecoder, AccessToken, |bytes: &Vec<u8>| Ok(
+    AccessToken::new(bytes.clone())
+));
+impl_encode!(AccessTokenEncoder, AccessToken, |item: AccessToken| item
+    .token);
+
+impl_decode!(
+    ThirdPartyAuthorizationDecoder,
+    ThirdPartyAuthorization,
+    |bytes: &Vec<u8>| {
+        String::from_utf8(bytes.clone())
+            .map(ThirdPartyAuthorization)
+            .map_err(|_| {
+                bytecodec::Error::from(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "THIRD-PARTY-AUTHORIZATION is not valid UTF-8",
+                ))
+            })
+    }
+);
+impl_encode!(
+    ThirdPartyAuthorizationEncoder,
+    ThirdPartyAuthorization,
+    |item: ThirdPartyAuthorization| item.0.into_bytes()
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::{DecodeExt, EncodeExt};
+    use stun_codec::rfc5389::attributes::{Nonce, Username};
+    use stun_codec::rfc5766::methods::ALLOCATE;
+    use stun_codec::{MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+
+    #[test]
+    fn message_integrity_is_checked_with_key_of_access_token() {
+        let key = [1u8; 32];
+
+        let mut message = Message::<ServerAttribute>::new(
+            MessageClass::Request,
+            ALLOCATE,
+            TransactionId::new([0u8; 12]),
+        );
+        message.add_attribute(Username::new("kid".to_owned()).unwrap());
+        message.add_attribute(Nonce::new("nonce".to_owned()).unwrap());
+        message.add_attribute(AccessToken::new(b"token".to_vec()));
+
+        let message = decode(&with_message_integrity(message, &key));
+        let access_token = AccessToken::parse(&message).unwrap();
+        let message_integrity = message.get_attribute::<MessageIntegrity>().unwrap();
+
+        assert_eq!(access_token.token(), b"token");
+        assert!(access_token.check_message_integrity(message_integrity, &key));
+        assert!(!access_token.check_message_integrity(message_integrity, &[2u8; 32]));
+    }
+
+    #[test]
+    fn third_party_authorization_roundtrip() {
+        let mut message = Message::<ServerAttribute>::new(
+            MessageClass::ErrorResponse,
+            ALLOCATE,
+            TransactionId::new([0u8; 12]),
+        );
+        message.add_attribute(ThirdPartyAuthorization::new("relay.example.com".to_owned()));
+
+        let message = decode(&MessageEncoder::new().encode_into_bytes(message).unwrap());
+
+        assert_eq!(
+            message
+                .get_attribute::<ThirdPartyAuthorization>()
+                .unwrap()
+                .server_name(),
+            "relay.example.com"
+        );
+    }
+
+    /// Appends a `MESSAGE-INTEGRITY` keyed by `key`, which `stun_codec` can't compute for us.
+    fn with_message_integrity(message: Message<ServerAttribute>, key: &[u8]) -> Vec<u8> {
+        let mut bytes = message_into_bytes(message, 20).unwrap();
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+        mac.update(&bytes);
+
+        bytes.extend_from_slice(&MessageIntegrity::CODEPOINT.to_be_bytes());
+        bytes.extend_from_slice(&20u16.to_be_bytes());
+        bytes.extend_from_slice(&mac.finalize().into_bytes());
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Message<ServerAttribute> {
+        MessageDecoder::<ServerAttribute>::new()
+            .decode_from_bytes(bytes)
+            .unwrap()
+            .unwrap()
+    }
+}
@@ -0,0 +1,440 @@
+//! Attributes defined in [RFC 8489](https://www.rfc-editor.org/rfc/rfc8489) that `stun_codec` doesn't support yet.
+
+use crate::auth::constant_time_eq;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::{ByteCount, Decode, Encode, EncodeExt, Eos, SizedEncode, TryTaggedDecode};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io;
+use stun_codec::rfc5389::attributes::{ErrorCode, Realm, Username};
+use stun_codec::rfc5389::errors::Unauthorized;
+use stun_codec::{Attribute, AttributeType, Message, MessageEncoder};
+
+/// The `MESSAGE-INTEGRITY-SHA256` attribute.
+///
+/// Like `MESSAGE-INTEGRITY` but uses HMAC-SHA256, optionally truncated to as few as 16 bytes.
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-14.6>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageIntegritySha256 {
+    hmac_sha256: Vec<u8>,
+    preceding_message_bytes: Vec<u8>,
+}
+
+impl MessageIntegritySha256 {
+    pub const CODEPOINT: u16 = 0x001C;
+
+    /// Computes the message integrity of `message` for the given long-term credentials.
+    pub fn new_long_term_credential<A>(
+        message: &Message<A>,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> bytecodec::Result<Self>
+    where
+        A: Attribute,
+    {
+        let preceding_message_bytes = message_into_bytes(message.clone(), 32)?;
+        let hmac_sha256 = hmac_sha256(
+            &algorithm.long_term_key(username, realm, password),
+            &preceding_message_bytes,
+        );
+
+        Ok(Self {
+            hmac_sha256,
+            preceding_message_bytes,
+        })
+    }
+
+    /// Checks whether this has the valid long-term credential for `password`.
+    pub fn check_long_term_credential(
+        &self,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> Result<(), ErrorCode> {
+        let expected = hmac_sha256(
+            &algorithm.long_term_key(username, realm, password),
+            &self.preceding_message_bytes,
+        );
+
+        // A truncated HMAC is valid as long as it is a prefix of the full one.
+        if constant_time_eq(&self.hmac_sha256, &expected[..self.hmac_sha256.len()]) {
+            Ok(())
+        } else {
+            Err(Unauthorized.into())
+        }
+    }
+
+    /// Computes the message integrity of `message` for the given key, as used by access tokens.
+    pub fn new_with_key<A>(message: &Message<A>, key: &[u8]) -> bytecodec::Result<Self>
+    where
+        A: Attribute,
+    {
+        let preceding_message_bytes = message_into_bytes(message.clone(), 32)?;
+        let hmac_sha256 = hmac_sha256(key, &preceding_message_bytes);
+
+        Ok(Self {
+            hmac_sha256,
+            preceding_message_bytes,
+        })
+    }
+
+    /// Checks whether this is valid for the given key, as used by access tokens.
+    pub fn check_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        let expected = hmac_sha256(key, &self.preceding_message_bytes);
+
+        if constant_time_eq(&self.hmac_sha256, &expected[..self.hmac_sha256.len()]) {
+            Ok(())
+        } else {
+            Err(Unauthorized.into())
+        }
+    }
+}
+
+impl Attribute for MessageIntegritySha256 {
+    type Decoder = MessageIntegritySha256Decoder;
+    type Encoder = MessageIntegritySha256Encoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+
+    fn after_decode<A: Attribute>(&mut self, message: &Message<A>) -> bytecodec::Result<()> {
+        self.preceding_message_bytes = message_into_bytes(message.clone(), self.hmac_sha256.len())?;
+
+        Ok(())
+    }
+}
+
+/// A password algorithm, also used as the `PASSWORD-ALGORITHM` attribute.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-14.12>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PasswordAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl PasswordAlgorithm {
+    pub const CODEPOINT: u16 = 0x001D;
+
+    const MD5: u16 = 0x0001;
+    const SHA256: u16 = 0x0002;
+
+    /// The key for long-term credentials, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.2>.
+    fn long_term_key(&self, username: &Username, realm: &Realm, password: &str) -> Vec<u8> {
+        let input = format!("{}:{}:{}", username.name(), realm.text(), password);
+
+        match self {
+            PasswordAlgorithm::Md5 => md5::compute(input).0.to_vec(),
+            PasswordAlgorithm::Sha256 => Sha256::digest(input).to_vec(),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 4] {
+        let algorithm = match self {
+            PasswordAlgorithm::Md5 => Self::MD5,
+            PasswordAlgorithm::Sha256 => Self::SHA256,
+        };
+        let [a, b] = algorithm.to_be_bytes();
+
+        // Neither of the algorithms has parameters.
+        [a, b, 0, 0]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> bytecodec::Result<Self> {
+        let [a, b, 0, 0] = bytes else {
+            return Err(invalid_input(
+                "password algorithms without parameters are 4 bytes long",
+            ));
+        };
+
+        match u16::from_be_bytes([*a, *b]) {
+            Self::MD5 => Ok(PasswordAlgorithm::Md5),
+            Self::SHA256 => Ok(PasswordAlgorithm::Sha256),
+            other => Err(invalid_input(&format!(
+                "unknown password algorithm {other}"
+            ))),
+        }
+    }
+}
+
+impl Attribute for PasswordAlgorithm {
+    type Decoder = PasswordAlgorithmDecoder;
+    type Encoder = PasswordAlgorithmEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The `PASSWORD-ALGORITHMS` attribute, listing the password algorithms a server supports.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-14.11>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PasswordAlgorithms(Vec<PasswordAlgorithm>);
+
+impl PasswordAlgorithms {
+    pub const CODEPOINT: u16 = 0x8002;
+
+    pub fn new(algorithms: Vec<PasswordAlgorithm>) -> Self {
+        Self(algorithms)
+    }
+
+    pub fn algorithms(&self) -> &[PasswordAlgorithm] {
+        &self.0
+    }
+}
+
+impl Attribute for PasswordAlgorithms {
+    type Decoder = PasswordAlgorithmsDecoder;
+    type Encoder = PasswordAlgorithmsEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// Decodes the value of an attribute from all its bytes.
+macro_rules! impl_decode {
+    ($decoder:ident, $item:ident, $from_bytes:expr) => {
+        #[derive(Debug, Default)]
+        pub struct $decoder(RemainingBytesDecoder);
+
+        impl Decode for $decoder {
+            type Item = $item;
+
+            fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+                self.0.decode(buf, eos)
+            }
+
+            #[allow(clippy::redundant_closure_call)]
+            fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+                let bytes = self.0.finish_decoding()?;
+
+                $from_bytes(&bytes)
+            }
+
+            fn requiring_bytes(&self) -> ByteCount {
+                self.0.requiring_bytes()
+            }
+
+            fn is_idle(&self) -> bool {
+                self.0.is_idle()
+            }
+        }
+
+        impl TryTaggedDecode for $decoder {
+            type Tag = AttributeType;
+
+            fn try_start_decoding(&mut self, attr_type: Self::Tag) -> bytecodec::Result<bool> {
+                Ok(attr_type.as_u16() == $item::CODEPOINT)
+            }
+        }
+    };
+}
+
+/// Encodes the value of an attribute as bytes.
+macro_rules! impl_encode {
+    ($encoder:ident, $item:ident, $to_bytes:expr) => {
+        #[derive(Debug, Default)]
+        pub struct $encoder(BytesEncoder<Vec<u8>>);
+
+        impl Encode for $encoder {
+            type Item = $item;
+
+            fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+                self.0.encode(buf, eos)
+            }
+
+            #[allow(clippy::redundant_closure_call)]
+            fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+                self.0.start_encoding($to_bytes(item))
+            }
+
+            fn requiring_bytes(&self) -> ByteCount {
+                self.0.requiring_bytes()
+            }
+
+            fn is_idle(&self) -> bool {
+                self.0.is_idle()
+            }
+        }
+
+        impl SizedEncode for $encoder {
+            fn exact_requiring_bytes(&self) -> u64 {
+                self.0.exact_requiring_bytes()
+            }
+        }
+    };
+}
+
+pub(crate) use {impl_decode, impl_encode};
+
+impl_decode!(
+    MessageIntegritySha256Decoder,
+    MessageIntegritySha256,
+    |bytes: &Vec<u8>| {
+        // The HMAC may be truncated to a multiple of 4 bytes but must be at least 16 bytes long.
+        if !(16..=32).contains(&bytes.len()) || bytes.len() % 4 != 0 {
+            return Err(invalid_input("invalid length of MESSAGE-INTEGRITY-SHA256"));
+        }
+
+        Ok(MessageIntegritySha256 {
+            hmac_sha256: bytes.clone(),
+            preceding_message_bytes: Vec::new(), // Set in `after_decode`.
+        })
+    }
+);
+impl_encode!(
+    MessageIntegritySha256Encoder,
+    MessageIntegritySha256,
+    |item: MessageIntegritySha256| item.hmac_sha256
+);
+
+impl_decode!(
+    PasswordAlgorithmDecoder,
+    PasswordAlgorithm,
+    |bytes: &Vec<u8>| PasswordAlgorithm::from_bytes(bytes)
+);
+impl_encode!(
+    PasswordAlgorithmEncoder,
+    PasswordAlgorithm,
+    |item: PasswordAlgorithm| item.to_bytes().to_vec()
+);
+
+impl_decode!(
+    PasswordAlgorithmsDecoder,
+    PasswordAlgorithms,
+    |bytes: &Vec<u8>| {
+        bytes
+            .chunks(4)
+            .map(PasswordAlgorithm::from_bytes)
+            .collect::<bytecodec::Result<Vec<_>>>()
+            .map(PasswordAlgorithms)
+    }
+);
+impl_encode!(
+    PasswordAlgorithmsEncoder,
+    PasswordAlgorithms,
+    |item: PasswordAlgorithms| item
+        .0
+        .into_iter()
+        .flat_map(PasswordAlgorithm::to_bytes)
+        .collect()
+);
+
+/// Encodes the message preceding the message integrity attribute, adjusting the length in the header to include the attribute, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.6>.
+pub(crate) fn message_into_bytes<A: Attribute>(
+    message: Message<A>,
+    hmac_len: usize,
+) -> bytecodec::Result<Vec<u8>> {
+    let mut bytes = MessageEncoder::default().encode_into_bytes(message)?;
+    let adjusted_len = (bytes.len() - 20 + 4 + hmac_len) as u16; // Without the message header but with the attribute header.
+
+    bytes[2..4].copy_from_slice(&adjusted_len.to_be_bytes());
+
+    Ok(bytes)
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn invalid_input(msg: &str) -> bytecodec::Error {
+    bytecodec::Error::from(io::Error::new(io::ErrorKind::InvalidData, msg.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Attribute;
+    use bytecodec::DecodeExt;
+    use stun_codec::rfc5389::methods::BINDING;
+    use stun_codec::{MessageClass, MessageDecoder, TransactionId};
+
+    #[test]
+    fn message_integrity_sha256_roundtrip() {
+        let username = Username::new("user".to_owned()).unwrap();
+        let realm = Realm::new("firezone".to_owned()).unwrap();
+
+        let mut message = sample_message();
+        message.add_attribute(username.clone());
+        let message_integrity = MessageIntegritySha256::new_long_term_credential(
+            &message,
+            &username,
+            &realm,
+            "password",
+            PasswordAlgorithm::Sha256,
+        )
+        .unwrap();
+        message.add_attribute(message_integrity);
+
+        let message = roundtrip(message);
+        let message_integrity = message.get_attribute::<MessageIntegritySha256>().unwrap();
+
+        message_integrity
+            .check_long_term_credential(&username, &realm, "password", PasswordAlgorithm::Sha256)
+            .unwrap();
+        message_integrity
+            .check_long_term_credential(&username, &realm, "wrong", PasswordAlgorithm::Sha256)
+            .unwrap_err();
+        message_integrity
+            .check_long_term_credential(&username, &realm, "password", PasswordAlgorithm::Md5)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn password_algorithms_roundtrip() {
+        let algorithms =
+            PasswordAlgorithms::new(vec![PasswordAlgorithm::Md5, PasswordAlgorithm::Sha256]);
+
+        let mut message = sample_message();
+        message.add_attribute(algorithms.clone());
+        message.add_attribute(PasswordAlgorithm::Sha256);
+
+        let message = roundtrip(message);
+
+        assert_eq!(
+            message.get_attribute::<PasswordAlgorithms>(),
+            Some(&algorithms)
+        );
+        assert_eq!(
+            message.get_attribute::<PasswordAlgorithm>(),
+            Some(&PasswordAlgorithm::Sha256)
+        );
+    }
+
+    #[test]
+    fn unknown_password_algorithm_fails_to_decode() {
+        let bytes = hex_literal::hex!("000100082112a442000000000000000000000000001d000400030000");
+
+        let message = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&bytes)
+            .unwrap();
+
+        assert!(message.is_err());
+    }
+
+    fn roundtrip(message: Message<Attribute>) -> Message<Attribute> {
+        let bytes = MessageEncoder::new().encode_into_bytes(message).unwrap();
+
+        MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&bytes)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn sample_message() -> Message<Attribute> {
+        Message::new(
+            MessageClass::Request,
+            BINDING,
+            TransactionId::new([0u8; 12]),
+        )
+    }
+}
@@ -0,0 +1,176 @@
+use crate::net_ext::IpAddrExt;
+use crate::server::{
+    channels_of, Allocation, AllocationId, AllocationUsage, Channel, Command, DeletionReason,
+    Event, Server, TimedAction, Transport, CHANNEL_REUSE_TIMEOUT,
+};
+use rand::Rng;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// A snapshot of the state of a [`Server`].
+///
+/// Restoring a snapshot via [`Server::restore`] allows allocations, channel bindings and credentials to survive a restart of the relay.
+/// Snapshots contain the secrets used to authenticate clients and should be treated as such.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    auth_secret: String,
+    /// Previous auth secrets together with the time until which we accept them.
+    #[serde(default)]
+    previous_auth_secrets: Vec<(String, SystemTime)>,
+    nonce_secret: String,
+    next_allocation_id: AllocationId,
+    allocations: Vec<(SocketAddr, Allocation)>,
+    channels: Vec<((AllocationId, u16), Channel)>,
+}
+
+impl<R> Server<R>
+where
+    R: Rng,
+{
+    /// Takes a snapshot of all state that is needed to continue serving existing allocations.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            auth_secret: self.auth_secrets.current().expose_secret().clone(),
+            previous_auth_secrets: self
+                .auth_secrets
+                .previous()
+                .map(|(secret, valid_until)| (secret.expose_secret().clone(), valid_until))
+                .collect(),
+            nonce_secret: self.nonces.secret().expose_secret().clone(),
+            next_allocation_id: self.next_allocation_id,
+            // The peer connections of TCP allocations cannot survive a restart, thus neither can the allocations.
+            allocations: self
+                .allocations
+                .iter()
+                .filter(|(_, allocation)| allocation.transport == Transport::Udp)
+                .map(|(client, allocation)| (*client, allocation.clone()))
+                .collect(),
+            channels: self
+                .channels_by_allocation
+                .iter()
+                .map(|(key, channel)| (*key, channel.clone()))
+                .collect(),
+        }
+    }
+
+    /// Restores the state from the given [`Snapshot`].
+    ///
+    /// This must be called before handling any input.
+    /// Allocations that expired in the meantime, including those that expire right `now`, are discarded and reported via [`Event::AllocationDeleted`].
+    /// For all others, this emits [`Command::CreateAllocation`] to re-bind their ports as well as a [`Command::Wake`] for the next timed event.
+    pub fn restore(&mut self, snapshot: Snapshot, now: SystemTime) {
+        debug_assert!(
+            self.allocations.is_empty(),
+            "must restore into a fresh server"
+        );
+
+        self.auth_secrets
+            .set_current(SecretString::new(snapshot.auth_secret));
+        for (secret, valid_until) in snapshot.previous_auth_secrets {
+            if valid_until >= now {
+                self.auth_secrets
+                    .add_previous(SecretString::new(secret), valid_until);
+            }
+        }
+        self.nonces
+            .set_secret(SecretString::new(snapshot.nonce_secret));
+
+        self.next_allocation_id = snapshot.next_allocation_id;
+
+        let mut wake_deadline = None;
+
+        for (client, allocation) in snapshot.allocations {
+            if allocation.is_expired(now) {
+                let channels = channels_of(
+                    snapshot
+                        .channels
+                        .iter()
+                        .map(|(key, channel)| (key, channel)),
+                    allocation.id,
+                )
+                .into_iter()
+                .map(|(number, channel)| channel.usage(number))
+                .collect();
+
+                tracing::info!(target: "relay", allocation = %allocation.id, username = %allocation.username, "Discarding allocation that expired while we were down");
+
+                self.pending_events.push_back(Event::AllocationDeleted {
+                    relay_addresses: allocation.relay_addresses(),
+                    usage: AllocationUsage {
+                        id: allocation.id,
+                        client,
+                        username: allocation.username,
+                        traffic: allocation.traffic,
+                        channels,
+                    },
+                    reason: DeletionReason::Expired,
+                });
+                continue;
+            }
+
+            wake_deadline = Some(self.time_events.add(
+                allocation.expires_at,
+                TimedAction::ExpireAllocation(allocation.id),
+            ));
+
+            for family in [
+                Some(allocation.first_relay_addr),
+                allocation.second_relay_addr,
+            ]
+            .into_iter()
+            .flatten()
+            .map(|addr| addr.family())
+            {
+                self.pending_commands.push_back(Command::CreateAllocation {
+                    id: allocation.id,
+                    family,
+                    port: allocation.port,
+                    dont_fragment: allocation.dont_fragment,
+                });
+            }
+
+            self.allocations_by_port
+                .insert(allocation.port, allocation.id);
+            self.clients_by_allocation.insert(allocation.id, client);
+            self.allocations.insert(client, allocation);
+            self.allocations_up_down_counter.add(1, &[]);
+        }
+
+        for ((id, number), channel) in snapshot.channels {
+            if !self.clients_by_allocation.contains_key(&id) {
+                continue;
+            }
+
+            let (trigger, action) = if channel.bound {
+                self.channel_bindings_up_down_counter.add(1, &[]);
+
+                (channel.expiry, TimedAction::UnbindChannel(id, number))
+            } else {
+                // Unbound channels are deleted once nobody can mistake them for the old binding anymore.
+                (
+                    channel.expiry + CHANNEL_REUSE_TIMEOUT,
+                    TimedAction::DeleteChannel(id, number),
+                )
+            };
+
+            wake_deadline = Some(self.time_events.add(trigger, action));
+
+            self.channel_numbers_by_peer
+                .insert((id, channel.peer_address), number);
+            self.channels_by_allocation.insert((id, number), channel);
+        }
+
+        if let Some(deadline) = wake_deadline {
+            self.pending_commands.push_back(Command::Wake { deadline });
+        }
+
+        tracing::info!(
+            target: "relay",
+            allocations = self.allocations.len(),
+            channels = self.channels_by_allocation.len(),
+            "Restored state from snapshot"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::server::AllocationId;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// The data relayed through an allocation or channel, in both directions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Traffic {
+    /// The number of bytes relayed from the client to its peers.
+    pub bytes_to_peer: u64,
+    /// The number of packets relayed from the client to its peers.
+    pub packets_to_peer: u64,
+    /// The number of bytes relayed from peers to the client.
+    pub bytes_to_client: u64,
+    /// The number of packets relayed from peers to the client.
+    pub packets_to_client: u64,
+}
+
+/// The data relayed over the lifetime of an allocation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationUsage {
+    pub id: AllocationId,
+    pub client: SocketAddr,
+    pub username: String,
+    /// The traffic of the allocation as a whole, including data relayed via channels that have since been deleted.
+    pub traffic: Traffic,
+    /// The traffic of all channels that still existed when the allocation was deleted.
+    pub channels: Vec<ChannelUsage>,
+}
+
+/// The data relayed via a single channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelUsage {
+    pub number: u16,
+    pub peer: SocketAddr,
+    pub traffic: Traffic,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    ToPeer,
+    ToClient,
+}
+
+impl Direction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Direction::ToPeer => "to_peer",
+            Direction::ToClient => "to_client",
+        }
+    }
+}
+
+impl Traffic {
+    pub(crate) fn record(&mut self, direction: Direction, bytes: usize) {
+        let (total_bytes, packets) = match direction {
+            Direction::ToPeer => (&mut self.bytes_to_peer, &mut self.packets_to_peer),
+            Direction::ToClient => (&mut self.bytes_to_client, &mut self.packets_to_client),
+        };
+
+        *total_bytes += bytes as u64;
+        *packets += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_bytes_and_packets_per_direction() {
+        let mut traffic = Traffic::default();
+
+        traffic.record(Direction::ToPeer, 100);
+        traffic.record(Direction::ToPeer, 50);
+        traffic.record(Direction::ToClient, 1000);
+
+        assert_eq!(
+            traffic,
+            Traffic {
+                bytes_to_peer: 150,
+                packets_to_peer: 2,
+                bytes_to_client: 1000,
+                packets_to_client: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use anyhow::{anyhow, Context as _, Result};
+use bytes::BytesMut;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+/// The length of a STUN message header.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-5>.
+const STUN_HEADER_LEN: usize = 20;
+
+/// The length of a channel data message header.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-the-channeldata-message>.
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// The maximum amount of items that can be buffered in the channel to a stream connection.
+const MAX_BUFFERED_ITEMS: usize = 10;
+
+/// Events emitted by the tasks that handle clients connected over a stream-oriented transport (TCP or TLS).
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// A new client connected.
+    ///
+    /// All messages for this client must be sent via the provided `outbound` channel.
+    Connected {
+        peer: SocketAddr,
+        outbound: mpsc::Sender<Outbound>,
+    },
+    /// A client sent a complete STUN message or channel data message.
+    Data { peer: SocketAddr, frame: Vec<u8> },
+    /// The connection to a client has been closed.
+    Disconnected { peer: SocketAddr },
+}
+
+/// Instructions for the task handling a client connected over a stream-oriented transport.
+#[derive(Debug)]
+pub enum Outbound {
+    /// Send a STUN message or channel data message to the client.
+    Frame(Vec<u8>),
+    /// Send a final STUN message to the client and from then on, relay all data between the client and the given peer connection as is.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.4>.
+    Splice { response: Vec<u8>, peer: TcpStream },
+}
+
+/// Split off the next complete frame from the given buffer.
+///
+/// Stream-oriented transports don't preserve message boundaries.
+/// Thus, we need to look at the header of each message to find out how many bytes belong to it.
+/// As per <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>, channel data messages are padded to a multiple of 4 bytes over TCP and TLS.
+///
+/// Returns `Ok(None)` if the buffer does not contain a complete frame yet.
+pub fn decode_frame(buffer: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+    let Some(first_byte) = buffer.first() else {
+        return Ok(None);
+    };
+
+    let frame_len = match first_byte {
+        0..=3 => {
+            let Some(length) = length_field(buffer) else {
+                return Ok(None);
+            };
+
+            STUN_HEADER_LEN + length
+        }
+        64..=79 => {
+            let Some(length) = length_field(buffer) else {
+                return Ok(None);
+            };
+
+            CHANNEL_DATA_HEADER_LEN + padded_len(length)
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type {other}"),
+            ))
+        }
+    };
+
+    if buffer.len() < frame_len {
+        buffer.reserve(frame_len - buffer.len());
+
+        return Ok(None);
+    }
+
+    Ok(Some(buffer.split_to(frame_len)))
+}
+
+/// Prepare a message produced by the [`Server`](crate::Server) for sending over a stream-oriented transport.
+///
+/// STUN messages are always a multiple of 4 bytes, channel data messages need to be padded.
+pub fn encode_frame(mut payload: Vec<u8>) -> Vec<u8> {
+    if matches!(payload.first(), Some(64..=79)) {
+        let len = payload.len();
+
+        payload.resize(
+            CHANNEL_DATA_HEADER_LEN + padded_len(len - CHANNEL_DATA_HEADER_LEN),
+            0,
+        );
+    }
+
+    payload
+}
+
+/// Creates a [`TlsAcceptor`] from PEM-encoded certificate chain and private key files.
+pub fn make_tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file).with_context(|| format!("Failed to open {}", cert_file.display()))?,
+    ))
+    .context("Failed to parse certificate chain")?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect::<Vec<_>>();
+
+    let mut key_reader = BufReader::new(
+        File::open(key_file).with_context(|| format!("Failed to open {}", key_file.display()))?,
+    );
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader).context("Failed to parse private key")? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key in {}", key_file.display())),
+        }
+    };
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accept clients on a TCP listener, optionally wrapping each connection in TLS.
+///
+/// Every accepted connection is handled in its own task and reports its progress via `events`.
+pub async fn listen(
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    events: mpsc::Sender<StreamEvent>,
+) -> Result<Infallible> {
+    let listener =
+        make_listener(addr).with_context(|| format!("Failed to bind TCP listener to {addr}"))?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let events = events.clone();
+
+        match tls.clone() {
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            tracing::debug!(%peer, "TLS handshake failed: {e}");
+                            return;
+                        }
+                    };
+
+                    handle_connection(stream, peer, events).await
+                });
+            }
+            None => {
+                tokio::spawn(handle_connection(stream, peer, events));
+            }
+        }
+    }
+}
+
+async fn handle_connection<S>(stream: S, peer: SocketAddr, mut events: mpsc::Sender<StreamEvent>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (outbound, outbound_receiver) = mpsc::channel(MAX_BUFFERED_ITEMS);
+
+    if events
+        .send(StreamEvent::Connected { peer, outbound })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    if let Err(e) = forward_frames(stream, peer, &mut events, outbound_receiver).await {
+        tracing::debug!(%peer, "Stream connection failed: {e:#}");
+    }
+
+    let _ = events.send(StreamEvent::Disconnected { peer }).await;
+}
+
+async fn forward_frames<S>(
+    stream: S,
+    peer: SocketAddr,
+    events: &mut mpsc::Sender<StreamEvent>,
+    mut outbound_receiver: mpsc::Receiver<Outbound>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = BytesMut::with_capacity(STUN_HEADER_LEN);
+
+    loop {
+        tokio::select! {
+            result = reader.read_buf(&mut buffer) => {
+                if result? == 0 {
+                    return Ok(());
+                }
+
+                while let Some(frame) = decode_frame(&mut buffer)? {
+                    events.send(StreamEvent::Data { peer, frame: frame.to_vec() }).await?;
+                }
+            }
+            maybe_item = outbound_receiver.next() => {
+                match maybe_item {
+                    Some(Outbound::Frame(payload)) => {
+                        writer.write_all(&encode_frame(payload)).await?;
+                    }
+                    Some(Outbound::Splice { response, peer }) => {
+                        writer.write_all(&response).await?;
+
+                        return splice(reader.unsplit(writer), buffer, peer, outbound_receiver).await;
+                    }
+                    None => return Ok(()), // The event loop dropped the connection.
+                }
+            }
+        }
+    }
+}
+
+/// Relay all data between a client and a peer until either side closes its connection or the event loop drops it.
+async fn splice<S>(
+    mut stream: S,
+    buffered: BytesMut,
+    mut peer: TcpStream,
+    mut outbound_receiver: mpsc::Receiver<Outbound>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Anything the client sent after its CONNECTION-BIND request is already meant for the peer.
+    peer.write_all(&buffered).await?;
+
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut stream, &mut peer) => {
+            result?;
+        }
+        // Nothing but closing the connection makes sense anymore, thus we ignore all other instructions.
+        _ = async { while outbound_receiver.next().await.is_some() {} } => {}
+    }
+
+    Ok(())
+}
+
+fn length_field(buffer: &[u8]) -> Option<usize> {
+    let length = buffer.get(2..4)?;
+
+    Some(u16::from_be_bytes([length[0], length[1]]) as usize)
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Creates a [`TcpListener`] via the [socket2] library that is configured for our needs.
+///
+/// Similar to the UDP sockets, this sets the `IPV6_V6ONLY` flag to allow binding to IP4 and IP6 addresses on the same port.
+fn make_listener(addr: SocketAddr) -> Result<TcpListener> {
+    use socket2::*;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_stun_header_yields_nothing() {
+        let mut buffer = BytesMut::from(&[0x00, 0x01, 0x00][..]);
+
+        assert_eq!(decode_frame(&mut buffer).unwrap(), None);
+        assert_eq!(buffer.len(), 3);
+    }
+
+    #[test]
+    fn splits_consecutive_stun_messages() {
+        let mut message = vec![0x00, 0x01, 0x00, 0x04];
+        message.extend_from_slice(&[0u8; 16]); // Remaining header.
+        message.extend_from_slice(&[1, 2, 3, 4]); // Attributes.
+
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&message);
+        buffer.extend_from_slice(&message[..10]);
+
+        assert_eq!(
+            decode_frame(&mut buffer).unwrap().as_deref(),
+            Some(message.as_slice())
+        );
+        assert_eq!(decode_frame(&mut buffer).unwrap(), None);
+        assert_eq!(buffer.len(), 10);
+    }
+
+    #[test]
+    fn channel_data_frames_include_padding() {
+        let mut buffer = BytesMut::from(&[0x40, 0x00, 0x00, 0x03, 1, 2, 3, 0, 0x40][..]);
+
+        assert_eq!(
+            decode_frame(&mut buffer).unwrap().as_deref(),
+            Some([0x40, 0x00, 0x00, 0x03, 1, 2, 3, 0].as_slice())
+        );
+        assert_eq!(buffer.as_ref(), &[0x40]);
+    }
+
+    #[test]
+    fn unknown_message_type_is_an_error() {
+        let mut buffer = BytesMut::from(&[0x80, 0x00, 0x00, 0x00][..]);
+
+        assert!(decode_frame(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn encoding_pads_channel_data() {
+        assert_eq!(
+            encode_frame(vec![0x40, 0x00, 0x00, 0x01, 0xFF]),
+            vec![0x40, 0x00, 0x00, 0x01, 0xFF, 0, 0, 0]
+        );
+        assert_eq!(
+            encode_frame(vec![0x40, 0x00, 0x00, 0x04, 1, 2, 3, 4]),
+            vec![0x40, 0x00, 0x00, 0x04, 1, 2, 3, 4]
+        );
+    }
+}
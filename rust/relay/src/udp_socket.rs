@@ -1,84 +1,427 @@
-use crate::AddressFamily;
 use anyhow::{Context as _, Result};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::task::{ready, Context, Poll};
-use tokio::io::ReadBuf;
+use socket2::SockAddr;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, RawFd};
+use tokio::io::Interest;
 
+/// The maximum number of datagrams we receive or send with a single syscall.
+pub const BATCH_SIZE: usize = 16;
+
+/// The size of each slot of our receive buffer, large enough for any UDP datagram.
+///
+/// Together with [`BATCH_SIZE`], this results in a 1 MiB receive buffer per socket.
 const MAX_UDP_SIZE: usize = 65536;
 
-/// A thin wrapper around [`tokio::net::UdpSocket`] that provides a slightly more convenient API.
+/// A thin wrapper around [`tokio::net::UdpSocket`] that receives and sends datagrams in batches.
+///
+/// On Linux, each batch only costs a single `recvmmsg` / `sendmmsg` syscall.
+/// Elsewhere, we fall back to one `recvfrom` / `sendto` syscall per datagram.
 pub struct UdpSocket {
     inner: tokio::net::UdpSocket,
-    recv_buf: [u8; MAX_UDP_SIZE],
+    recv_buf: Box<[u8]>,
 }
 
 impl UdpSocket {
-    pub fn bind(family: AddressFamily, port: u16) -> Result<Self> {
-        let std_socket = make_wildcard_socket(family, port)
-            .with_context(|| format!("Failed to bind UDP socket for {family}"))?;
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        Self::new(addr, false)
+    }
+
+    /// Binds to an address that other sockets of this process are already bound to.
+    ///
+    /// The kernel load-balances incoming datagrams across all of these sockets based on the sender's address.
+    pub fn bind_reuse_port(addr: SocketAddr) -> Result<Self> {
+        Self::new(addr, true)
+    }
+
+    fn new(addr: SocketAddr, reuse_port: bool) -> Result<Self> {
+        let std_socket = make_socket(addr, reuse_port)
+            .with_context(|| format!("Failed to bind UDP socket to {addr}"))?;
 
         Ok(Self {
             inner: tokio::net::UdpSocket::from_std(std_socket)?,
-            recv_buf: [0u8; MAX_UDP_SIZE],
+            recv_buf: vec![0u8; BATCH_SIZE * MAX_UDP_SIZE].into_boxed_slice(),
         })
     }
 
-    pub async fn recv(&mut self) -> Result<(&[u8], SocketAddr)> {
-        let (length, sender) = self.inner.recv_from(&mut self.recv_buf).await?;
+    /// Sets the DF bit on all outgoing IPv4 datagrams and disables fragmentation of outgoing IPv6 datagrams.
+    ///
+    /// Datagrams exceeding the path MTU are dropped instead.
+    pub fn set_dont_fragment(&self) -> Result<()> {
+        let fd = self.inner.as_raw_fd();
+
+        if self.local_addr()?.is_ipv4() {
+            #[cfg(target_os = "linux")]
+            let result = set_int_option(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            );
+            #[cfg(not(target_os = "linux"))]
+            let result = set_int_option(fd, libc::IPPROTO_IP, libc::IP_DONTFRAG, 1);
+
+            result
+        } else {
+            set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1)
+        }
+        .context("Failed to disable fragmentation")
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.local_addr()?)
+    }
+
+    /// Receives at least one and up to [`BATCH_SIZE`] datagrams.
+    ///
+    /// This is cancel-safe.
+    pub async fn recv_batch(&mut self) -> Result<DatagramBatch> {
+        let fd = self.inner.as_raw_fd();
+
+        loop {
+            self.inner.readable().await?;
+
+            let batch = match self.inner.try_io(Interest::READABLE, || {
+                recv_datagrams(fd, &mut self.recv_buf)
+            }) {
+                Ok(batch) => batch,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            };
 
-        Ok((&self.recv_buf[..length], sender))
+            if batch.is_empty() {
+                continue; // Everything we received was truncated.
+            }
+
+            return Ok(batch);
+        }
     }
 
-    pub async fn send_to(&mut self, buf: &[u8], target: SocketAddr) -> Result<()> {
-        self.inner.send_to(buf, target).await?;
+    /// Sends all given datagrams, using as few syscalls as possible.
+    pub async fn send_batch(&mut self, datagrams: &[(Vec<u8>, SocketAddr)]) -> Result<()> {
+        let fd = self.inner.as_raw_fd();
+        let mut remaining = datagrams;
+
+        while !remaining.is_empty() {
+            self.inner.writable().await?;
+
+            match self
+                .inner
+                .try_io(Interest::WRITABLE, || send_datagrams(fd, remaining))
+            {
+                Ok(sent) => remaining = &remaining[sent..],
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
 
         Ok(())
     }
+}
+
+/// Datagrams received with a single syscall.
+///
+/// To avoid an allocation per datagram, their payloads are stored back-to-back in a single buffer.
+#[derive(Debug, Default)]
+pub struct DatagramBatch {
+    buffer: Vec<u8>,
+    datagrams: Vec<(Range<usize>, SocketAddr)>,
+}
 
-    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<(ReadBuf<'_>, SocketAddr)>> {
-        let mut buffer = ReadBuf::new(&mut self.recv_buf);
-        let sender = ready!(self.inner.poll_recv_from(cx, &mut buffer))?;
+impl DatagramBatch {
+    fn push(&mut self, data: &[u8], sender: SocketAddr) {
+        let start = self.buffer.len();
+        self.buffer.extend_from_slice(data);
 
-        Poll::Ready(Ok((buffer, sender)))
+        self.datagrams.push((start..self.buffer.len(), sender));
     }
 
-    pub fn try_send_to(
-        &mut self,
-        buf: &[u8],
-        target: SocketAddr,
-        cx: &mut Context<'_>,
-    ) -> Poll<Result<()>> {
-        ready!(self.inner.poll_send_ready(cx)?);
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+        self.datagrams
+            .iter()
+            .map(|(range, sender)| (&self.buffer[range.clone()], *sender))
+    }
 
-        let sent_bytes = self.inner.try_send_to(buf, target)?;
-        debug_assert_eq!(sent_bytes, buf.len());
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
 
-        Poll::Ready(Ok(()))
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
     }
 }
 
+#[cfg(target_os = "linux")]
+fn recv_datagrams(fd: RawFd, buffer: &mut [u8]) -> io::Result<DatagramBatch> {
+    // SAFETY: All of these are plain C structs for which all zeroes is a valid value.
+    let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    for (iovec, slot) in iovecs.iter_mut().zip(buffer.chunks_exact_mut(MAX_UDP_SIZE)) {
+        iovec.iov_base = slot.as_mut_ptr().cast();
+        iovec.iov_len = slot.len();
+    }
+    for ((header, addr), iovec) in headers.iter_mut().zip(&mut addrs).zip(&mut iovecs) {
+        header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+        header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        header.msg_hdr.msg_iov = iovec;
+        header.msg_hdr.msg_iovlen = 1;
+    }
+
+    // SAFETY: Every header points to a valid address storage and a distinct slot of `buffer`, all of which outlive the syscall.
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            BATCH_SIZE as _,
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut batch = DatagramBatch::default();
+
+    for (index, header) in headers.iter().take(received as usize).enumerate() {
+        let len = header.msg_len as usize;
+
+        if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            tracing::debug!("Dropping datagram larger than {MAX_UDP_SIZE} bytes");
+            continue;
+        }
+
+        // SAFETY: The kernel initialised the address storage and set its length.
+        let sender = unsafe { SockAddr::new(addrs[index], header.msg_hdr.msg_namelen) };
+        let Some(sender) = sender.as_socket() else {
+            continue;
+        };
+
+        batch.push(&buffer[index * MAX_UDP_SIZE..][..len], sender);
+    }
+
+    Ok(batch)
+}
+
+/// Sends up to [`BATCH_SIZE`] of the given datagrams, returning how many were sent.
+#[cfg(target_os = "linux")]
+fn send_datagrams(fd: RawFd, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+    let datagrams = &datagrams[..datagrams.len().min(BATCH_SIZE)];
+
+    // SAFETY: All of these are plain C structs for which all zeroes is a valid value.
+    let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut addr_lens: [libc::socklen_t; BATCH_SIZE] = [0; BATCH_SIZE];
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    for (index, (data, recipient)) in datagrams.iter().enumerate() {
+        let recipient = SockAddr::from(*recipient);
+
+        addr_lens[index] = recipient.len();
+        addrs[index] = recipient.as_storage();
+        iovecs[index].iov_base = data.as_ptr() as *mut libc::c_void;
+        iovecs[index].iov_len = data.len();
+    }
+    for (((header, addr), addr_len), iovec) in headers
+        .iter_mut()
+        .zip(&mut addrs)
+        .zip(addr_lens)
+        .zip(&mut iovecs)
+    {
+        header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+        header.msg_hdr.msg_namelen = addr_len;
+        header.msg_hdr.msg_iov = iovec;
+        header.msg_hdr.msg_iovlen = 1;
+    }
+
+    // SAFETY: The first `datagrams.len()` headers point to valid addresses and payloads, all of which outlive the syscall.
+    let sent = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), datagrams.len() as _, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(sent as usize)
+}
+
+/// Receives a single datagram because `recvmmsg` is only available on Linux.
+#[cfg(not(target_os = "linux"))]
+fn recv_datagrams(fd: RawFd, buffer: &mut [u8]) -> io::Result<DatagramBatch> {
+    // SAFETY: `sockaddr_storage` is a plain C struct for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    // SAFETY: `buffer` and the address storage outlive the syscall and the lengths passed match their sizes.
+    let received = unsafe {
+        libc::recvfrom(
+            fd,
+            buffer.as_mut_ptr().cast(),
+            MAX_UDP_SIZE,
+            0,
+            (&mut addr as *mut libc::sockaddr_storage).cast(),
+            &mut addr_len,
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut batch = DatagramBatch::default();
+
+    // SAFETY: The kernel initialised the address storage and set its length.
+    let sender = unsafe { SockAddr::new(addr, addr_len) };
+    if let Some(sender) = sender.as_socket() {
+        batch.push(&buffer[..received as usize], sender);
+    }
+
+    Ok(batch)
+}
+
+/// Sends the first of the given datagrams because `sendmmsg` is only available on Linux.
+#[cfg(not(target_os = "linux"))]
+fn send_datagrams(fd: RawFd, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+    let (data, recipient) = &datagrams[0];
+    let recipient = SockAddr::from(*recipient);
+
+    // SAFETY: `data` and `recipient` outlive the syscall and the lengths passed match their sizes.
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            data.as_ptr().cast(),
+            data.len(),
+            0,
+            recipient.as_ptr(),
+            recipient.len(),
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(1)
+}
+
+fn set_int_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `value` outlives the syscall and the length passed matches its size.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 /// Creates an [std::net::UdpSocket] via the [socket2] library that is configured for our needs.
 ///
 /// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
-fn make_wildcard_socket(family: AddressFamily, port: u16) -> Result<std::net::UdpSocket> {
+fn make_socket(addr: SocketAddr, reuse_port: bool) -> Result<std::net::UdpSocket> {
     use socket2::*;
 
-    let domain = match family {
-        AddressFamily::V4 => Domain::IPV4,
-        AddressFamily::V6 => Domain::IPV6,
-    };
-    let address = match family {
-        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
-        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
-    };
-
-    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
-    if family == AddressFamily::V6 {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
         socket.set_only_v6(true)?;
     }
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
 
     socket.set_nonblocking(true)?;
-    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.bind(&SockAddr::from(addr))?;
 
     Ok(socket.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_and_receives_batches() {
+        let mut sender = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut receiver = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        let datagrams = (0..BATCH_SIZE + 4)
+            .map(|i| (vec![i as u8; i + 1], receiver_addr))
+            .collect::<Vec<_>>();
+        sender.send_batch(&datagrams).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            let batch = receiver.recv_batch().await.unwrap();
+            assert!(batch.len() <= BATCH_SIZE);
+
+            for (data, from) in batch.iter() {
+                assert_eq!(from, sender.local_addr().unwrap());
+                received.push((data.to_vec(), receiver_addr));
+            }
+        }
+
+        assert_eq!(received, datagrams);
+    }
+
+    #[tokio::test]
+    async fn receives_datagrams_of_maximum_size() {
+        let mut sender = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut receiver = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        // The largest payload that fits into an IPv4 datagram.
+        let datagrams = vec![
+            (vec![1; 65507], receiver_addr),
+            (vec![2; 65507], receiver_addr),
+        ];
+        sender.send_batch(&datagrams).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            let batch = receiver.recv_batch().await.unwrap();
+
+            for (data, _) in batch.iter() {
+                received.push((data.to_vec(), receiver_addr));
+            }
+        }
+
+        assert_eq!(received, datagrams);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn sets_dont_fragment_on_ip4_sockets() {
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        socket.set_dont_fragment().unwrap();
+
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.inner.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                (&mut value as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+
+        assert_eq!(result, 0);
+        assert_eq!(value, libc::IP_PMTUDISC_DO);
+    }
+}
@@ -1,23 +1,51 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
-    AddressFamily, Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, Command, IpStack, Refresh, Server,
+    AccessTokenKeys, AddressFamily, Allocate, AllocationId, AllocationInfo, AllocationUsage,
+    Attribute, Binding, CaptureEnd, CaptureFilter, CaptureId, ChannelBind, ChannelData,
+    ChannelInfo, ChannelUsage, ClientMessage, Command, Connect, ConnectionAlreadyExists,
+    ConnectionBind, ConnectionId, ConnectionTimeoutOrFailure, CreatePermission, CredentialScheme,
+    DeletionReason, DiscoverySocket, Event, IpStack, Limits, MessageIntegritySha256, NatDiscovery,
+    PasswordAlgorithm, PasswordAlgorithms, Refresh, SendIndication, Server, Snapshot,
+    ThirdPartyAuthorization, Traffic,
 };
 use rand::rngs::mock::StepRng;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
 use std::collections::HashMap;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::time::{Duration, SystemTime};
-use stun_codec::rfc5389::attributes::{ErrorCode, Nonce, Realm, Username, XorMappedAddress};
-use stun_codec::rfc5389::errors::Unauthorized;
+use stun_codec::rfc5389::attributes::{
+    AlternateServer, ErrorCode, Fingerprint, MessageIntegrity, Nonce, Realm, Software,
+    UnknownAttributes, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{
+    BadRequest, StaleNonce, TryAlternate, Unauthorized, UnknownAttribute,
+};
 use stun_codec::rfc5389::methods::BINDING;
-use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
-use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+use stun_codec::rfc5766::attributes::{
+    ChannelNumber, Data, DontFragment, EvenPort, Lifetime, RequestedTransport, ReservationToken,
+    XorPeerAddress, XorRelayAddress,
+};
+use stun_codec::rfc5766::errors::{
+    AllocationMismatch, AllocationQuotaReached, Forbidden, InsufficientCapacity,
+};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
+use stun_codec::rfc5780::attributes::{ChangeRequest, OtherAddress, ResponseOrigin};
+use stun_codec::rfc8016::attributes::MobilityTicket;
+use stun_codec::rfc8016::errors::MobilityForbidden;
+use stun_codec::{
+    AttributeType, Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId,
+};
 use test_strategy::proptest;
-use uuid::Uuid;
-use Output::{CreateAllocation, FreeAllocation, Wake};
+use Output::{
+    CloseConnection, ConnectToPeer, CreateAllocation, CreateDontFragmentAllocation,
+    CreateTcpAllocation, FreeAllocation, Wake,
+};
+
+/// The methods of RFC 6062, which `stun_codec` doesn't know about.
+const CONNECT: u16 = 0x000A;
+const CONNECTION_BIND: u16 = 0x000B;
+const CONNECTION_ATTEMPT: u16 = 0x000C;
 
 #[proptest]
 fn can_answer_stun_request_from_ip4_address(
@@ -47,9 +75,8 @@ fn deallocate_once_time_expired(
     source: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
-    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr);
     let secret = server.auth_secret();
 
     server.assert_commands(
@@ -60,7 +87,7 @@ fn deallocate_once_time_expired(
                 Some(lifetime.clone()),
                 valid_username(now, &username_salt),
                 secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -89,11 +116,9 @@ fn unauthenticated_allocate_triggers_authentication(
     public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
 ) {
-    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
-    let first_nonce = Uuid::from_u128(0x0);
-
     let mut server = TestServer::new(public_relay_addr);
     let secret = server.auth_secret().to_owned();
+    let first_nonce = server.nonce(source, now);
 
     server.assert_commands(
         from_client(
@@ -103,7 +128,7 @@ fn unauthenticated_allocate_triggers_authentication(
         ),
         [send_message(
             source,
-            unauthorized_allocate_response(transaction_id, first_nonce),
+            unauthorized_allocate_response(transaction_id, &first_nonce),
         )],
     );
 
@@ -115,7 +140,7 @@ fn unauthenticated_allocate_triggers_authentication(
                 Some(lifetime.clone()),
                 valid_username(now, &username_salt),
                 &secret,
-                first_nonce,
+                &first_nonce,
             ),
             now,
         ),
@@ -130,6 +155,107 @@ fn unauthenticated_allocate_triggers_authentication(
     );
 }
 
+#[proptest]
+fn expired_nonce_is_rejected_as_stale(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+    let nonce = server.nonce(source, now);
+
+    let now = now + Duration::from_secs(601);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            stale_nonce_allocate_response(transaction_id, &server.nonce(source, now)),
+        )],
+    );
+}
+
+#[proptest]
+fn nonce_of_other_client_is_rejected_as_stale(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let other_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(other_source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            stale_nonce_allocate_response(transaction_id, &server.nonce(source, now)),
+        )],
+    );
+}
+
+#[proptest]
+fn nonce_of_rotated_secret_is_rejected_as_stale(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+    let nonce = server.nonce(source, now);
+
+    server.rotate_nonce_secret(SecretString::from("south".to_owned()));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            stale_nonce_allocate_response(transaction_id, &server.nonce(source, now)),
+        )],
+    );
+}
+
 #[proptest]
 fn when_refreshed_in_time_allocation_does_not_expire(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -140,9 +266,8 @@ fn when_refreshed_in_time_allocation_does_not_expire(
     source: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
-    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr);
     let secret = server.auth_secret().to_owned();
     let first_wake = now + allocate_lifetime.lifetime();
 
@@ -154,7 +279,7 @@ fn when_refreshed_in_time_allocation_does_not_expire(
                 Some(allocate_lifetime.clone()),
                 valid_username(now, &username_salt),
                 &secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -186,7 +311,7 @@ fn when_refreshed_in_time_allocation_does_not_expire(
                 Some(refresh_lifetime.clone()),
                 valid_username(now, &username_salt),
                 &secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -198,6 +323,14 @@ fn when_refreshed_in_time_allocation_does_not_expire(
             ),
         ],
     );
+    assert!(matches!(
+        server.next_event(),
+        Some(Event::AllocationCreated { .. })
+    ));
+    assert!(matches!(
+        server.next_event(),
+        Some(Event::AllocationRefreshed { lifetime, .. }) if lifetime == refresh_lifetime.lifetime()
+    ));
 
     // The allocation MUST NOT be expired 1 sec before its refresh lifetime.
     // Note that depending on how the lifetimes were generated, this may still be before the initial allocation lifetime.
@@ -216,9 +349,8 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
     source: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
-    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr);
     let secret = server.auth_secret().to_owned();
     let first_wake = now + allocate_lifetime.lifetime();
 
@@ -230,7 +362,7 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
                 Some(allocate_lifetime.clone()),
                 valid_username(now, &username_salt),
                 &secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -261,7 +393,7 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
                 Some(Lifetime::new(Duration::ZERO).unwrap()),
                 valid_username(now, &username_salt),
                 &secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -276,9 +408,61 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
             ),
         ],
     );
+    assert_eq!(server.deletion_reasons(), [DeletionReason::ClientDeleted]);
 
     // Assert that forwarding time does not produce an obsolete event.
     server.assert_commands(forward_time_to(first_wake + Duration::from_secs(1)), []);
+    assert_eq!(server.deletion_reasons(), []);
+}
+
+#[proptest]
+fn when_stream_connection_closes_then_delete_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        client_disconnected(source),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+    assert_eq!(
+        server.deletion_reasons(),
+        [DeletionReason::ClientDisconnected]
+    );
+
+    // Assert that forwarding time does not produce an obsolete event.
+    server.assert_commands(
+        forward_time_to(now + lifetime.lifetime() + Duration::from_secs(1)),
+        [],
+    );
 }
 
 // #[test]
@@ -295,16 +479,15 @@ fn ping_pong_relay(
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
     source: SocketAddrV4,
-    peer: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
     peer_to_client_ping: [u8; 32],
     client_to_peer_ping: [u8; 32],
-    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
     let _ = env_logger::try_init();
 
-    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let mut server = TestServer::new(public_relay_addr);
     let secret = server.auth_secret().to_owned();
 
     server.assert_commands(
@@ -315,7 +498,7 @@ fn ping_pong_relay(
                 Some(lifetime.clone()),
                 valid_username(now, &username_salt),
                 &secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -346,7 +529,7 @@ fn ping_pong_relay(
                 XorPeerAddress::new(peer.into()),
                 valid_username(now, &username_salt),
                 &secret,
-                nonce,
+                &server.nonce(source, now),
             ),
             now,
         ),
@@ -368,7 +551,7 @@ fn ping_pong_relay(
     );
 
     server.assert_commands(
-        from_peer(peer, peer_to_client_ping.as_ref(), 49152),
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
         [send_channel_data(
             source,
             ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
@@ -377,40 +560,51 @@ fn ping_pong_relay(
 }
 
 #[proptest]
-fn can_make_ipv6_allocation(
+fn relaying_to_denied_peers_is_forbidden(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
     source: SocketAddrV4,
-    public_relay_ip4_addr: Ipv4Addr,
-    public_relay_ip6_addr: Ipv6Addr,
+    #[strategy(firezone_relay::proptest::peer())] allowed_peer: SocketAddrV4,
+    #[strategy(proptest::sample::select(vec![
+        Ipv4Addr::LOCALHOST,
+        Ipv4Addr::new(169, 254, 169, 254),
+        Ipv4Addr::new(100, 100, 100, 200),
+        Ipv4Addr::new(10, 0, 0, 1),
+        Ipv4Addr::new(192, 168, 1, 1),
+        Ipv4Addr::new(100, 64, 0, 1),
+    ]))]
+    denied_ip: Ipv4Addr,
+    #[filter(#public_relay_addr != *#allowed_peer.ip())] public_relay_addr: Ipv4Addr,
+    #[filter(#listen_ip != *#allowed_peer.ip())] listen_ip: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
-    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
-    let mut server =
-        TestServer::new((public_relay_ip4_addr, public_relay_ip6_addr)).with_nonce(nonce);
-    let secret = server.auth_secret();
+    let mut server = TestServer::new(public_relay_addr)
+        .with_listen_addr(SocketAddrV4::new(listen_ip, 3478).into());
+    let secret = server.auth_secret().to_owned();
 
     server.assert_commands(
         from_client(
             source,
-            Allocate::new_authenticated_udp_ip6(
-                transaction_id,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
                 Some(lifetime.clone()),
                 valid_username(now, &username_salt),
-                secret,
-                nonce,
+                &secret,
+                &server.nonce(source, now),
             ),
             now,
         ),
         [
             Wake(now + lifetime.lifetime()),
-            CreateAllocation(49152, AddressFamily::V6),
+            CreateAllocation(49152, AddressFamily::V4),
             send_message(
                 source,
                 allocate_response(
-                    transaction_id,
-                    public_relay_ip6_addr,
+                    allocate_transaction_id,
+                    public_relay_addr,
                     49152,
                     source,
                     &lifetime,
@@ -418,69 +612,3059 @@ fn can_make_ipv6_allocation(
             ),
         ],
     );
-}
 
-struct TestServer {
-    server: Server<StepRng>,
-    id_to_port: HashMap<u16, AllocationId>,
-}
+    for denied_ip in [denied_ip, public_relay_addr, listen_ip] {
+        let denied_peer = SocketAddrV4::new(denied_ip, allowed_peer.port());
 
-impl TestServer {
-    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
-        Self {
-            server: Server::new(relay_public_addr, StepRng::new(0, 0), 49152, 65535),
-            id_to_port: Default::default(),
-        }
+        server.assert_commands(
+            from_client(
+                source,
+                ChannelBind::new(
+                    transaction_id,
+                    channel,
+                    XorPeerAddress::new(denied_peer.into()),
+                    valid_username(now, &username_salt),
+                    &secret,
+                    &server.nonce(source, now),
+                ),
+                now,
+            ),
+            [send_message(
+                source,
+                forbidden_response(CHANNEL_BIND, transaction_id),
+            )],
+        );
+
+        // Either all permissions are installed or none.
+        server.assert_commands(
+            from_client(
+                source,
+                CreatePermission::new(
+                    transaction_id,
+                    vec![
+                        XorPeerAddress::new(allowed_peer.into()),
+                        XorPeerAddress::new(denied_peer.into()),
+                    ],
+                    valid_username(now, &username_salt),
+                    &secret,
+                    &server.nonce(source, now),
+                ),
+                now,
+            ),
+            [send_message(
+                source,
+                forbidden_response(CREATE_PERMISSION, transaction_id),
+            )],
+        );
     }
 
-    fn with_nonce(mut self, nonce: Uuid) -> Self {
-        self.server.add_nonce(nonce);
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                transaction_id,
+                XorPeerAddress::new(allowed_peer.into()),
+                Data::new(b"ping".to_vec()).unwrap(),
+            ),
+            now,
+        ),
+        [],
+    );
+}
+
+#[proptest]
+fn allocations_can_use_the_same_channel_number(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source_a: SocketAddrV4,
+    #[filter(#source_b != #source_a)] source_b: SocketAddrV4,
+    #[filter(#stranger != #source_a && #stranger != #source_b)] stranger: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer_a: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())]
+    #[filter(#peer_b != #peer_a)]
+    peer_b: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    ping: [u8; 32],
+) {
+    // Every other port selection yields the middle of the port range.
+    let mut server = TestServer::new_with_rng(public_relay_addr, StepRng::new(0, 1 << 31));
+    let secret = server.auth_secret().to_owned();
 
-        self
+    for (source, port) in [(source_a, 49152), (source_b, 57343)] {
+        server.assert_commands(
+            from_client(
+                source,
+                Allocate::new_authenticated_udp_implicit_ip4(
+                    transaction_id,
+                    Some(lifetime.clone()),
+                    valid_username(now, &username_salt),
+                    &secret,
+                    &server.nonce(source, now),
+                ),
+                now,
+            ),
+            [
+                Wake(now + lifetime.lifetime()),
+                CreateAllocation(port, AddressFamily::V4),
+                send_message(
+                    source,
+                    allocate_response(transaction_id, public_relay_addr, port, source, &lifetime),
+                ),
+            ],
+        );
     }
 
-    fn auth_secret(&self) -> &SecretString {
-        self.server.auth_secret()
+    for (source, peer) in [(source_a, peer_a), (source_b, peer_b)] {
+        server.assert_commands(
+            from_client(
+                source,
+                ChannelBind::new(
+                    transaction_id,
+                    channel,
+                    XorPeerAddress::new(peer.into()),
+                    valid_username(now, &username_salt),
+                    &secret,
+                    &server.nonce(source, now),
+                ),
+                now,
+            ),
+            [send_message(source, channel_bind_response(transaction_id))],
+        );
     }
 
-    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
-        match input {
-            Input::Client(sender, message, now) => {
-                self.server.handle_client_message(message, sender, now);
-            }
-            Input::Time(now) => {
-                self.server.handle_deadline_reached(now);
-            }
-            Input::Peer(peer, data, port) => {
-                self.server
-                    .handle_relay_input(&data, peer, self.id_to_port[&port]);
-            }
-        }
-
-        for expected_output in output {
-            let Some(actual_output) = self.server.next_command() else {
-                let msg = match expected_output {
-                    Output::SendMessage((recipient, msg)) => {
-                        format!("to send message {:?} to {recipient}", msg)
-                    }
-                    Wake(time) => format!("to be woken at {time:?}"),
-                    CreateAllocation(port, family) => {
-                        format!("to create allocation on port {port} for address family {family}")
-                    }
-                    FreeAllocation(port, family) => {
-                        format!("to free allocation on port {port} for address family {family}")
-                    }
-                    Output::SendChannelData((peer, _)) => {
-                        format!("to send channel data from {peer} to client")
-                    }
-                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
-                };
-
-                panic!("No commands produced but expected {msg}");
-            };
+    for (source, peer, port) in [(source_a, peer_a, 49152), (source_b, peer_b, 57343)] {
+        server.assert_commands(
+            from_client(
+                source,
+                ChannelData::new(channel.value(), ping.as_ref()),
+                now,
+            ),
+            [forward(peer, &ping, port)],
+        );
+        server.assert_commands(
+            from_peer(peer, ping.as_ref(), port, now),
+            [send_channel_data(
+                source,
+                ChannelData::new(channel.value(), ping.as_ref()),
+            )],
+        );
+    }
 
-            match (expected_output, actual_output) {
-                (
+    server.assert_commands(
+        from_client(
+            stranger,
+            ChannelData::new(channel.value(), ping.as_ref()),
+            now,
+        ),
+        [],
+    );
+}
+
+#[proptest]
+fn remaining_allocations_can_be_deleted_at_once(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source_a: SocketAddrV4,
+    #[filter(#source_b != #source_a)] source_b: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    for (source, port) in [(source_a, 49152), (source_b, 49153)] {
+        server.assert_commands(
+            from_client(
+                source,
+                Allocate::new_authenticated_udp_implicit_ip4(
+                    transaction_id,
+                    Some(lifetime.clone()),
+                    valid_username(now, &username_salt),
+                    &secret,
+                    &server.nonce(source, now),
+                ),
+                now,
+            ),
+            [
+                Wake(now + lifetime.lifetime()),
+                CreateAllocation(port, AddressFamily::V4),
+                send_message(
+                    source,
+                    allocate_response(transaction_id, public_relay_addr, port, source, &lifetime),
+                ),
+            ],
+        );
+    }
+    server.deletion_reasons();
+
+    server.assert_commands(
+        kill_all_allocations(DeletionReason::Drained),
+        [
+            FreeAllocation(49152, AddressFamily::V4),
+            FreeAllocation(49153, AddressFamily::V4),
+        ],
+    );
+    assert!(server.allocations().is_empty());
+    assert_eq!(
+        server.deletion_reasons(),
+        [DeletionReason::Drained, DeletionReason::Drained]
+    );
+}
+
+#[proptest]
+fn allocation_quota_per_user_is_enforced(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source_a: SocketAddrV4,
+    #[filter(#source_b.ip() != #source_a.ip())] source_b: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_user_limits(Limits {
+        max_allocations: Some(1),
+        ..Limits::default()
+    });
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source_a,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_a, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source_a,
+                allocate_response(
+                    transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source_a,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source_b,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_b, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source_b,
+            allocation_quota_reached_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn allocation_quota_per_ip_is_enforced(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt_a: String,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt_b: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_ip_limits(Limits {
+        max_allocations: Some(1),
+        ..Limits::default()
+    });
+    let secret = server.auth_secret().to_owned();
+    let other_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt_a),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            other_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt_b),
+                &secret,
+                &server.nonce(other_source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            other_source,
+            allocation_quota_reached_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn bandwidth_quota_drops_excess_data(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr).with_user_limits(Limits {
+        max_bytes_per_second: Some(32),
+        ..Limits::default()
+    });
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(source, channel_bind_response(transaction_id))],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &ping, 49152)],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), ping.as_ref()),
+            now,
+        ),
+        [],
+    );
+    server.assert_commands(from_peer(peer, ping.as_ref(), 49152, now), []);
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_peer(peer, ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), ping.as_ref()),
+        )],
+    );
+}
+
+#[proptest]
+fn peer_data_without_permission_is_dropped(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+}
+
+#[proptest]
+fn permission_allows_data_indications_in_both_directions(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            now,
+        ),
+        [],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            create_permission_response(create_permission_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_message(
+            source,
+            data_indication(peer, &peer_to_client_ping),
+        )],
+    );
+
+    let now = now + Duration::from_secs(300);
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+}
+
+#[proptest]
+fn when_draining_then_redirect_new_allocations_but_keep_existing_ones(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[filter(#other_source != #source)] other_source: SocketAddrV4,
+    alternate_server: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server =
+        TestServer::new(public_relay_addr).with_alternate_servers(vec![alternate_server.into()]);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.start_draining();
+
+    server.assert_commands(
+        from_client(
+            other_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(other_source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            other_source,
+            try_alternate_response(allocate_transaction_id, alternate_server.into()),
+        )],
+    );
+
+    let now = now + lifetime.lifetime() / 2;
+
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            send_message(
+                source,
+                refresh_response(refresh_transaction_id, lifetime.clone()),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn allocations_of_a_draining_server_survive_a_restart(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())]
+    #[filter(#lifetime.lifetime() > Duration::from_secs(1))]
+    lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    client_to_peer_ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(source, channel_bind_response(transaction_id))],
+    );
+
+    // Upon SIGTERM, we start draining and persist the snapshot that we restore from after the restart.
+    server.start_draining();
+    let snapshot = serde_json::to_vec(&server.snapshot()).unwrap();
+    let snapshot = serde_json::from_slice(&snapshot).unwrap();
+
+    let allocation_expiry = now + lifetime.lifetime();
+    let channel_expiry = now + Duration::from_secs(600);
+    let now = now + Duration::from_secs(1);
+    let mut server = TestServer::new(public_relay_addr);
+
+    server.assert_commands(
+        restore_from(snapshot, now),
+        [
+            CreateAllocation(49152, AddressFamily::V4),
+            Wake(allocation_expiry.min(channel_expiry)),
+        ],
+    );
+    assert_eq!(server.next_event(), None);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+}
+
+#[proptest]
+fn when_draining_without_alternate_server_then_reject_new_allocations(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.start_draining();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            insufficient_capacity_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn when_above_load_shedding_threshold_then_redirect_to_sibling_relay(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    sibling_relay: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_alternate_servers(vec![sibling_relay.into()])
+        .with_load_shedding_threshold(0.0);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            try_alternate_response(transaction_id, sibling_relay.into()),
+        )],
+    );
+}
+
+#[proptest]
+fn when_above_load_shedding_threshold_without_sibling_relays_then_allocate(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_load_shedding_threshold(0.0);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn restored_server_continues_to_serve_existing_allocations(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    client_to_peer_ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    // Nonces issued before the restart remain valid afterwards.
+    let nonce = server.nonce(source, now);
+
+    // Snapshots are persisted as JSON, make sure they survive the round-trip.
+    let snapshot = serde_json::to_vec(&server.snapshot()).unwrap();
+    let snapshot = serde_json::from_slice(&snapshot).unwrap();
+
+    let allocation_expiry = now + lifetime.lifetime();
+    let channel_expiry = now + Duration::from_secs(600);
+    let username = valid_username(now, &username_salt);
+    let now = now + Duration::from_secs(1);
+    let mut server = TestServer::new(public_relay_addr);
+
+    // An allocation that expires right when we restore is gone, like it would be after the restart.
+    if allocation_expiry <= now {
+        server.assert_commands(restore_from(snapshot, now), []);
+        assert_eq!(
+            server.next_event(),
+            Some(Event::AllocationDeleted {
+                usage: AllocationUsage {
+                    id: serde_json::from_str("1").unwrap(),
+                    client: source.into(),
+                    username: username.name().to_owned(),
+                    traffic: Traffic::default(),
+                    channels: vec![ChannelUsage {
+                        number: channel.value(),
+                        peer: peer.into(),
+                        traffic: Traffic::default(),
+                    }],
+                },
+                reason: DeletionReason::Expired,
+                relay_addresses: vec![SocketAddr::new(public_relay_addr.into(), 49152)],
+            })
+        );
+        server.assert_commands(
+            from_client(
+                source,
+                ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+                now,
+            ),
+            [],
+        );
+
+        return Ok(());
+    }
+
+    server.assert_commands(
+        restore_from(snapshot, now),
+        [
+            CreateAllocation(49152, AddressFamily::V4),
+            Wake(allocation_expiry.min(channel_expiry)),
+        ],
+    );
+    assert_eq!(server.auth_secret().expose_secret(), secret.expose_secret());
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &nonce,
+            ),
+            now,
+        ),
+        [
+            Wake((now + lifetime.lifetime()).min(channel_expiry)),
+            send_message(
+                source,
+                refresh_response(refresh_transaction_id, lifetime.clone()),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn allocations_can_be_inspected_and_killed(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 16],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+    let username = valid_username(now, &username_salt);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                username.clone(),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+
+    let [allocation] = server.allocations().try_into().unwrap();
+    let expected_traffic = Traffic {
+        bytes_to_peer: 16,
+        packets_to_peer: 1,
+        bytes_to_client: 32,
+        packets_to_client: 1,
+    };
+
+    assert_eq!(allocation.client, SocketAddr::from(source));
+    assert_eq!(allocation.username, username.name());
+    assert_eq!(
+        allocation.relay_addresses,
+        vec![SocketAddr::from((public_relay_addr, 49152))]
+    );
+    assert_eq!(allocation.expires_at, now + lifetime.lifetime());
+    assert_eq!(
+        allocation.channels,
+        vec![ChannelInfo {
+            number: channel.value(),
+            peer: peer.into(),
+            expires_at: now + Duration::from_secs(600),
+            bound: true,
+            traffic: expected_traffic,
+        }]
+    );
+    assert_eq!(allocation.traffic, expected_traffic);
+    assert_eq!(
+        server.next_event(),
+        Some(Event::AllocationCreated {
+            id: allocation.id,
+            client: source.into(),
+            username: username.name().to_owned(),
+            relay_addresses: vec![SocketAddr::from((public_relay_addr, 49152))],
+            lifetime: lifetime.lifetime(),
+        })
+    );
+    assert_eq!(
+        server.next_event(),
+        Some(Event::ChannelBound {
+            id: allocation.id,
+            client: source.into(),
+            username: username.name().to_owned(),
+            number: channel.value(),
+            peer: peer.into(),
+        })
+    );
+
+    server.assert_commands(
+        kill_allocation(49152),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+    assert!(server.allocations().is_empty());
+    assert_eq!(
+        server.next_event(),
+        Some(Event::AllocationDeleted {
+            usage: AllocationUsage {
+                id: allocation.id,
+                client: source.into(),
+                username: username.name().to_owned(),
+                traffic: expected_traffic,
+                channels: vec![ChannelUsage {
+                    number: channel.value(),
+                    peer: peer.into(),
+                    traffic: expected_traffic,
+                }],
+            },
+            reason: DeletionReason::AdminKilled,
+            relay_addresses: vec![SocketAddr::new(public_relay_addr.into(), 49152)],
+        })
+    );
+}
+
+#[proptest]
+fn relayed_traffic_of_allocation_can_be_captured(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    client_to_peer_ping: [u8; 16],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+    let username = valid_username(now, &username_salt);
+
+    assert_eq!(
+        server.start_capture(
+            CaptureFilter::Allocation(serde_json::from_str("1").unwrap()),
+            1024,
+            Duration::from_secs(60),
+            now
+        ),
+        None
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                username.clone(),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+    let [allocation] = server.allocations().try_into().unwrap();
+    while server.next_event().is_some() {}
+
+    let capture = server
+        .start_capture(
+            CaptureFilter::Allocation(allocation.id),
+            1024,
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+    let Some(Event::CaptureData { id, bytes: header }) = server.next_event() else {
+        panic!("expected the header of the capture")
+    };
+    assert_eq!(id, capture);
+    assert_eq!(&header[..4], &[0x0A, 0x0D, 0x0D, 0x0A]); // Section header block
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    let Some(Event::CaptureData { id, bytes }) = server.next_event() else {
+        panic!("expected captured data")
+    };
+    assert_eq!(id, capture);
+    assert_eq!(bytes.len(), (32 + 20 + 8 + 20) + (32 + 20 + 8 + 16)); // Channel data to us and datagram to the peer
+    assert!(bytes
+        .windows(client_to_peer_ping.len())
+        .any(|window| window == client_to_peer_ping));
+
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                TransactionId::new([0; 12]),
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    let Some(Event::CaptureData { id, bytes }) = server.next_event() else {
+        panic!("expected captured data")
+    };
+    assert_eq!(id, capture);
+    assert_eq!(bytes.len(), (32 + 20 + 8 + 52) + (32 + 20 + 8 + 16)); // SEND indication to us and datagram to the peer
+
+    server.assert_commands(
+        kill_allocation(49152),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+    assert!(iter::from_fn(|| server.next_event()).any(|event| event
+        == Event::CaptureFinished {
+            id: capture,
+            reason: CaptureEnd::AllocationDeleted
+        }));
+}
+
+#[proptest]
+fn captures_that_would_never_end_are_rejected(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let username = valid_username(now, &username_salt);
+
+    let capture = server.start_capture(
+        CaptureFilter::Username(username.name().to_owned()),
+        1024 * 1024,
+        Duration::MAX,
+        now,
+    );
+
+    assert_eq!(capture, None);
+    assert_eq!(server.next_event(), None);
+}
+
+#[proptest]
+fn captures_are_bounded_by_size_and_duration(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    // The allocation must outlive the capture that is bounded by duration.
+    #[strategy(firezone_relay::proptest::allocation_lifetime())]
+    #[filter(#lifetime.lifetime() > Duration::from_secs(2))]
+    lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+    let username = valid_username(now, &username_salt);
+
+    let by_duration = server
+        .start_capture(
+            CaptureFilter::Username(username.name().to_owned()),
+            1024 * 1024,
+            Duration::from_secs(1),
+            now,
+        )
+        .unwrap();
+    let by_size = server
+        .start_capture(
+            CaptureFilter::Username(username.name().to_owned()),
+            100,
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(1)), // The capture expires first.
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                TransactionId::new([0; 12]),
+                vec![XorPeerAddress::new(peer.into())],
+                username.clone(),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            create_permission_response(TransactionId::new([0; 12])),
+        )],
+    );
+    while server.next_event().is_some() {}
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_message(
+            source,
+            data_indication(peer, &peer_to_client_ping),
+        )],
+    );
+
+    let events = iter::from_fn(|| server.next_event()).collect::<Vec<_>>();
+    assert!(events.contains(&Event::CaptureFinished {
+        id: by_size,
+        reason: CaptureEnd::MaxBytes
+    }));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Event::CaptureData { id, bytes } if *id == by_duration && bytes.len() == (32 + 20 + 8 + 32) + (32 + 20 + 8 + 68) // Datagram from the peer and DATA indication to the client
+    )));
+
+    server.assert_commands(forward_time_to(now + Duration::from_secs(2)), []);
+    assert_eq!(
+        server.next_event(),
+        Some(Event::CaptureFinished {
+            id: by_duration,
+            reason: CaptureEnd::Duration
+        })
+    );
+}
+
+#[proptest]
+fn credentials_of_rotated_secret_remain_valid_during_grace_period(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server =
+        TestServer::new(public_relay_addr).with_auth_secret_grace_period(Duration::from_secs(60));
+    let old_secret = server.auth_secret().to_owned();
+    let new_secret = SecretString::from("new-secret".to_owned());
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &old_secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.rotate_auth_secret(new_secret.clone(), now);
+    assert_eq!(server.auth_secret().expose_secret(), "new-secret");
+
+    for secret in [&old_secret, &new_secret] {
+        server.assert_commands(
+            from_client(
+                source,
+                Refresh::new(
+                    refresh_transaction_id,
+                    Some(lifetime.clone()),
+                    valid_username(now, &username_salt),
+                    secret,
+                    &server.nonce(source, now),
+                ),
+                now,
+            ),
+            [
+                Wake(now + lifetime.lifetime()),
+                send_message(
+                    source,
+                    refresh_response(refresh_transaction_id, lifetime.clone()),
+                ),
+            ],
+        );
+    }
+
+    let now = now + Duration::from_secs(61);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &old_secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            unauthorized_refresh_response(refresh_transaction_id, &server.nonce(source, now)),
+        )],
+    );
+}
+
+#[proptest]
+fn accepts_turn_rest_credentials(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_auth_secret(SecretString::from("north".to_owned()))
+        .with_credential_scheme(CredentialScheme::TurnRest);
+
+    // The password is base64(hmac-sha1(secret, username)), as computed by coturn.
+    let allocate = turn_rest_allocate(
+        transaction_id,
+        &lifetime,
+        "4102444800:alice",
+        "58Tl4e2VjINId23vxEnD/7NNBaQ=",
+        &server.nonce(source, now),
+    );
+
+    server.assert_commands(
+        from_client(source, allocate, now),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn accepts_message_integrity_sha256_with_negotiated_password_algorithm(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(proptest::sample::select(vec![PasswordAlgorithm::Md5, PasswordAlgorithm::Sha256]))]
+    algorithm: PasswordAlgorithm,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_auth_secret(SecretString::from("north".to_owned()))
+        .with_credential_scheme(CredentialScheme::TurnRest);
+
+    let allocate = sha256_allocate(
+        transaction_id,
+        &lifetime,
+        &server.nonce(source, now),
+        PasswordAlgorithms::new(vec![PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5]),
+        algorithm,
+    );
+
+    server.assert_commands(
+        from_client(source, allocate, now),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn password_algorithms_must_match_the_offered_ones(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_auth_secret(SecretString::from("north".to_owned()))
+        .with_credential_scheme(CredentialScheme::TurnRest);
+
+    // A man-in-the-middle removed SHA256 from the algorithms we offered to downgrade the client to MD5.
+    let allocate = sha256_allocate(
+        transaction_id,
+        &lifetime,
+        &server.nonce(source, now),
+        PasswordAlgorithms::new(vec![PasswordAlgorithm::Md5]),
+        PasswordAlgorithm::Md5,
+    );
+
+    server.assert_commands(
+        from_client(source, allocate, now),
+        [send_message(
+            source,
+            bad_request_allocate_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn accepts_access_tokens_of_third_party_authorization_server(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_access_token_keys(access_token_keys());
+
+    let access_token = access_token_keys()
+        .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+        .unwrap();
+    let allocate = Allocate::new_authenticated_udp_access_token(
+        transaction_id,
+        Some(lifetime.clone()),
+        Username::new("kid".to_owned()).unwrap(),
+        access_token,
+        &[2; 32],
+        &server.nonce(source, now),
+    );
+
+    server.assert_commands(
+        from_client(source, allocate, now),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn allocation_quota_per_user_applies_to_each_access_token(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source_a: SocketAddrV4,
+    #[filter(#source_b != #source_a)] source_b: SocketAddrV4,
+    #[filter(#source_c != #source_a && #source_c != #source_b)] source_c: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_access_token_keys(access_token_keys())
+        .with_user_limits(Limits {
+            max_allocations: Some(1),
+            ..Limits::default()
+        });
+
+    for (source, token_nonce, port) in [(source_a, [1; 12], 49152), (source_b, [5; 12], 49153)] {
+        let access_token = access_token_keys()
+            .seal("kid", token_nonce, &[2; 32], now, Duration::from_secs(3600))
+            .unwrap();
+        let allocate = Allocate::new_authenticated_udp_access_token(
+            transaction_id,
+            Some(lifetime.clone()),
+            Username::new("kid".to_owned()).unwrap(),
+            access_token,
+            &[2; 32],
+            &server.nonce(source, now),
+        );
+
+        server.assert_commands(
+            from_client(source, allocate, now),
+            [
+                Wake(now + lifetime.lifetime()),
+                CreateAllocation(port, AddressFamily::V4),
+                send_message(
+                    source,
+                    allocate_response(transaction_id, public_relay_addr, port, source, &lifetime),
+                ),
+            ],
+        );
+    }
+
+    let access_token = access_token_keys()
+        .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+        .unwrap();
+    let allocate = Allocate::new_authenticated_udp_access_token(
+        transaction_id,
+        Some(lifetime.clone()),
+        Username::new("kid".to_owned()).unwrap(),
+        access_token,
+        &[2; 32],
+        &server.nonce(source_c, now),
+    );
+
+    server.assert_commands(
+        from_client(source_c, allocate, now),
+        [send_message(
+            source_c,
+            allocation_quota_reached_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn expired_access_token_is_rejected_with_third_party_authorization(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_access_token_keys(access_token_keys());
+
+    let access_token = access_token_keys()
+        .seal(
+            "kid",
+            [1; 12],
+            &[2; 32],
+            now - Duration::from_secs(7200),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    let allocate = Allocate::new_authenticated_udp_access_token(
+        transaction_id,
+        Some(lifetime),
+        Username::new("kid".to_owned()).unwrap(),
+        access_token,
+        &[2; 32],
+        &server.nonce(source, now),
+    );
+
+    let mut response = unauthorized_allocate_response(transaction_id, &server.nonce(source, now));
+    response.add_attribute(ThirdPartyAuthorization::new("relay.example.com".to_owned()));
+
+    server.assert_commands(
+        from_client(source, allocate, now),
+        [send_message(source, response)],
+    );
+}
+
+#[proptest]
+fn access_tokens_are_rejected_unless_configured(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+
+    let access_token = access_token_keys()
+        .seal("kid", [1; 12], &[2; 32], now, Duration::from_secs(3600))
+        .unwrap();
+    let allocate = Allocate::new_authenticated_udp_access_token(
+        transaction_id,
+        Some(lifetime),
+        Username::new("kid".to_owned()).unwrap(),
+        access_token,
+        &[2; 32],
+        &server.nonce(source, now),
+    );
+
+    server.assert_commands(
+        from_client(source, allocate, now),
+        [send_message(
+            source,
+            unauthorized_allocate_response(transaction_id, &server.nonce(source, now)),
+        )],
+    );
+}
+
+#[proptest]
+fn can_make_ipv6_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_ip4_addr: Ipv4Addr,
+    public_relay_ip6_addr: Ipv6Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new((public_relay_ip4_addr, public_relay_ip6_addr));
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_ip6(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V6),
+            send_message(
+                source,
+                allocate_response(
+                    transaction_id,
+                    public_relay_ip6_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn tcp_allocation_connects_to_peer_and_splices_data_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    data_connection: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    proptest::prop_assume!(source != data_connection);
+
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [ConnectToPeer(peer.into(), 1, 49152)],
+    );
+
+    // A second connection to the same peer is refused while the first one is in progress.
+    server.assert_commands(
+        from_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            connect_error_response(connect_transaction_id, ConnectionAlreadyExists),
+        )],
+    );
+
+    server.assert_commands(
+        peer_connected(1, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_message(source, connect_response(connect_transaction_id, 1)),
+        ],
+    );
+
+    server.assert_commands(client_connected(data_connection), []);
+    server.assert_commands(
+        from_client(
+            data_connection,
+            ConnectionBind::new(
+                bind_transaction_id,
+                ConnectionId::new(1),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(data_connection, now),
+            ),
+            now,
+        ),
+        [splice_connection(
+            1,
+            data_connection,
+            connection_bind_response(bind_transaction_id),
+        )],
+    );
+
+    // Bound connections don't expire.
+    server.assert_commands(forward_time_to(now + Duration::from_secs(31)), []);
+
+    server.assert_commands(client_disconnected(data_connection), [CloseConnection(1)]);
+    server.assert_commands(
+        client_disconnected(source),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+}
+
+#[proptest]
+fn peer_connections_need_a_permission_and_expire_unless_bound(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(peer_connection(peer, 49152, now), []);
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            create_permission_response(create_permission_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        peer_connection(peer, 49152, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_message(source, connection_attempt(peer, 1)),
+        ],
+    );
+
+    server.assert_commands(
+        forward_time_to(now + Duration::from_secs(31)),
+        [CloseConnection(1)],
+    );
+}
+
+#[proptest]
+fn failed_peer_connection_is_reported_to_client(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [ConnectToPeer(peer.into(), 1, 49152)],
+    );
+
+    server.assert_commands(
+        peer_connection_failed(1),
+        [send_message(
+            source,
+            connect_error_response(connect_transaction_id, ConnectionTimeoutOrFailure),
+        )],
+    );
+}
+
+#[proptest]
+fn tcp_allocation_requires_stream_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            bad_request_allocate_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn even_port_allocations_can_reserve_the_next_port(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source_a: SocketAddrV4,
+    #[filter(#source_b != #source_a)] source_b: SocketAddrV4,
+    #[filter(#source_c != #source_a && #source_c != #source_b)] source_c: SocketAddrV4,
+    #[filter(#source_d != #source_a && #source_d != #source_b && #source_d != #source_c)]
+    source_d: SocketAddrV4,
+    #[filter(#source_e != #source_a && #source_e != #source_b && #source_e != #source_c && #source_e != #source_d)]
+    source_e: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+    let reservation_expiry = now + Duration::from_secs(30);
+
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source_a,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_a, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source_a,
+                allocate_response(
+                    transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source_a,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // The lowest port is taken, so the next even port is allocated and the one after it is reserved.
+    let mut response = allocate_response(
+        transaction_id,
+        public_relay_addr,
+        49154,
+        source_b,
+        &lifetime,
+    );
+    response.add_attribute(ReservationToken::new(0));
+    server.assert_commands(
+        from_client(
+            source_b,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_b, now),
+                vec![EvenPort::new(true).into()],
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            Wake(reservation_expiry),
+            CreateAllocation(49154, AddressFamily::V4),
+            send_message(source_b, response),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source_c,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_c, now),
+            ),
+            now,
+        ),
+        [
+            Wake(reservation_expiry),
+            CreateAllocation(49153, AddressFamily::V4),
+            send_message(
+                source_c,
+                allocate_response(
+                    transaction_id,
+                    public_relay_addr,
+                    49153,
+                    source_c,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source_d,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_d, now),
+                vec![ReservationToken::new(0).into()],
+            ),
+            now,
+        ),
+        [
+            Wake(reservation_expiry),
+            CreateAllocation(49155, AddressFamily::V4),
+            send_message(
+                source_d,
+                allocate_response(
+                    transaction_id,
+                    public_relay_addr,
+                    49155,
+                    source_d,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // A reservation can only be claimed once.
+    server.assert_commands(
+        from_client(
+            source_e,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_e, now),
+                vec![ReservationToken::new(0).into()],
+            ),
+            now,
+        ),
+        [send_message(
+            source_e,
+            insufficient_capacity_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn reserved_port_is_released_after_30_seconds(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source_a: SocketAddrV4,
+    #[filter(#source_b != #source_a)] source_b: SocketAddrV4,
+    #[filter(#source_c != #source_a && #source_c != #source_b)] source_c: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    let mut response = allocate_response(
+        transaction_id,
+        public_relay_addr,
+        49152,
+        source_a,
+        &lifetime,
+    );
+    response.add_attribute(ReservationToken::new(0));
+    server.assert_commands(
+        from_client(
+            source_a,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_a, now),
+                vec![EvenPort::new(true).into()],
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            Wake(now + Duration::from_secs(30)),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(source_a, response),
+        ],
+    );
+
+    let allocation_expiry = now + lifetime.lifetime();
+    let now = now + Duration::from_secs(31);
+    server.assert_commands(forward_time_to(now), []);
+
+    server.assert_commands(
+        from_client(
+            source_b,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_b, now),
+                vec![ReservationToken::new(0).into()],
+            ),
+            now,
+        ),
+        [send_message(
+            source_b,
+            insufficient_capacity_response(transaction_id),
+        )],
+    );
+
+    // The previously reserved port is available to everybody again.
+    server.assert_commands(
+        from_client(
+            source_c,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source_c, now),
+            ),
+            now,
+        ),
+        [
+            Wake(allocation_expiry),
+            CreateAllocation(49153, AddressFamily::V4),
+            send_message(
+                source_c,
+                allocate_response(
+                    transaction_id,
+                    public_relay_addr,
+                    49153,
+                    source_c,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn dont_fragment_is_applied_to_allocation_and_survives_restore(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+                vec![DontFragment.into()],
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateDontFragmentAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    let snapshot = serde_json::to_vec(&server.snapshot()).unwrap();
+    let snapshot = serde_json::from_slice(&snapshot).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr);
+
+    server.assert_commands(
+        restore_from(snapshot, now),
+        [
+            CreateDontFragmentAllocation(49152, AddressFamily::V4),
+            Wake(now + lifetime.lifetime()),
+        ],
+    );
+}
+
+#[proptest]
+fn mobility_ticket_moves_allocation_to_new_address(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    old_source: SocketAddrV4,
+    #[filter(#new_source != #old_source)] new_source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+    // The first allocation with a secret of 0.
+    let ticket = MobilityTicket::new(vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+
+    let mut response = allocate_response(
+        transaction_id,
+        public_relay_addr,
+        49152,
+        old_source,
+        &lifetime,
+    );
+    response.add_attribute(ticket.clone());
+    server.assert_commands(
+        from_client(
+            old_source,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(old_source, now),
+                vec![MobilityTicket::empty().into()],
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(old_source, response),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            old_source,
+            ChannelBind::new(
+                transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(old_source, now),
+            ),
+            now,
+        ),
+        [send_message(
+            old_source,
+            channel_bind_response(transaction_id),
+        )],
+    );
+
+    let mut response = refresh_response(transaction_id, lifetime.clone());
+    response.add_attribute(ticket.clone());
+    server.assert_commands(
+        from_client(
+            new_source,
+            Refresh::new_with_mobility_ticket(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(new_source, now),
+                Some(ticket),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            send_message(new_source, response),
+        ],
+    );
+
+    // The channel binding moved together with the allocation.
+    server.assert_commands(
+        from_client(
+            new_source,
+            ChannelData::new(channel.value(), ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &ping, 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, ping.as_ref(), 49152, now),
+        [send_channel_data(
+            new_source,
+            ChannelData::new(channel.value(), ping.as_ref()),
+        )],
+    );
+    server.assert_commands(
+        from_client(
+            old_source,
+            ChannelData::new(channel.value(), ping.as_ref()),
+            now,
+        ),
+        [],
+    );
+    assert!(iter::from_fn(|| server.next_event()).any(|event| matches!(
+        event,
+        Event::AllocationMoved { from, to, .. } if from == old_source.into() && to == new_source.into()
+    )));
+}
+
+#[proptest]
+fn mobility_ticket_must_belong_to_mobile_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[filter(#new_source != #source)] new_source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    for (ticket, error) in [
+        (
+            vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+            ErrorCode::from(MobilityForbidden),
+        ),
+        (
+            vec![0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0],
+            ErrorCode::from(AllocationMismatch),
+        ),
+        (vec![1, 2, 3], ErrorCode::from(BadRequest)),
+    ] {
+        server.assert_commands(
+            from_client(
+                new_source,
+                Refresh::new_with_mobility_ticket(
+                    transaction_id,
+                    Some(lifetime.clone()),
+                    valid_username(now, &username_salt),
+                    &secret,
+                    &server.nonce(new_source, now),
+                    Some(MobilityTicket::new(ticket).unwrap()),
+                ),
+                now,
+            ),
+            [send_message(
+                new_source,
+                refresh_error_response(transaction_id, error),
+            )],
+        );
+    }
+}
+
+#[proptest]
+fn stream_clients_cannot_request_mobility(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+                vec![MobilityTicket::empty().into()],
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            mobility_forbidden_allocate_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn binding_response_includes_response_origin_and_other_address(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_relay_addr: Ipv4Addr,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nat_discovery(NatDiscovery::new(3478, 3479).with_alternate_ip4(alternate_relay_addr));
+
+    server.assert_commands(
+        from_client(source, Binding::new(transaction_id), SystemTime::now()),
+        [send_message(
+            source,
+            nat_discovery_binding_response(
+                transaction_id,
+                source,
+                (public_relay_addr, 3478),
+                Some((alternate_relay_addr, 3479)),
+            ),
+        )],
+    );
+}
+
+#[proptest]
+fn change_request_is_answered_from_changed_socket(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_relay_addr: Ipv4Addr,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nat_discovery(NatDiscovery::new(3478, 3479).with_alternate_ip4(alternate_relay_addr));
+    let alternate_ip_and_port = DiscoverySocket {
+        alternate_ip: true,
+        alternate_port: true,
+    };
+
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, true, true),
+            SystemTime::now(),
+        ),
+        [send_message_from(
+            alternate_ip_and_port,
+            source,
+            nat_discovery_binding_response(
+                transaction_id,
+                source,
+                (alternate_relay_addr, 3479),
+                Some((alternate_relay_addr, 3479)),
+            ),
+        )],
+    );
+}
+
+#[proptest]
+fn change_request_on_alternate_port_is_answered_from_primary_port(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_relay_addr: Ipv4Addr,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nat_discovery(NatDiscovery::new(3478, 3479).with_alternate_ip4(alternate_relay_addr));
+    let alternate_port = DiscoverySocket {
+        alternate_ip: false,
+        alternate_port: true,
+    };
+
+    server.assert_commands(
+        from_client_on(
+            alternate_port,
+            source,
+            Binding::new_with_change_request(transaction_id, false, true),
+        ),
+        [send_message(
+            source,
+            nat_discovery_binding_response(
+                transaction_id,
+                source,
+                (public_relay_addr, 3478),
+                Some((alternate_relay_addr, 3478)),
+            ),
+        )],
+    );
+}
+
+#[proptest]
+fn only_binding_requests_are_answered_on_discovery_sockets(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let mut server =
+        TestServer::new(public_relay_addr).with_nat_discovery(NatDiscovery::new(3478, 3479));
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client_on(
+            DiscoverySocket {
+                alternate_ip: false,
+                alternate_port: true,
+            },
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                &server.nonce(source, now),
+            ),
+        ),
+        [],
+    );
+}
+
+#[proptest]
+fn change_request_without_alternate_ip_is_unknown_attribute(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let mut server =
+        TestServer::new(public_relay_addr).with_nat_discovery(NatDiscovery::new(3478, 3479));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, true, false),
+            SystemTime::now(),
+        ),
+        [send_message(
+            source,
+            change_request_unknown_response(transaction_id),
+        )],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, false, true),
+            SystemTime::now(),
+        ),
+        [send_message_from(
+            DiscoverySocket {
+                alternate_ip: false,
+                alternate_port: true,
+            },
+            source,
+            nat_discovery_binding_response(
+                transaction_id,
+                source,
+                (public_relay_addr, 3479),
+                None::<SocketAddr>,
+            ),
+        )],
+    );
+}
+
+#[proptest]
+fn change_request_without_nat_discovery_is_unknown_attribute(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let mut server = TestServer::new(public_relay_addr);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, false, true),
+            SystemTime::now(),
+        ),
+        [send_message(
+            source,
+            change_request_unknown_response(transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn stream_clients_cannot_send_change_request(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let mut server =
+        TestServer::new(public_relay_addr).with_nat_discovery(NatDiscovery::new(3478, 3479));
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, false, true),
+            SystemTime::now(),
+        ),
+        [send_message(source, bad_binding_response(transaction_id))],
+    );
+    server.assert_commands(
+        from_client(source, Binding::new(transaction_id), SystemTime::now()),
+        [send_message(
+            source,
+            binding_response(transaction_id, source),
+        )],
+    );
+}
+
+struct TestServer {
+    server: Server<StepRng>,
+    id_to_port: HashMap<u16, AllocationId>,
+}
+
+impl TestServer {
+    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
+        Self::new_with_rng(relay_public_addr, StepRng::new(0, 0))
+    }
+
+    /// Creates a [`TestServer`] with a custom [`StepRng`].
+    ///
+    /// `StepRng::new(0, 0)` always starts the search for an unused port at the lowest port.
+    fn new_with_rng(relay_public_addr: impl Into<IpStack>, rng: StepRng) -> Self {
+        Self {
+            server: Server::new(relay_public_addr, rng, 49152, 65535),
+            id_to_port: Default::default(),
+        }
+    }
+
+    fn nonce(&self, client: impl Into<SocketAddr>, now: SystemTime) -> String {
+        self.server.issue_nonce(client.into(), now)
+    }
+
+    fn with_user_limits(mut self, limits: Limits) -> Self {
+        self.server = self.server.with_user_limits(limits);
+
+        self
+    }
+
+    fn with_ip_limits(mut self, limits: Limits) -> Self {
+        self.server = self.server.with_ip_limits(limits);
+
+        self
+    }
+
+    fn with_alternate_servers(mut self, alternate_servers: Vec<SocketAddr>) -> Self {
+        self.server = self.server.with_alternate_servers(alternate_servers);
+
+        self
+    }
+
+    fn with_listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.server = self.server.with_listen_addr(addr);
+
+        self
+    }
+
+    fn with_nat_discovery(mut self, nat_discovery: NatDiscovery) -> Self {
+        self.server = self.server.with_nat_discovery(nat_discovery);
+
+        self
+    }
+
+    fn with_access_token_keys(mut self, keys: AccessTokenKeys) -> Self {
+        self.server = self.server.with_access_token_keys(keys);
+
+        self
+    }
+
+    fn with_load_shedding_threshold(mut self, threshold: f64) -> Self {
+        self.server = self.server.with_load_shedding_threshold(threshold);
+
+        self
+    }
+
+    fn start_draining(&mut self) {
+        self.server.start_draining();
+    }
+
+    fn with_auth_secret(mut self, auth_secret: SecretString) -> Self {
+        self.server = self.server.with_auth_secret(auth_secret);
+
+        self
+    }
+
+    fn with_credential_scheme(mut self, scheme: CredentialScheme) -> Self {
+        self.server = self.server.with_credential_scheme(scheme);
+
+        self
+    }
+
+    fn with_auth_secret_grace_period(mut self, grace_period: Duration) -> Self {
+        self.server = self.server.with_auth_secret_grace_period(grace_period);
+
+        self
+    }
+
+    fn auth_secret(&self) -> &SecretString {
+        self.server.auth_secret()
+    }
+
+    fn rotate_auth_secret(&mut self, auth_secret: SecretString, now: SystemTime) {
+        self.server.rotate_auth_secret(auth_secret, now);
+    }
+
+    fn rotate_nonce_secret(&mut self, nonce_secret: SecretString) {
+        self.server.rotate_nonce_secret(nonce_secret);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.server.snapshot()
+    }
+
+    fn allocations(&self) -> Vec<AllocationInfo> {
+        self.server.allocations()
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        self.server.next_event()
+    }
+
+    /// The reasons of all allocations deleted since the last call, skipping all other events.
+    fn deletion_reasons(&mut self) -> Vec<DeletionReason> {
+        iter::from_fn(|| self.server.next_event())
+            .filter_map(|event| match event {
+                Event::AllocationDeleted { reason, .. } => Some(reason),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn start_capture(
+        &mut self,
+        filter: CaptureFilter,
+        max_bytes: u64,
+        duration: Duration,
+        now: SystemTime,
+    ) -> Option<CaptureId> {
+        let id = self
+            .server
+            .start_capture(filter, max_bytes, duration, now)?;
+        assert!(matches!(
+            self.server.next_command(),
+            Some(Command::Wake { .. })
+        ));
+
+        Some(id)
+    }
+
+    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
+        match input {
+            Input::Client(sender, message, now) => {
+                self.server.handle_client_message(message, sender, now);
+            }
+            Input::Discovery(socket, sender, message) => {
+                self.server
+                    .handle_discovery_message(message, sender, socket);
+            }
+            Input::Time(now) => {
+                self.server.handle_deadline_reached(now);
+            }
+            Input::Connect(client) => {
+                self.server.handle_client_connected(client);
+            }
+            Input::Disconnect(client) => {
+                self.server.handle_client_disconnected(client);
+            }
+            Input::PeerConnection(peer, port, now) => {
+                self.server
+                    .handle_peer_connection(self.id_to_port[&port], peer, now);
+            }
+            Input::PeerConnected(connection, now) => {
+                self.server
+                    .handle_peer_connected(ConnectionId::new(connection), now);
+            }
+            Input::PeerConnectionFailed(connection) => {
+                self.server
+                    .handle_peer_connection_failed(ConnectionId::new(connection));
+            }
+            Input::KillAll(reason) => {
+                self.server.kill_all_allocations(reason);
+            }
+            Input::Kill(port) => {
+                assert!(self
+                    .server
+                    .kill_allocation(self.id_to_port[&port], DeletionReason::AdminKilled));
+            }
+            Input::Restore(snapshot, now) => {
+                self.server.restore(snapshot, now);
+            }
+            Input::Peer(peer, data, port, now) => {
+                self.server
+                    .handle_relay_input(&data, peer, self.id_to_port[&port], now);
+            }
+        }
+
+        for expected_output in output {
+            let Some(actual_output) = self.server.next_command() else {
+                let msg = match expected_output {
+                    Output::SendMessage((recipient, msg)) => {
+                        format!("to send message {:?} to {recipient}", msg)
+                    }
+                    Output::SendMessageFrom((socket, recipient, msg)) => {
+                        format!("to send message {:?} from {socket:?} to {recipient}", msg)
+                    }
+                    Wake(time) => format!("to be woken at {time:?}"),
+                    CreateAllocation(port, family) => {
+                        format!("to create allocation on port {port} for address family {family}")
+                    }
+                    CreateDontFragmentAllocation(port, family) => {
+                        format!("to create allocation with DONT-FRAGMENT on port {port} for address family {family}")
+                    }
+                    FreeAllocation(port, family) => {
+                        format!("to free allocation on port {port} for address family {family}")
+                    }
+                    CreateTcpAllocation(port, family) => {
+                        format!(
+                            "to create TCP allocation on port {port} for address family {family}"
+                        )
+                    }
+                    ConnectToPeer(peer, connection, _) => {
+                        format!("to connect to peer {peer} as connection {connection}")
+                    }
+                    Output::SpliceConnection(connection, client, _) => {
+                        format!("to splice connection {connection} with client {client}")
+                    }
+                    CloseConnection(connection) => format!("to close connection {connection}"),
+                    Output::SendChannelData((peer, _)) => {
+                        format!("to send channel data from {peer} to client")
+                    }
+                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
+                };
+
+                panic!("No commands produced but expected {msg}");
+            };
+
+            match (expected_output, actual_output) {
+                (
                     Output::SendMessage((to, message)),
                     Command::SendMessage { payload, recipient },
                 ) => {
@@ -497,6 +3681,28 @@ impl TestServer {
 
                     assert_eq!(recipient, to);
                 }
+                (
+                    Output::SendMessageFrom((from, to, message)),
+                    Command::SendMessageFrom {
+                        payload,
+                        recipient,
+                        socket,
+                    },
+                ) => {
+                    let expected_bytes = MessageEncoder::new()
+                        .encode_into_bytes(message.clone())
+                        .unwrap();
+
+                    if expected_bytes != payload {
+                        let expected_message = format!("{:?}", message);
+                        let actual_message = format!("{:?}", parse_message(&payload));
+
+                        difference::assert_diff!(&expected_message, &actual_message, "\n", 0);
+                    }
+
+                    assert_eq!(recipient, to);
+                    assert_eq!(socket, from);
+                }
                 (Wake(when), Command::Wake { deadline }) => {
                     assert_eq!(when, deadline);
                 }
@@ -506,6 +3712,16 @@ impl TestServer {
                         id,
                         family: actual_family,
                         port: actual_port,
+                        dont_fragment: false,
+                    },
+                )
+                | (
+                    CreateDontFragmentAllocation(expected_port, expected_family),
+                    Command::CreateAllocation {
+                        id,
+                        family: actual_family,
+                        port: actual_port,
+                        dont_fragment: true,
                     },
                 ) => {
                     self.id_to_port.insert(actual_port, id);
@@ -523,6 +3739,55 @@ impl TestServer {
                     assert_eq!(id, actual_id);
                     assert_eq!(family, actual_family);
                 }
+                (
+                    CreateTcpAllocation(expected_port, expected_family),
+                    Command::CreateTcpAllocation {
+                        id,
+                        family: actual_family,
+                        port: actual_port,
+                    },
+                ) => {
+                    self.id_to_port.insert(actual_port, id);
+                    assert_eq!(expected_port, actual_port);
+                    assert_eq!(expected_family, actual_family);
+                }
+                (
+                    ConnectToPeer(expected_peer, expected_connection, port),
+                    Command::ConnectToPeer {
+                        id,
+                        connection,
+                        peer,
+                    },
+                ) => {
+                    assert_eq!(self.id_to_port[&port], id);
+                    assert_eq!(ConnectionId::new(expected_connection), connection);
+                    assert_eq!(expected_peer, peer);
+                }
+                (
+                    Output::SpliceConnection(expected_connection, expected_client, message),
+                    Command::SpliceConnection {
+                        connection,
+                        client,
+                        response,
+                    },
+                ) => {
+                    let expected_bytes = MessageEncoder::new()
+                        .encode_into_bytes(message.clone())
+                        .unwrap();
+
+                    if expected_bytes != response {
+                        let expected_message = format!("{:?}", message);
+                        let actual_message = format!("{:?}", parse_message(&response));
+
+                        difference::assert_diff!(&expected_message, &actual_message, "\n", 0);
+                    }
+
+                    assert_eq!(ConnectionId::new(expected_connection), connection);
+                    assert_eq!(expected_client, client);
+                }
+                (CloseConnection(expected), Command::CloseConnection { connection }) => {
+                    assert_eq!(ConnectionId::new(expected), connection);
+                }
                 (Wake(when), Command::SendMessage { payload, .. }) => {
                     panic!(
                         "Expected `Wake({})`, got `SendMessage({:?})`",
@@ -586,6 +3851,105 @@ fn binding_response(
     message
 }
 
+fn nat_discovery_binding_response(
+    transaction_id: TransactionId,
+    address: impl Into<SocketAddr>,
+    response_origin: impl Into<SocketAddr>,
+    other_address: Option<impl Into<SocketAddr>>,
+) -> Message<Attribute> {
+    let mut message = binding_response(transaction_id, address);
+    message.add_attribute(ResponseOrigin::new(response_origin.into()));
+    if let Some(other_address) = other_address {
+        message.add_attribute(OtherAddress::new(other_address.into()));
+    }
+
+    message
+}
+
+fn change_request_unknown_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, BINDING, transaction_id);
+    message.add_attribute(ErrorCode::from(UnknownAttribute));
+    message.add_attribute(UnknownAttributes::new(vec![AttributeType::new(
+        ChangeRequest::CODEPOINT,
+    )]));
+
+    message
+}
+
+fn bad_binding_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, BINDING, transaction_id);
+    message.add_attribute(ErrorCode::from(BadRequest));
+
+    message
+}
+
+fn turn_rest_allocate(
+    transaction_id: TransactionId,
+    lifetime: &Lifetime,
+    username: &str,
+    password: &str,
+    nonce: &str,
+) -> Allocate {
+    let username = Username::new(username.to_owned()).unwrap();
+
+    let mut message = Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
+    message.add_attribute(RequestedTransport::new(17));
+    message.add_attribute(username.clone());
+    message.add_attribute(Nonce::new(nonce.to_owned()).unwrap());
+    message.add_attribute(lifetime.clone());
+
+    let message_integrity = MessageIntegrity::new_long_term_credential(
+        &message,
+        &username,
+        &Realm::new("firezone".to_owned()).unwrap(),
+        password,
+    )
+    .unwrap();
+    message.add_attribute(message_integrity);
+
+    Allocate::parse(&message).unwrap()
+}
+
+/// An allocate request with `MESSAGE-INTEGRITY-SHA256`, using the TURN REST credentials of [`accepts_turn_rest_credentials`].
+fn sha256_allocate(
+    transaction_id: TransactionId,
+    lifetime: &Lifetime,
+    nonce: &str,
+    password_algorithms: PasswordAlgorithms,
+    password_algorithm: PasswordAlgorithm,
+) -> Allocate {
+    let username = Username::new("4102444800:alice".to_owned()).unwrap();
+
+    let mut message = Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
+    message.add_attribute(RequestedTransport::new(17));
+    message.add_attribute(username.clone());
+    message.add_attribute(Nonce::new(nonce.to_owned()).unwrap());
+    message.add_attribute(lifetime.clone());
+    message.add_attribute(password_algorithms);
+    message.add_attribute(password_algorithm);
+
+    let message_integrity = MessageIntegritySha256::new_long_term_credential(
+        &message,
+        &username,
+        &Realm::new("firezone".to_owned()).unwrap(),
+        "58Tl4e2VjINId23vxEnD/7NNBaQ=",
+        password_algorithm,
+    )
+    .unwrap();
+    message.add_attribute(message_integrity);
+
+    Allocate::parse(&message).unwrap()
+}
+
+/// The keys of the third-party authorization server in the access token tests.
+fn access_token_keys() -> AccessTokenKeys {
+    AccessTokenKeys::new("relay.example.com".to_owned())
+        .with_key("kid".to_owned(), vec![3; 16])
+        .unwrap()
+}
+
 fn allocate_response(
     transaction_id: TransactionId,
     public_relay_addr: impl Into<IpAddr>,
@@ -607,13 +3971,97 @@ fn allocate_response(
 
 fn unauthorized_allocate_response(
     transaction_id: TransactionId,
-    nonce: Uuid,
+    nonce: &str,
 ) -> Message<Attribute> {
     let mut message =
         Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
     message.add_attribute(ErrorCode::from(Unauthorized));
-    message.add_attribute(Nonce::new(nonce.as_hyphenated().to_string()).unwrap());
+    message.add_attribute(Nonce::new(nonce.to_owned()).unwrap());
+    message.add_attribute(Realm::new("firezone".to_owned()).unwrap());
+    message.add_attribute(PasswordAlgorithms::new(vec![
+        PasswordAlgorithm::Sha256,
+        PasswordAlgorithm::Md5,
+    ]));
+
+    message
+}
+
+fn unauthorized_refresh_response(transaction_id: TransactionId, nonce: &str) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, REFRESH, transaction_id);
+    message.add_attribute(ErrorCode::from(Unauthorized));
+    message.add_attribute(Nonce::new(nonce.to_owned()).unwrap());
+    message.add_attribute(Realm::new("firezone".to_owned()).unwrap());
+    message.add_attribute(PasswordAlgorithms::new(vec![
+        PasswordAlgorithm::Sha256,
+        PasswordAlgorithm::Md5,
+    ]));
+
+    message
+}
+
+fn stale_nonce_allocate_response(transaction_id: TransactionId, nonce: &str) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(StaleNonce));
+    message.add_attribute(Nonce::new(nonce.to_owned()).unwrap());
     message.add_attribute(Realm::new("firezone".to_owned()).unwrap());
+    message.add_attribute(PasswordAlgorithms::new(vec![
+        PasswordAlgorithm::Sha256,
+        PasswordAlgorithm::Md5,
+    ]));
+
+    message
+}
+
+fn bad_request_allocate_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(BadRequest));
+
+    message
+}
+
+fn mobility_forbidden_allocate_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(MobilityForbidden));
+
+    message
+}
+
+fn forbidden_response(method: Method, transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, method, transaction_id);
+    message.add_attribute(ErrorCode::from(Forbidden));
+
+    message
+}
+
+fn allocation_quota_reached_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(AllocationQuotaReached));
+
+    message
+}
+
+fn try_alternate_response(
+    transaction_id: TransactionId,
+    alternate_server: SocketAddr,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(TryAlternate));
+    message.add_attribute(AlternateServer::new(alternate_server));
+
+    message
+}
+
+fn insufficient_capacity_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(InsufficientCapacity));
 
     message
 }
@@ -630,6 +4078,82 @@ fn channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
     Message::<Attribute>::new(MessageClass::SuccessResponse, CHANNEL_BIND, transaction_id)
 }
 
+fn create_permission_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        CREATE_PERMISSION,
+        transaction_id,
+    )
+}
+
+fn connect_response(transaction_id: TransactionId, connection: u32) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        Method::new(CONNECT).unwrap(),
+        transaction_id,
+    );
+    message.add_attribute(ConnectionId::new(connection));
+
+    message
+}
+
+fn connection_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        Method::new(CONNECTION_BIND).unwrap(),
+        transaction_id,
+    )
+}
+
+fn refresh_error_response(
+    transaction_id: TransactionId,
+    error_code: impl Into<ErrorCode>,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, REFRESH, transaction_id);
+    message.add_attribute(error_code.into());
+
+    message
+}
+
+fn connect_error_response(
+    transaction_id: TransactionId,
+    error_code: impl Into<ErrorCode>,
+) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::ErrorResponse,
+        Method::new(CONNECT).unwrap(),
+        transaction_id,
+    );
+    message.add_attribute(error_code.into());
+
+    message
+}
+
+fn connection_attempt(peer: impl Into<SocketAddr>, connection: u32) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::Indication,
+        Method::new(CONNECTION_ATTEMPT).unwrap(),
+        TransactionId::new([0; 12]), // `StepRng::new(0, 0)` always yields zeros.
+    );
+    message.add_attribute(XorPeerAddress::new(peer.into()));
+    message.add_attribute(ConnectionId::new(connection));
+
+    message
+}
+
+fn data_indication(peer: impl Into<SocketAddr>, data: &[u8]) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::Indication,
+        DATA,
+        TransactionId::new([0; 12]), // `StepRng::new(0, 0)` always yields zeros.
+    );
+    message.add_attribute(XorPeerAddress::new(peer.into()));
+    message.add_attribute(Data::new(data.to_vec()).unwrap());
+
+    message
+}
+
 fn parse_message(message: &[u8]) -> Message<Attribute> {
     MessageDecoder::new()
         .decode_from_bytes(message)
@@ -639,8 +4163,17 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 
 enum Input<'a> {
     Client(SocketAddr, ClientMessage<'a>, SystemTime),
-    Peer(SocketAddr, Vec<u8>, u16),
+    Discovery(DiscoverySocket, SocketAddr, ClientMessage<'a>),
+    Peer(SocketAddr, Vec<u8>, u16, SystemTime),
     Time(SystemTime),
+    Connect(SocketAddr),
+    Disconnect(SocketAddr),
+    PeerConnection(SocketAddr, u16, SystemTime),
+    PeerConnected(u32, SystemTime),
+    PeerConnectionFailed(u32),
+    Restore(Snapshot, SystemTime),
+    Kill(u16),
+    KillAll(DeletionReason),
 }
 
 fn from_client<'a>(
@@ -651,28 +4184,102 @@ fn from_client<'a>(
     Input::Client(from.into(), message.into(), now)
 }
 
-fn from_peer<'a>(from: impl Into<SocketAddr>, data: &[u8], port: u16) -> Input<'a> {
-    Input::Peer(from.into(), data.to_vec(), port)
+/// A message from a client, received on one of the sockets that only serve NAT behaviour discovery.
+fn from_client_on<'a>(
+    socket: DiscoverySocket,
+    from: impl Into<SocketAddr>,
+    message: impl Into<ClientMessage<'a>>,
+) -> Input<'a> {
+    Input::Discovery(socket, from.into(), message.into())
+}
+
+fn from_peer<'a>(
+    from: impl Into<SocketAddr>,
+    data: &[u8],
+    port: u16,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::Peer(from.into(), data.to_vec(), port, now)
 }
 
 fn forward_time_to<'a>(when: SystemTime) -> Input<'a> {
     Input::Time(when)
 }
 
+fn restore_from<'a>(snapshot: Snapshot, now: SystemTime) -> Input<'a> {
+    Input::Restore(snapshot, now)
+}
+
+fn kill_allocation<'a>(port: u16) -> Input<'a> {
+    Input::Kill(port)
+}
+
+fn kill_all_allocations<'a>(reason: DeletionReason) -> Input<'a> {
+    Input::KillAll(reason)
+}
+
+fn client_connected<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Connect(client.into())
+}
+
+fn client_disconnected<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Disconnect(client.into())
+}
+
+fn peer_connection<'a>(peer: impl Into<SocketAddr>, port: u16, now: SystemTime) -> Input<'a> {
+    Input::PeerConnection(peer.into(), port, now)
+}
+
+fn peer_connected<'a>(connection: u32, now: SystemTime) -> Input<'a> {
+    Input::PeerConnected(connection, now)
+}
+
+fn peer_connection_failed<'a>(connection: u32) -> Input<'a> {
+    Input::PeerConnectionFailed(connection)
+}
+
 #[derive(Debug)]
 enum Output<'a> {
     SendMessage((SocketAddr, Message<Attribute>)),
+    SendMessageFrom((DiscoverySocket, SocketAddr, Message<Attribute>)),
     SendChannelData((SocketAddr, ChannelData<'a>)),
     Forward((SocketAddr, Vec<u8>, u16)),
     Wake(SystemTime),
     CreateAllocation(u16, AddressFamily),
+    CreateDontFragmentAllocation(u16, AddressFamily),
     FreeAllocation(u16, AddressFamily),
+    CreateTcpAllocation(u16, AddressFamily),
+    ConnectToPeer(SocketAddr, u32, u16),
+    SpliceConnection(u32, SocketAddr, Message<Attribute>),
+    CloseConnection(u32),
 }
 
-fn send_message<'a>(source: impl Into<SocketAddr>, message: Message<Attribute>) -> Output<'a> {
+fn send_message<'a>(source: impl Into<SocketAddr>, mut message: Message<Attribute>) -> Output<'a> {
+    if matches!(
+        message.class(),
+        MessageClass::SuccessResponse | MessageClass::ErrorResponse
+    ) {
+        message.add_attribute(
+            Software::new(format!("firezone-relay/{}", env!("CARGO_PKG_VERSION"))).unwrap(),
+        );
+        message.add_attribute(Fingerprint::new(&message).unwrap());
+    }
+
     Output::SendMessage((source.into(), message))
 }
 
+fn send_message_from<'a>(
+    socket: DiscoverySocket,
+    source: impl Into<SocketAddr>,
+    message: Message<Attribute>,
+) -> Output<'a> {
+    let Output::SendMessage((source, message)) = send_message(source, message) else {
+        unreachable!()
+    };
+
+    Output::SendMessageFrom((socket, source, message))
+}
+
 fn send_channel_data(source: impl Into<SocketAddr>, message: ChannelData) -> Output {
     Output::SendChannelData((source.into(), message))
 }
@@ -680,3 +4287,15 @@ fn send_channel_data(source: impl Into<SocketAddr>, message: ChannelData) -> Out
 fn forward(source: impl Into<SocketAddr>, data: &[u8], port: u16) -> Output {
     Output::Forward((source.into(), data.to_vec(), port))
 }
+
+fn splice_connection<'a>(
+    connection: u32,
+    client: impl Into<SocketAddr>,
+    message: Message<Attribute>,
+) -> Output<'a> {
+    let Output::SendMessage((client, message)) = send_message(client, message) else {
+        unreachable!()
+    };
+
+    Output::SpliceConnection(connection, client, message)
+}